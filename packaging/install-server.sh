@@ -197,8 +197,11 @@ Description=Sessio SSH Server (User)
 After=network.target
 
 [Service]
-Type=simple
+Type=notify
 ExecStart=$BIN_DIR/$BIN_NAME run
+ExecReload=/bin/kill -HUP \$MAINPID
+KillMode=mixed
+TimeoutStopSec=45
 Restart=always
 RestartSec=10
 Environment="RUST_LOG=info TERM=xterm-256color"
@@ -214,8 +217,11 @@ After=network.target
 Wants=network.target
 
 [Service]
-Type=simple
+Type=notify
 ExecStart=$BIN_DIR/$BIN_NAME run
+ExecReload=/bin/kill -HUP \$MAINPID
+KillMode=mixed
+TimeoutStopSec=45
 Restart=always
 RestartSec=10
 StandardOutput=journal
@@ -54,6 +54,20 @@ fn success(msg: &str) {
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Connect straight to a server run with `--direct`, the device ID is then its host[:port]
+    #[arg(long, global = true)]
+    direct: bool,
+
+    /// SHA256 fingerprint of the host key of a direct server, pinned on the first connection
+    #[arg(long, global = true, requires = "direct", value_name = "FINGERPRINT")]
+    host_key: Option<String>,
+}
+
+/// How sessions reach their device
+struct ConnectOptions {
+    direct: bool,
+    host_key: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -64,6 +78,19 @@ enum Commands {
     /// Connect to interactive shell on device (ephemeral)
     Shell {
         device_id: String,
+
+        /// Run the shell in a persistent session with this ID, or reattach to it
+        #[arg(long)]
+        persist: Option<String>,
+
+        /// Set an environment variable in the shell, as NAME=VALUE. LANG and LC_* are
+        /// passed from the local environment. The server drops variables it does not accept
+        #[arg(long = "env", value_name = "NAME=VALUE")]
+        env: Vec<String>,
+
+        /// Forward the local SSH agent (SSH_AUTH_SOCK) to the shell
+        #[arg(long, short = 'A')]
+        forward_agent: bool,
     },
 
     /// Start SFTP session for file operations (ephemeral)
@@ -134,6 +161,18 @@ enum ForwardAction {
         #[arg(help = "local_port:remote_host:remote_port (e.g., 8080:localhost:80)")]
         port_spec: String,
     },
+    /// Start remote port forwarding, the device listens and forwards connections back here
+    Remote {
+        device_id: String,
+        #[arg(help = "remote_port:local_host:local_port (e.g., 8080:localhost:3000)")]
+        port_spec: String,
+    },
+    /// Forward a Unix socket on the device to a local port or local Unix socket
+    Socket {
+        device_id: String,
+        #[arg(help = "local_port:remote_socket or local_socket:remote_socket (e.g., 2375:/var/run/docker.sock)")]
+        socket_spec: String,
+    },
     /// Stop port forwarding
     Stop {
         device_id: String,
@@ -161,14 +200,18 @@ enum FileOperation {
 }
 
 
-async fn new_session(client: &mut ClientIpcClient<Channel>, session_data: SessionData) -> anyhow::Result<String>{
+async fn new_session(client: &mut ClientIpcClient<Channel>, session_data: SessionData, options: &ConnectOptions) -> anyhow::Result<String>{
     let connection_request = tonic::Request::new(NewConnectionRequest {
         coordinator_url: "".into(),
         target_id: session_data.device_id.clone(),
         own_ipv6: None,
+        direct_address: options.direct.then(|| session_data.device_id.clone()),
+        host_key_fingerprint: options.host_key.clone(),
     });
 
-    client.start_coordinator(CoordinatorStartRequest{}).await?;
+    if !options.direct {
+        client.start_coordinator(CoordinatorStartRequest{}).await?;
+    }
     let connection_response = client.new_connection(connection_request).await?;
 
     // Request a new session from the server
@@ -187,7 +230,24 @@ async fn new_session(client: &mut ClientIpcClient<Channel>, session_data: Sessio
     Ok(session_id)
 }
 
-async fn start_interactive_shell(client: &mut ClientIpcClient<Channel>, session_id: String) -> anyhow::Result<()> {
+/// Locale variables from the local environment followed by the ones given with `--env`
+fn shell_environment(overrides: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    let mut env: Vec<(String, String)> = std::env::vars()
+        .filter(|(name, _)| name == "LANG" || name.starts_with("LC_"))
+        .collect();
+
+    for entry in overrides {
+        let Some((name, value)) = entry.split_once('=') else {
+            anyhow::bail!("Invalid environment variable {:?}, expected NAME=VALUE", entry);
+        };
+        env.retain(|(existing, _)| existing != name);
+        env.push((name.to_string(), value.to_string()));
+    }
+
+    Ok(env)
+}
+
+async fn start_interactive_shell(client: &mut ClientIpcClient<Channel>, session_id: String, env: Vec<(String, String)>) -> anyhow::Result<()> {
     // Initialize Crossterm for terminal manipulation
     let mut stdout_std = std::io::stdout();
 
@@ -232,6 +292,12 @@ async fn start_interactive_shell(client: &mut ClientIpcClient<Channel>, session_
 
     tx.send(initial_pty_request).await?;
 
+    for (name, value) in env {
+        tx.send(Msg {
+            r#type: Some(clientipc::msg::Type::EnvRequest(clientipc::msg::EnvRequest { name, value })),
+        }).await?;
+    }
+
     // Immediately send shell request
     tx.send(Msg {
         r#type: Some(clientipc::msg::Type::ShellRequest(clientipc::msg::ShellRequest{})),
@@ -613,6 +679,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .await?;
     
     let mut client = ClientIpcClient::new(channel);
+    let options = ConnectOptions {
+        direct: cli.direct,
+        host_key: cli.host_key,
+    };
 
     match cli.command {
         Commands::List => {
@@ -664,6 +734,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                                                   lpf.local_port, lpf.remote_host, lpf.remote_port);
                         entry.1.push(forward_info);
                     },
+                    Some(clientipc::session_data::Kind::Rpf(rpf)) => {
+                        let forward_info = format!("RemoteForward({}<-{}:{})",
+                                                  rpf.remote_port, rpf.local_host, rpf.local_port);
+                        entry.1.push(forward_info);
+                    },
+                    Some(clientipc::session_data::Kind::Lsf(lsf)) => {
+                        let local = lsf.local_socket_path.clone()
+                            .unwrap_or_else(|| lsf.local_port.to_string());
+                        entry.1.push(format!("SocketForward({}->{})", local, lsf.remote_socket_path));
+                    },
                     None => {},
                 };
             }
@@ -686,17 +766,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             device_table.printstd();
         }
         
-        Commands::Shell { device_id } => {
+        Commands::Shell { device_id, persist, env, forward_agent } => {
+            let env = shell_environment(&env)?;
             println!("Connecting to shell on {}...", device_id);
             let session_data = SessionData {
                 device_id: device_id.to_string(),
                 username: "root".into(),
-                kind: Some(clientipc::session_data::Kind::Pty(clientipc::session_data::PtySession {})),
+                kind: Some(clientipc::session_data::Kind::Pty(clientipc::session_data::PtySession {
+                    persistent_id: persist,
+                })),
+                forward_agent,
+                agent_socket: forward_agent
+                    .then(|| std::env::var("SSH_AUTH_SOCK").ok())
+                    .flatten(),
                 ..Default::default()
             };
             
-            let session_id = new_session(&mut client, session_data).await?;
-            start_interactive_shell(&mut client, session_id).await?;
+            let session_id = new_session(&mut client, session_data, &options).await?;
+            start_interactive_shell(&mut client, session_id, env).await?;
         }
         
         Commands::Sftp { device_id } => {
@@ -708,7 +795,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 ..Default::default()
             };
             
-            let session_id = new_session(&mut client, session_data).await?;
+            let session_id = new_session(&mut client, session_data, &options).await?;
             println!("SFTP session created with ID: {}", session_id);
             // TODO: Implement SFTP interactive session
         }
@@ -762,7 +849,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                         ..Default::default()
                     };
                     
-                    let session_id = new_session(&mut client, session_data).await?;
+                    let session_id = new_session(&mut client, session_data, &options).await?;
                     
                     // Start the local port forward
                     let lpf_request = tonic::Request::new(SessionData {
@@ -776,6 +863,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                             remote_port: remote_port as u32,
                         })),
                         active: true,
+                        ..Default::default()
                     });
                     
                     client.local_port_forward(lpf_request).await?;
@@ -788,6 +876,120 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("\nStopping port forwarding...");
                 }
                 
+                ForwardAction::Remote { device_id, port_spec } => {
+                    let parts: Vec<&str> = port_spec.split(':').collect();
+                    if parts.len() != 3 {
+                        error("Invalid port spec. Use format: remote_port:local_host:local_port");
+                        return Ok(());
+                    }
+
+                    let Ok(remote_port) = parts[0].parse::<u16>() else {
+                        error("Invalid remote port number");
+                        return Ok(());
+                    };
+
+                    let local_host = parts[1].to_string();
+                    let local_port: u16 = parts[2].parse().map_err(|_| {
+                        error("Invalid local port number");
+                    }).unwrap_or(0);
+
+                    if local_port == 0 {
+                        return Ok(());
+                    }
+
+                    let rpf = clientipc::session_data::RpfSession {
+                        remote_host: "localhost".to_string(),
+                        remote_port: remote_port as u32,
+                        local_host,
+                        local_port: local_port as u32,
+                    };
+
+                    let session_data = SessionData {
+                        device_id: device_id.to_string(),
+                        username: "root".into(),
+                        kind: Some(clientipc::session_data::Kind::Rpf(rpf.clone())),
+                        ..Default::default()
+                    };
+
+                    let session_id = new_session(&mut client, session_data, &options).await?;
+
+                    let rpf_request = tonic::Request::new(SessionData {
+                        session_id: Some(session_id.clone()),
+                        device_id: device_id.to_string(),
+                        username: "root".into(),
+                        kind: Some(clientipc::session_data::Kind::Rpf(rpf.clone())),
+                        active: true,
+                        ..Default::default()
+                    });
+
+                    let bound_port = client.remote_port_forward(rpf_request).await?.into_inner().bound_port;
+                    success(&format!("Remote port forwarding active: {}:{} -> {}:{}",
+                                   device_id, bound_port, rpf.local_host, rpf.local_port));
+                    println!("Press Ctrl+C to stop");
+
+                    // Keep the process running
+                    tokio::signal::ctrl_c().await?;
+                    println!("\nStopping port forwarding...");
+                }
+
+                ForwardAction::Socket { device_id, socket_spec } => {
+                    let Some((local, remote_socket_path)) = socket_spec.split_once(':') else {
+                        error("Invalid socket spec. Use format: local_port:remote_socket or local_socket:remote_socket");
+                        return Ok(());
+                    };
+                    if local.is_empty() || remote_socket_path.is_empty() {
+                        error("Invalid socket spec. Use format: local_port:remote_socket or local_socket:remote_socket");
+                        return Ok(());
+                    }
+
+                    // A number is a local TCP port, anything else a local socket path
+                    let (local_port, local_socket_path) = match local.parse::<u16>() {
+                        Ok(port) => (port as u32, None),
+                        Err(_) => (0, Some(local.to_string())),
+                    };
+
+                    let lsf = clientipc::session_data::LsfSession {
+                        local_host: "127.0.0.1".to_string(),
+                        local_port,
+                        local_socket_path: local_socket_path.clone(),
+                        remote_socket_path: remote_socket_path.to_string(),
+                    };
+
+                    let session_data = SessionData {
+                        device_id: device_id.to_string(),
+                        username: "root".into(),
+                        kind: Some(clientipc::session_data::Kind::Lsf(lsf.clone())),
+                        ..Default::default()
+                    };
+
+                    let session_id = new_session(&mut client, session_data, &options).await?;
+
+                    let lsf_request = tonic::Request::new(SessionData {
+                        session_id: Some(session_id.clone()),
+                        device_id: device_id.to_string(),
+                        username: "root".into(),
+                        kind: Some(clientipc::session_data::Kind::Lsf(lsf)),
+                        active: true,
+                        ..Default::default()
+                    });
+
+                    client.local_socket_forward(lsf_request).await?;
+                    let local_display = match &local_socket_path {
+                        Some(path) => path.clone(),
+                        None => format!("localhost:{}", local_port),
+                    };
+                    success(&format!("Socket forwarding active: {} -> {}:{}",
+                                   local_display, device_id, remote_socket_path));
+                    println!("Press Ctrl+C to stop");
+
+                    // Keep the process running
+                    tokio::signal::ctrl_c().await?;
+                    println!("\nStopping socket forwarding...");
+                    if let Some(path) = local_socket_path {
+                        let _ = std::fs::remove_file(path);
+                    }
+                }
+
                 ForwardAction::Stop { device_id, local_port } => {
                     println!("Stopping port forward on {} port {}", device_id, local_port);
                     // TODO: Implement stop functionality - need to track active forwards
@@ -4,6 +4,7 @@ use chrono::Utc;
 use clap::Parser;
 use client::Msg;
 use common::utils::events::EventBus;
+use common::utils::direct::split_host_port;
 use common::utils::map_ipv4_to_ipv6;
 use russh::keys::ssh_key::PrivateKey;
 
@@ -64,7 +65,7 @@ use crossterm::{
 use futures::{select, stream};
 use russh::*;
 use russh::keys::*;
-use russh_sftp::{client::SftpSession, protocol::OpenFlags};
+use russh_sftp::client::RawSftpSession;
 use std::env;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
@@ -73,7 +74,11 @@ use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::{task, time};
 use dirs;
 
+use common::utils::persistent_sessions::{
+    PersistentSessionInfo, PERSISTENT_SESSIONS_SUBSYSTEM, PERSISTENT_SESSION_ENV,
+};
 use common::utils::streams::BiStream;
+use common::utils::tcp_fallback::{SshStream, TcpEndpoint};
 use sessio_coordinator_common::coordinator_client::CoordinatorClient;
 
 //Reusable channel where the listening end always takes the receiver
@@ -114,7 +119,7 @@ pub fn make_client_endpoint(socket: UdpSocket) -> Result<Endpoint> {
 
 pub struct Client {
     //Map of active connections
-    pub connections: HashMap<String, Connection>,
+    pub connections: HashMap<String, Transport>,
     pub sessions: HashMap<String, Arc<Mutex<Session>>>,
     pub data_folder_path: PathBuf,
     pub event_bus: EventBus<ClientEvent>,
@@ -124,6 +129,65 @@ pub struct Client {
     // Discovered external IP addresses
     pub external_ipv4: Option<SocketAddr>,
     pub external_ipv6: Option<SocketAddr>,
+    //Servers in direct mode by connection ID
+    pub direct_targets: HashMap<String, DirectTarget>,
+}
+
+//How a device is reached. A QUIC connection multiplexes every SSH session,
+//over the TCP fallback each session opens its own connection.
+#[derive(Clone)]
+pub enum Transport {
+    Quic(Connection),
+    Tcp {
+        endpoint: TcpEndpoint,
+        remote_addr: SocketAddr,
+    },
+}
+
+impl Transport {
+    pub fn close_reason(&self) -> Option<quinn::ConnectionError> {
+        match self {
+            Transport::Quic(conn) => conn.close_reason(),
+            //Connections are made per session, the endpoint itself stays usable
+            Transport::Tcp { .. } => None,
+        }
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        match self {
+            Transport::Quic(conn) => conn.remote_address(),
+            Transport::Tcp { remote_addr, .. } => *remote_addr,
+        }
+    }
+
+    //Opens the stream a new SSH session runs over
+    async fn open_stream(&self) -> Result<Box<dyn SshStream>> {
+        match self {
+            Transport::Quic(conn) => {
+                let (send, recv) = conn
+                    .open_bi()
+                    .await
+                    .context("Failed to open stream")?;
+                Ok(Box::new(BiStream {
+                    recv_stream: recv,
+                    send_stream: send,
+                }))
+            }
+            Transport::Tcp { endpoint, .. } => endpoint
+                .connect()
+                .await
+                .with_context(|| format!("Failed to connect to {}", endpoint)),
+        }
+    }
+}
+
+//A server in direct mode, reached by address instead of through the coordinator
+#[derive(Clone, Debug)]
+pub struct DirectTarget {
+    pub host: String,
+    pub port: u16,
+    //Fingerprint the user trusts, used to pin the host key when it's not known yet
+    pub pinned_fingerprint: Option<String>,
 }
 
 //The name "Session" is confusing, it's actually a SSH connection
@@ -143,17 +207,29 @@ pub struct Session {
     pub active: Arc<AtomicBool>,
 
     pub channel_stream: ChannelBiStream,
-    pub sftp_session: Option<SftpSession>,
+    pub sftp_session: Option<Arc<RawSftpSession>>,
+    //Extensions the SFTP server advertised, name -> version
+    pub sftp_extensions: HashMap<String, String>,
     pub event_sender: Sender<ClientEvent>,
+
+    //Remote forwards requested on this session, (remote host, port) -> (local host, port)
+    remote_forwards: RemoteForwardTargets,
 }
 
+type RemoteForwardTargets = Arc<Mutex<HashMap<(String, u32), (String, u32)>>>;
+
 pub struct ClientHandler {
-    connection: Connection,
+    connection: Transport,
     remote_addr: SocketAddr,
     server_id: String,
     session_id: String,
     event_tx: Sender<ClientEvent>,
     known_hosts_path: PathBuf,
+    remote_forwards: RemoteForwardTargets,
+    //Local agent that agent channels are relayed to, None if agent forwarding is off
+    agent_socket: Option<PathBuf>,
+    //Set when the server runs in direct mode, its host key is checked by address
+    direct: Option<DirectTarget>,
 }
 
 impl Client {
@@ -184,7 +260,7 @@ impl Client {
             ).into());
         }
 
-        self.coordinator = HolepunchService::new(coord_url.clone(), jwt_token.clone(), self.external_ipv4, self.external_ipv6, settings.device_id.clone(), self.endpoint.clone())
+        self.coordinator = HolepunchService::new(coord_url.clone(), jwt_token.clone(), self.external_ipv4, self.external_ipv6, settings.device_id.clone(), self.endpoint.clone(), None)
         .await
         .ok();
 
@@ -284,6 +360,7 @@ impl Client {
             coordinator: None,
             external_ipv4,
             external_ipv6,
+            direct_targets: HashMap::default(),
         };
 
         // Try to initialize coordinator - will skip if not configured yet
@@ -381,7 +458,19 @@ impl Client {
     }
 
     pub fn init_connection(&mut self, target_id: String, conn: Connection) {
-        self.connections.insert(target_id.clone(), conn);
+        self.connections.insert(target_id.clone(), Transport::Quic(conn));
+    }
+
+    //Reaches the device through the TCP fallback listener it advertises, used when hole punching fails
+    pub async fn new_fallback_connection(&mut self, target_id: String, endpoint: TcpEndpoint) -> Result<()> {
+        let remote_addr = tokio::net::lookup_host((endpoint.host.as_str(), endpoint.port))
+            .await?
+            .next()
+            .with_context(|| format!("No address found for {}", endpoint.host))?;
+
+        info!("Reaching {} over the TCP fallback {}", target_id, endpoint);
+        self.connections.insert(target_id, Transport::Tcp { endpoint, remote_addr });
+        Ok(())
     }
 
     //Create a new connection
@@ -422,6 +511,45 @@ impl Client {
         Ok(true)
     }
 
+    //Connects to a server in direct mode at host[:port], without the coordinator
+    pub async fn new_direct_connection(
+        &mut self,
+        address: String,
+        host_key_fingerprint: Option<String>,
+    ) -> Result<()> {
+        let (host, port) = split_host_port(&address)?;
+        self.direct_targets.insert(
+            address.clone(),
+            DirectTarget {
+                host: host.clone(),
+                port,
+                pinned_fingerprint: host_key_fingerprint,
+            },
+        );
+
+        if let Some(conn) = self.connections.get(&address) {
+            if conn.close_reason().is_none() {
+                info!("Reusing connection for {}", address);
+                return Ok(());
+            }
+        }
+
+        let remote = tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .next()
+            .with_context(|| format!("No address found for {}", host))?;
+        //The endpoint is dual-stack, IPv4 peers are reached through mapped addresses
+        let conn = self
+            .endpoint
+            .connect(map_ipv4_to_ipv6(remote), "server")?
+            .await
+            .with_context(|| format!("Failed to connect to {}", address))?;
+
+        info!("Connected directly to {} at {}", address, remote);
+        self.init_connection(address, conn);
+        Ok(())
+    }
+
     pub fn get_keypair(path: &Path) -> Result<PrivateKey> {
         let private_key_path = path.join("keys/id_ed25519");
         let res = load_secret_key(private_key_path, None)?;
@@ -494,16 +622,7 @@ impl Client {
 
         info!("[client] Connected to: {}", connection.remote_address(),);
 
-        let (mut send, mut recv) = connection
-            .open_bi()
-            .await
-            .map_err(|e| format!("failed to open stream: {}", e))
-            .unwrap();
-
-        let bi_stream = BiStream {
-            recv_stream: recv,
-            send_stream: send,
-        };
+        let stream = connection.open_stream().await?;
 
         let id = if session_id.is_none() {
             Uuid::new_v4().to_string()
@@ -511,6 +630,20 @@ impl Client {
             session_id.unwrap()
         };
 
+        let remote_forwards = RemoteForwardTargets::default();
+
+        let agent_socket = if data.forward_agent {
+            data.agent_socket
+                .clone()
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("SSH_AUTH_SOCK").map(PathBuf::from))
+        } else {
+            None
+        };
+        if data.forward_agent && agent_socket.is_none() {
+            warn!("Agent forwarding requested but no agent socket is available");
+        }
+
         let session_handler = ClientHandler {
             remote_addr: connection.remote_address(),
             server_id: target_id.clone(),
@@ -518,10 +651,13 @@ impl Client {
             known_hosts_path: known_hosts_path.to_path_buf(),
             event_tx: self.event_bus.new_sender().await,
             session_id: id.clone(),
+            remote_forwards: remote_forwards.clone(),
+            agent_socket,
+            direct: self.direct_targets.get(&target_id).cloned(),
         };
 
         let mut handle =
-            russh::client::connect_stream(config, Box::new(bi_stream), session_handler).await?;
+            russh::client::connect_stream(config, stream, session_handler).await?;
 
         //let signal_thread = create_signal_thread();
 
@@ -551,9 +687,11 @@ impl Client {
                 server_messages: EventBus::default(),
             },
             sftp_session: None,
+            sftp_extensions: HashMap::new(),
             closed: Arc::new(AtomicBool::new(false)),
             active: Arc::new(AtomicBool::new(false)),
             event_sender: self.event_bus.new_sender().await,
+            remote_forwards,
         };
 
         self.sessions
@@ -573,11 +711,13 @@ impl client::Handler for ClientHandler {
         server_public_key: &PublicKey,
     ) -> impl std::future::Future<Output = Result<bool, Self::Error>> + Send {
         async move {
-        let host = &self.server_id;
-        let port = 22;
+        let (host, port) = match &self.direct {
+            Some(direct) => (direct.host.clone(), direct.port),
+            None => (self.server_id.clone(), 22),
+        };
 
         let is_known_res = check_known_hosts_path(
-            host,
+            &host,
             port,
             server_public_key,
             &self.known_hosts_path,
@@ -585,6 +725,22 @@ impl client::Handler for ClientHandler {
 
         if let Ok(known) = is_known_res {
             if !known {
+                if let Some(direct) = &self.direct {
+                    let fingerprint = server_public_key
+                        .fingerprint(russh::keys::ssh_key::HashAlg::Sha256)
+                        .to_string();
+                    if direct.pinned_fingerprint.as_deref() != Some(fingerprint.as_str()) {
+                        error!("Server {}:{} is not known. Its host key fingerprint is {}, compare it with the fingerprint the server logs at startup and pass it with --host-key to trust the server",
+                            host, port, fingerprint);
+                        return Ok(false);
+                    }
+                    if let Err(e) = learn_known_hosts_path(&host, port, server_public_key, &self.known_hosts_path) {
+                        error!("Failed to pin host key of {}:{}: {}", host, port, e);
+                        return Ok(false);
+                    }
+                    info!("Pinned host key {} of {}:{}", fingerprint, host, port);
+                    return Ok(true);
+                }
                 error!("Device is not known. You need to sign the target server in coordinator web ui to trust it. Server sent public key {}",
                     server_public_key.to_openssh().unwrap_or("Unknown".parse().unwrap()));
                 return Ok(false);
@@ -606,6 +762,25 @@ impl client::Handler for ClientHandler {
         }
     }
 
+    fn auth_banner(
+        &mut self,
+        banner: &str,
+        session: &mut russh::client::Session,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        let event = ClientEvent {
+            kind: Some(client_event::Kind::Banner(client_event::BannerEvent {
+                session_id: self.session_id.clone(),
+                device_id: self.server_id.clone(),
+                message: banner.to_string(),
+            })),
+        };
+        async move {
+            info!("Received banner from {}", self.server_id);
+            let _ = self.event_tx.send(event);
+            Ok(())
+        }
+    }
+
     fn channel_close(
         &mut self,
         channel: ChannelId,
@@ -617,6 +792,77 @@ impl client::Handler for ClientHandler {
         }
     }
 
+    //Opened by the server for every connection to a remote forward listener
+    fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut russh::client::Session,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        let key = (connected_address.to_string(), connected_port);
+        let originator = format!("{}:{}", originator_address, originator_port);
+        async move {
+            let target = self.remote_forwards.lock().await.get(&key).cloned();
+            let Some((local_host, local_port)) = target else {
+                warn!("No remote forward registered for {}:{}", key.0, key.1);
+                return Ok(());
+            };
+
+            info!(
+                "Forwarding {} from {}:{} to {}:{}",
+                originator, key.0, key.1, local_host, local_port
+            );
+
+            tokio::spawn(async move {
+                let mut stream = match TcpStream::connect((local_host.as_str(), local_port as u16)).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to connect to {}:{}: {}", local_host, local_port, e);
+                        return;
+                    }
+                };
+                let mut channel_stream = channel.into_stream();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream).await;
+            });
+            Ok(())
+        }
+    }
+
+    //Opened by the server for every connection to the forwarded agent socket
+    fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut russh::client::Session,
+    ) -> impl std::future::Future<Output = Result<(), Self::Error>> + Send {
+        let agent_socket = self.agent_socket.clone();
+        async move {
+            let Some(agent_socket) = agent_socket else {
+                warn!("Rejecting agent channel, agent forwarding is not enabled for this session");
+                let _ = channel.close().await;
+                return Ok(());
+            };
+
+            #[cfg(unix)]
+            tokio::spawn(async move {
+                let mut stream = match tokio::net::UnixStream::connect(&agent_socket).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        error!("Failed to connect to agent at {:?}: {}", agent_socket, e);
+                        return;
+                    }
+                };
+                let mut channel_stream = channel.into_stream();
+                let _ = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream).await;
+            });
+            #[cfg(not(unix))]
+            warn!("Agent forwarding to {:?} is not supported on this platform", agent_socket);
+            Ok(())
+        }
+    }
+
     /*     async fn channel_accept_stream(&mut self,
         id: ChannelId) -> Result<Option<Box<dyn SubStream>>, Self::Error> {
 
@@ -644,6 +890,44 @@ impl Session {
         return self.active.store(true, Ordering::SeqCst);
     }
 
+    //The persistent server side session the shell of this session runs in
+    pub fn persistent_id(&self) -> Option<String> {
+        match &self.data.kind {
+            Some(SessionKind::Pty(pty)) => pty.persistent_id.clone(),
+            _ => None,
+        }
+    }
+
+    //The next channel opened on this session attaches to the given persistent session
+    pub fn set_persistent_id(&mut self, persistent_id: String) -> Result<()> {
+        if self.is_active() {
+            bail!("Session already has an open channel");
+        }
+        self.data.kind = Some(SessionKind::Pty(PtySession {
+            persistent_id: Some(persistent_id),
+        }));
+        Ok(())
+    }
+
+    pub async fn list_persistent_sessions(&self) -> Result<Vec<PersistentSessionInfo>> {
+        let mut channel = self.handle.channel_open_session().await?;
+        channel
+            .request_subsystem(true, PERSISTENT_SESSIONS_SUBSYSTEM)
+            .await?;
+
+        let mut output = Vec::new();
+        while let Some(msg) = channel.wait().await {
+            match msg {
+                ChannelMsg::Data { ref data } => output.extend_from_slice(data),
+                ChannelMsg::Failure => bail!("Server does not support persistent sessions"),
+                ChannelMsg::Eof | ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+
+        Ok(serde_json::from_slice(&output)?)
+    }
+
     pub async fn direct_tcpip_forward(
         session: Arc<Mutex<Session>>,
         local_host: &str,
@@ -697,6 +981,133 @@ impl Session {
         Ok(())
     }
 
+    /// Accepts connections on local_socket_path, or local_host:local_port if there is none,
+    /// and forwards each one to the Unix socket at remote_socket_path on the server
+    pub async fn direct_streamlocal_forward(
+        session: Arc<Mutex<Session>>,
+        local_host: &str,
+        local_port: u32,
+        local_socket_path: Option<&str>,
+        remote_socket_path: &str,
+    ) -> Result<()> {
+        let remote_socket_path = remote_socket_path.to_string();
+
+        let (closed, active) = {
+            let session = session.lock().await;
+            (session.closed.clone(), session.active.clone())
+        };
+
+        if let Some(local_socket_path) = local_socket_path {
+            #[cfg(unix)]
+            {
+                let listener = tokio::net::UnixListener::bind(local_socket_path)?;
+                tokio::spawn(async move {
+                    while !closed.load(Ordering::SeqCst) {
+                        let Ok((stream, _)) = listener.accept().await else {
+                            continue;
+                        };
+                        Session::forward_to_streamlocal(
+                            session.clone(),
+                            stream,
+                            remote_socket_path.clone(),
+                            active.clone(),
+                        );
+                    }
+                });
+                return Ok(());
+            }
+            #[cfg(not(unix))]
+            bail!("Listening on {} requires Unix socket support", local_socket_path);
+        }
+
+        let listener = TcpListener::bind((local_host, local_port as u16)).await?;
+        tokio::spawn(async move {
+            while !closed.load(Ordering::SeqCst) {
+                let Ok((stream, _)) = listener.accept().await else {
+                    continue;
+                };
+                Session::forward_to_streamlocal(
+                    session.clone(),
+                    stream,
+                    remote_socket_path.clone(),
+                    active.clone(),
+                );
+            }
+        });
+        Ok(())
+    }
+
+    fn forward_to_streamlocal<S>(
+        session: Arc<Mutex<Session>>,
+        mut stream: S,
+        remote_socket_path: String,
+        active: Arc<AtomicBool>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let channel = {
+                let session = session.lock().await;
+                session
+                    .handle
+                    .channel_open_direct_streamlocal(remote_socket_path.clone())
+                    .await
+            };
+            let channel = match channel {
+                Ok(channel) => channel,
+                Err(e) => {
+                    error!("Failed to open direct-streamlocal to {}: {}", remote_socket_path, e);
+                    return;
+                }
+            };
+            active.store(true, Ordering::SeqCst);
+
+            let mut channel_stream = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream).await;
+        });
+    }
+
+    /// Asks the server to listen on remote_host:remote_port and forward every connection
+    /// back to local_host:local_port. Returns the port the server is listening on.
+    pub async fn remote_tcpip_forward(
+        session: Arc<Mutex<Session>>,
+        remote_host: &str,
+        remote_port: u32,
+        local_host: &str,
+        local_port: u32,
+    ) -> Result<u32> {
+        let session = session.lock().await;
+        let target = (local_host.to_string(), local_port);
+
+        //Registering before the request so that early connections are not dropped
+        session
+            .remote_forwards
+            .lock()
+            .await
+            .insert((remote_host.to_string(), remote_port), target.clone());
+
+        let bound_port = match session.handle.tcpip_forward(remote_host, remote_port).await {
+            Ok(port) => port,
+            Err(e) => {
+                session
+                    .remote_forwards
+                    .lock()
+                    .await
+                    .remove(&(remote_host.to_string(), remote_port));
+                return Err(e.into());
+            }
+        };
+
+        if bound_port != remote_port {
+            let mut forwards = session.remote_forwards.lock().await;
+            forwards.remove(&(remote_host.to_string(), remote_port));
+            forwards.insert((remote_host.to_string(), bound_port), target);
+        }
+
+        session.set_active();
+        Ok(bound_port)
+    }
+
     //We will have to do this separetely here because Channel::into_stream() consumes the channel
     pub async fn request_sftp(&mut self) -> Result<ChannelId> {
         let mut channel = self.handle.channel_open_session().await?;
@@ -706,11 +1117,14 @@ impl Session {
         channel.request_subsystem(true, "sftp").await?;
         info!("Subsystem requested!");
 
-        let sftp = SftpSession::new(channel.into_stream()).await?;
-        info!("session created!");
+        // The raw session exposes the longnames of directory entries
+        let sftp = RawSftpSession::new(channel.into_stream());
+        let version = sftp.init().await?;
+        info!("SFTP session created, version {}", version.version);
         self.set_active();
 
-        self.sftp_session = Option::from(sftp);
+        self.sftp_extensions = version.extensions;
+        self.sftp_session = Some(Arc::new(sftp));
 
         Ok(channel_id)
     }
@@ -727,6 +1141,8 @@ impl Session {
         let channel_id = self.id.clone();
 
         let closed = { self.closed.clone() };
+        let persistent_id = self.persistent_id();
+        let forward_agent = self.data.forward_agent;
 
         tokio::spawn(async move {
             while !closed.load(Ordering::SeqCst) {
@@ -747,6 +1163,12 @@ impl Session {
                                 }
                             }
                             Some(Type::ShellRequest(_)) => {
+                                if let Some(persistent_id) = &persistent_id {
+                                    let _ = channel.set_env(false, PERSISTENT_SESSION_ENV, persistent_id.as_str()).await;
+                                }
+                                if forward_agent {
+                                    let _ = channel.agent_forward(false).await;
+                                }
                                 //This will start the PTY data stream from server to client
                                 let _ = channel.request_shell(false).await;
                             }
@@ -766,6 +1188,9 @@ impl Session {
                             Some(Type::PtyResize(req)) => {
                                 let _ = channel.window_change(req.col_width, req.row_height, 0, 0).await;
                             }
+                            Some(Type::EnvRequest(req)) => {
+                                let _ = channel.set_env(false, req.name, req.value).await;
+                            }
                             Some(_) => {}
                             None => {}
                         }
@@ -789,6 +1214,22 @@ impl Session {
                                 channel.eof().await;
                                 break;
                             }
+                            Some(ChannelMsg::ExitSignal { signal_name, core_dumped, .. }) => {
+                                info!("Channel received exit signal! {:?}", signal_name);
+                                let _ = event_sender.send(ClientEvent {
+                                    kind: Some(client_event::Kind::Close(CloseEvent {
+                                        stream_type: client_event::StreamType::Channel.into(),
+                                        close_reason: if core_dumped {
+                                            format!("Killed by signal {:?} (core dumped)", signal_name)
+                                        } else {
+                                            format!("Killed by signal {:?}", signal_name)
+                                        },
+                                        id: channel_id
+                                    }))
+                                });
+                                channel.eof().await;
+                                break;
+                            }
                             None => {
                                 //This is usually called when timeout happens
                                 let _ = event_sender.send(ClientEvent {
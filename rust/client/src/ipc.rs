@@ -16,6 +16,8 @@ use clientipc::{
     FileWriteResponse, GenKeysRequest, GenKeysResponse, GetKeyRequest, GetSaveDataRequest,
     LocalPortForwardRequest, LocalPortForwardResponse, Msg, NatFilterRequest, NatFilterResponse,
     NewConnectionRequest, NewConnectionResponse, NewSessionRequest, NewSessionResponse, PublicKey,
+    RemotePortForwardResponse, LocalSocketForwardResponse, PersistentSessionsRequest, PersistentSessionList,
+    PersistentSessionInfo, ReattachRequest, ReattachResponse,
     SessionCloseRequest, SessionCloseResponse, SessionData, SessionMap, SessionRequest,
     SettingCheckRequest, SettingCheckResponse, Settings, SettingsRequest, SftpRequest,
     SftpRequestResponse, StreamResponse, SubscribeRequest, UserData, Value,
@@ -23,10 +25,15 @@ use clientipc::{
     CoordinatorStatusRequest, CoordinatorStatusResponse, DeviceInfo,
 };
 use clientipc::{FileDeleteResponse, FileRenameRequest, FileRenameResponse};
+use clientipc::{
+    FileChecksumRequest, FileChecksumResponse, FileCopyRequest, FileCopyResponse, FileLinkRequest,
+    FileLinkResponse, FileSystemStatsResponse,
+};
 use futures::{stream, Stream, StreamExt};
 use log4rs::append::file;
 use quinn::Connection;
-use russh_sftp::{client::SftpSession, protocol::Stat};
+use russh_sftp::client::error::Error as SftpError;
+use russh_sftp::protocol::{FileAttributes, StatusCode};
 use serde::ser::{Serialize, SerializeMap, SerializeSeq, SerializeStruct, Serializer};
 use sessio_coordinator_common::coordinator_client::CoordinatorClient;
 use std::{any::Any, collections::HashMap, net::Ipv6Addr, path::PathBuf, pin::Pin, sync::Arc};
@@ -40,10 +47,12 @@ use url::Url;
 use uuid::Uuid;
 
 use crate::client::{Client, Session};
+use crate::sftp_extensions;
 use log::{info, warn};
 use tokio::io::BufReader;
 use tokio::sync::Mutex;
 use common;
+use common::utils::tcp_fallback::{TcpEndpoint, TCP_ENDPOINT_METADATA};
 
 #[cfg(windows)]
 use tokio::net::TcpListener;
@@ -62,6 +71,56 @@ use std::path::Path;
 use common::utils::keygen::generate_keypair;
 use russh_sftp::protocol::OpenFlags;
 
+/// Bytes read or written per SFTP request, what OpenSSH's sftp-server allows
+const SFTP_CHUNK_SIZE: u32 = 255 * 1024;
+
+/// Fails with Unimplemented unless the session's SFTP server supports `extension`
+fn require_extension(session: &Session, extension: &str) -> Result<(), Status> {
+    if !session.sftp_extensions.contains_key(extension) {
+        return Err(Status::new(
+            tonic::Code::Unimplemented,
+            format!("The SFTP server doesn't support {}", extension),
+        ));
+    }
+    Ok(())
+}
+
+/// The canonical path of `path` on the server, None if it can't be resolved
+async fn sftp_realpath(sftp: &russh_sftp::client::RawSftpSession, path: &str) -> Option<String> {
+    let name = sftp.realpath(path).await.ok()?;
+    name.files.into_iter().next().map(|file| file.filename)
+}
+
+/// A directory entry of `dir` for the GUI. The owner names come from the
+/// `ls -l` longname, the attributes only carry numeric IDs.
+fn file_data(dir: &str, file: russh_sftp::protocol::File) -> FileData {
+    let mut fields = file.longname.split_whitespace();
+    let owners = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+        (Some(mode), Some(_links), Some(owner), Some(group)) if mode.len() == 10 => {
+            Some((owner.to_string(), group.to_string()))
+        }
+        _ => None,
+    };
+    let (owner, group) = match owners {
+        Some((owner, group)) => (Some(owner), Some(group)),
+        None => (
+            file.attrs.uid.map(|uid| uid.to_string()),
+            file.attrs.gid.map(|gid| gid.to_string()),
+        ),
+    };
+
+    FileData {
+        file_path: format!("{0}/{1}", dir, file.filename),
+        file_size: file.attrs.size.unwrap_or(0),
+        is_dir: file.attrs.file_type().is_dir(),
+        mtime: file.attrs.mtime.map(u64::from),
+        mode: file.attrs.permissions,
+        owner,
+        group,
+        file_name: file.filename,
+    }
+}
+
 struct ClientIpcHandler {
     client: Arc<Mutex<Client>>,
 }
@@ -248,6 +307,148 @@ impl ClientIpc for ClientIpcHandler {
         Ok(Response::new(LocalPortForwardResponse {}))
     }
 
+    async fn local_socket_forward(
+        &self,
+        request: Request<SessionData>,
+    ) -> Result<Response<LocalSocketForwardResponse>, Status> {
+        let request = request.into_inner();
+        let Some(crate::ipc::clientipc::session_data::Kind::Lsf(ref lsf_data)) = request.kind
+        else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Session kind must be LSF",
+            ));
+        };
+
+        let Some(session_id) = request.session_id.as_ref() else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Session id is required",
+            ));
+        };
+
+        let session = {
+            let client = self.client.lock().await;
+
+            match client.sessions.get(session_id) {
+                Some(session) => session.clone(),
+                None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+            }
+        };
+
+        Session::direct_streamlocal_forward(
+            session,
+            &lsf_data.local_host,
+            lsf_data.local_port,
+            lsf_data.local_socket_path.as_deref(),
+            &lsf_data.remote_socket_path,
+        )
+        .await
+        .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(Response::new(LocalSocketForwardResponse {}))
+    }
+
+    async fn list_persistent_sessions(
+        &self,
+        request: Request<PersistentSessionsRequest>,
+    ) -> Result<Response<PersistentSessionList>, Status> {
+        let request = request.into_inner();
+
+        let session = {
+            let client = self.client.lock().await;
+
+            match client.sessions.get(&request.session_id) {
+                Some(session) => session.clone(),
+                None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+            }
+        };
+
+        let sessions = session
+            .lock()
+            .await
+            .list_persistent_sessions()
+            .await
+            .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(Response::new(PersistentSessionList {
+            sessions: sessions
+                .into_iter()
+                .map(|s| PersistentSessionInfo {
+                    id: s.id,
+                    created_at: s.created_at,
+                    attached: s.attached,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn reattach_session(
+        &self,
+        request: Request<ReattachRequest>,
+    ) -> Result<Response<ReattachResponse>, Status> {
+        let request = request.into_inner();
+
+        let session = {
+            let client = self.client.lock().await;
+
+            match client.sessions.get(&request.session_id) {
+                Some(session) => session.clone(),
+                None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+            }
+        };
+
+        session
+            .lock()
+            .await
+            .set_persistent_id(request.persistent_id)
+            .map_err(|e| Status::new(tonic::Code::FailedPrecondition, e.to_string()))?;
+
+        Ok(Response::new(ReattachResponse {}))
+    }
+
+    async fn remote_port_forward(
+        &self,
+        request: Request<SessionData>,
+    ) -> Result<Response<RemotePortForwardResponse>, Status> {
+        let request = request.into_inner();
+        let Some(crate::ipc::clientipc::session_data::Kind::Rpf(ref rpf_data)) = request.kind
+        else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Session kind must be RPF",
+            ));
+        };
+
+        let Some(session_id) = request.session_id.as_ref() else {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Session id is required",
+            ));
+        };
+
+        let session = {
+            let client = self.client.lock().await;
+
+            match client.sessions.get(session_id) {
+                Some(session) => session.clone(),
+                None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+            }
+        };
+
+        let bound_port = Session::remote_tcpip_forward(
+            session,
+            &rpf_data.remote_host,
+            rpf_data.remote_port,
+            &rpf_data.local_host,
+            rpf_data.local_port,
+        )
+        .await
+        .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
+
+        Ok(Response::new(RemotePortForwardResponse { bound_port }))
+    }
+
     async fn get_settings(
         &self,
         request: Request<SettingsRequest>,
@@ -588,11 +789,11 @@ impl ClientIpc for ClientIpcHandler {
         };
         for file_data in request.data {
             if file_data.is_dir {
-                if let Err(e) = sftp.remove_dir(file_data.path).await {
+                if let Err(e) = sftp.rmdir(file_data.path).await {
                     return Err(Status::new(tonic::Code::Internal, e.to_string()));
                 }
             } else {
-                if let Err(e) = sftp.remove_file(file_data.path).await {
+                if let Err(e) = sftp.remove(file_data.path).await {
                     return Err(Status::new(tonic::Code::Internal, e.to_string()));
                 }
             }
@@ -614,12 +815,175 @@ impl ClientIpc for ClientIpcHandler {
         let Some(sftp) = &session.sftp_session else {
             return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
         };
-        if let Err(e) = sftp.rename(request.old_path, request.new_path).await {
+        let result = if request.replace {
+            require_extension(&session, sftp_extensions::POSIX_RENAME)?;
+            sftp_extensions::posix_rename(sftp, &request.old_path, &request.new_path).await
+        } else {
+            sftp.rename(request.old_path, request.new_path).await.map(|_| ())
+        };
+        if let Err(e) = result {
             return Err(Status::new(tonic::Code::Internal, e.to_string()));
         }
         Ok(Response::new(FileRenameResponse {}))
     }
 
+    async fn file_link(
+        &self,
+        request: Request<FileLinkRequest>,
+    ) -> Result<Response<FileLinkResponse>, Status> {
+        let request = request.into_inner();
+        let mut client = self.client.lock().await;
+        let session_guard = match client.sessions.get_mut(&request.session_id) {
+            Some(session) => session,
+            None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+        };
+        let session = session_guard.lock().await;
+        let Some(sftp) = &session.sftp_session else {
+            return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
+        };
+        require_extension(&session, sftp_extensions::HARDLINK)?;
+        if let Err(e) = sftp.hardlink(request.old_path, request.new_path).await {
+            return Err(Status::new(tonic::Code::Internal, e.to_string()));
+        }
+        Ok(Response::new(FileLinkResponse {}))
+    }
+
+    async fn file_copy(
+        &self,
+        request: Request<FileCopyRequest>,
+    ) -> Result<Response<FileCopyResponse>, Status> {
+        let request = request.into_inner();
+        let mut client = self.client.lock().await;
+        let session_guard = match client.sessions.get_mut(&request.session_id) {
+            Some(session) => session,
+            None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+        };
+        let session = session_guard.lock().await;
+        let Some(sftp) = &session.sftp_session else {
+            return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
+        };
+        require_extension(&session, sftp_extensions::COPY_DATA)?;
+
+        // Copying a file onto itself would destroy it
+        let source_path = sftp_realpath(sftp, &request.source_path).await;
+        if source_path.is_some() && source_path == sftp_realpath(sftp, &request.destination_path).await {
+            return Err(Status::new(
+                tonic::Code::InvalidArgument,
+                "Source and destination are the same file",
+            ));
+        }
+
+        let source = match sftp
+            .open(request.source_path, OpenFlags::READ, FileAttributes::empty())
+            .await
+        {
+            Ok(handle) => handle.handle,
+            Err(e) => return Err(Status::new(tonic::Code::NotFound, e.to_string())),
+        };
+        let source_attrs = match sftp.fstat(source.as_str()).await {
+            Ok(attrs) => attrs.attrs,
+            Err(e) => {
+                let _ = sftp.close(source).await;
+                return Err(Status::new(tonic::Code::Internal, e.to_string()));
+            }
+        };
+        // The copy gets the permissions of the source, like cp
+        let mut attrs = FileAttributes::empty();
+        attrs.permissions = source_attrs.permissions;
+        // Not truncated before the copy: the server refuses to copy a file onto
+        // itself, e.g. through a hard link, and truncating first would empty it
+        let destination = match sftp
+            .open(request.destination_path, OpenFlags::CREATE | OpenFlags::WRITE, attrs)
+            .await
+        {
+            Ok(handle) => handle.handle,
+            Err(e) => {
+                let _ = sftp.close(source).await;
+                return Err(Status::new(tonic::Code::Internal, e.to_string()));
+            }
+        };
+
+        let mut result = sftp_extensions::copy_data(sftp, &source, &destination).await;
+        if result.is_ok() {
+            // Cut off what is left of a longer file the copy replaced
+            let mut size = FileAttributes::empty();
+            size.size = source_attrs.size;
+            result = sftp.fsetstat(destination.as_str(), size).await.map(|_| ());
+        }
+        let _ = sftp.close(source).await;
+        let _ = sftp.close(destination).await;
+        if let Err(e) = result {
+            return Err(Status::new(tonic::Code::Internal, e.to_string()));
+        }
+        Ok(Response::new(FileCopyResponse {}))
+    }
+
+    async fn file_checksum(
+        &self,
+        request: Request<FileChecksumRequest>,
+    ) -> Result<Response<FileChecksumResponse>, Status> {
+        let request = request.into_inner();
+        let mut client = self.client.lock().await;
+        let session_guard = match client.sessions.get_mut(&request.session_id) {
+            Some(session) => session,
+            None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+        };
+        let session = session_guard.lock().await;
+        let Some(sftp) = &session.sftp_session else {
+            return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
+        };
+        require_extension(&session, sftp_extensions::CHECK_FILE_NAME)?;
+
+        let algorithms = match request.algorithms.is_empty() {
+            true => sftp_extensions::DEFAULT_HASH_ALGORITHMS
+                .iter()
+                .map(|algorithm| algorithm.to_string())
+                .collect(),
+            false => request.algorithms,
+        };
+        let (algorithm, hash) =
+            match sftp_extensions::check_file(sftp, &request.path, &algorithms).await {
+                Ok(checksum) => checksum,
+                Err(e) => return Err(Status::new(tonic::Code::Internal, e.to_string())),
+            };
+        Ok(Response::new(FileChecksumResponse {
+            algorithm,
+            checksum: hash.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }))
+    }
+
+    async fn file_system_stats(
+        &self,
+        request: Request<clientipc::Path>,
+    ) -> Result<Response<FileSystemStatsResponse>, Status> {
+        let request = request.into_inner();
+        let mut client = self.client.lock().await;
+        let session_guard = match client.sessions.get_mut(&request.session_id) {
+            Some(session) => session,
+            None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
+        };
+        let session = session_guard.lock().await;
+        let Some(sftp) = &session.sftp_session else {
+            return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
+        };
+        require_extension(&session, sftp_extensions::STATVFS)?;
+
+        let stats = match sftp.statvfs(request.path).await {
+            Ok(stats) => stats,
+            Err(e) => return Err(Status::new(tonic::Code::Internal, e.to_string())),
+        };
+        // Block counts are in fragments
+        Ok(Response::new(FileSystemStatsResponse {
+            block_size: stats.block_size,
+            total_bytes: stats.blocks.saturating_mul(stats.fragment_size),
+            free_bytes: stats.blocks_free.saturating_mul(stats.fragment_size),
+            available_bytes: stats.blocks_avail.saturating_mul(stats.fragment_size),
+            total_inodes: stats.inodes,
+            free_inodes: stats.inodes_free,
+            read_only: stats.flags & 0x1 != 0,
+        }))
+    }
+
     async fn list_directory(
         &self,
         request: Request<clientipc::Path>,
@@ -635,25 +999,33 @@ impl ClientIpc for ClientIpcHandler {
         let Some(sftp) = &session.sftp_session else {
             return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
         };
-        //info!("current path: {:?}", sftp.canonicalize(&request.path).await.unwrap());
 
-        let dir = match sftp.read_dir(&request.path).await {
-            Ok(dir) => dir,
+        let handle = match sftp.opendir(request.path.as_str()).await {
+            Ok(handle) => handle.handle,
             Err(e) => {
                 return Err(Status::new(tonic::Code::NotFound, e.to_string()));
             }
         };
 
+        // The server sends the entries in batches until it reports the end
         let mut list = Vec::<FileData>::new();
-        for entry in dir {
-            info!("DIR FILE {}", entry.file_name());
-            list.push(FileData {
-                file_name: entry.file_name(),
-                file_path: format!("{0}/{1}", &request.path, entry.file_name()),
-                file_size: entry.metadata().size.unwrap_or(0),
-                is_dir: entry.metadata().is_dir(),
-            });
+        let result = loop {
+            match sftp.readdir(handle.as_str()).await {
+                Ok(name) => list.extend(
+                    name.files
+                        .into_iter()
+                        .filter(|file| file.filename != "." && file.filename != "..")
+                        .map(|file| file_data(&request.path, file)),
+                ),
+                Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        let _ = sftp.close(handle).await;
+        if let Err(e) = result {
+            return Err(Status::new(tonic::Code::Internal, e.to_string()));
         }
+
         Ok(Response::new(FileList { files: list }))
     }
 
@@ -668,20 +1040,21 @@ impl ClientIpc for ClientIpcHandler {
             None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
         };
         let session = session_guard.lock().await;
-        let Some(sftp) = &session.sftp_session else {
+        let Some(sftp) = session.sftp_session.clone() else {
             return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
         };
-        let mut remote_file = sftp
-            .open_with_flags(
-                request.remote_path,
-                OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE | OpenFlags::READ,
-            )
+        let handle = match sftp
+            .open(request.remote_path, OpenFlags::READ, FileAttributes::empty())
             .await
-            .unwrap();
+        {
+            Ok(handle) => handle.handle,
+            Err(e) => return Err(Status::new(tonic::Code::NotFound, e.to_string())),
+        };
 
         let mut local_file = match File::create(&request.local_path).await {
             Ok(file) => file,
             Err(e) => {
+                let _ = sftp.close(handle).await;
                 return Err(Status::new(
                     tonic::Code::Internal,
                     format!("Failed to create local file: {}", e),
@@ -690,20 +1063,22 @@ impl ClientIpc for ClientIpcHandler {
         };
 
         let res = async_stream::try_stream! {
-            let mut buf = vec![0u8; 1024*512];
+            let mut offset: u64 = 0;
             let mut bytes_read: i32 = 0;
             loop {
-                let n: usize = match remote_file.read(&mut buf).await {
-                    Ok(n) if n == 0 => break, // EOF
-                    Ok(n) => n,
+                let data = match sftp.read(handle.as_str(), offset, SFTP_CHUNK_SIZE).await {
+                    Ok(data) if data.data.is_empty() => break,
+                    Ok(data) => data.data,
+                    Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => break,
                     Err(e) => {
                         //yield Err(Status::new(tonic::Code::Internal, format!("Failed to read from remote file: {}", e)));
                         log::error!("Failed to read from remote file: {}", e);
                         break;
                     },
                 };
-                bytes_read += n as i32;
-                if let Err(e) = local_file.write_all(&buf[..n]).await {
+                offset += data.len() as u64;
+                bytes_read += data.len() as i32;
+                if let Err(e) = local_file.write_all(&data).await {
                     //yield Err(Status::new(tonic::Code::Internal, format!("Failed to write to local file: {}", e)));
                     log::error!("Failed to write to local file: {}", e);
                     break;
@@ -719,6 +1094,7 @@ impl ClientIpc for ClientIpcHandler {
 
                 yield file_transfer_status;
             }
+            let _ = sftp.close(handle).await;
             yield FileTransferStatus {
                 typ: Some(Typ::Completed(Default::default())),
             };
@@ -738,16 +1114,10 @@ impl ClientIpc for ClientIpcHandler {
             None => return Err(Status::new(tonic::Code::NotFound, "Session not found")),
         };
         let session = session_guard.lock().await;
-        let Some(sftp) = &session.sftp_session else {
+        let Some(sftp) = session.sftp_session.clone() else {
             return Err(Status::new(tonic::Code::NotFound, "SFTP Session not found"));
         };
-        let mut remote_file = sftp
-            .open_with_flags(
-                request.remote_path,
-                OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE | OpenFlags::READ,
-            )
-            .await
-            .unwrap();
+        let fsync = session.sftp_extensions.contains_key(sftp_extensions::FSYNC);
 
         info!("Opening {}", request.local_path);
         let mut local_file = match File::open(&request.local_path).await {
@@ -760,8 +1130,21 @@ impl ClientIpc for ClientIpcHandler {
             }
         };
 
+        let handle = match sftp
+            .open(
+                request.remote_path,
+                OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+                FileAttributes::empty(),
+            )
+            .await
+        {
+            Ok(handle) => handle.handle,
+            Err(e) => return Err(Status::new(tonic::Code::Internal, e.to_string())),
+        };
+
         let res = async_stream::try_stream! {
-            let mut buf = vec![0u8; 1024*512];
+            let mut buf = vec![0u8; SFTP_CHUNK_SIZE as usize];
+            let mut offset: u64 = 0;
             let mut bytes_written: i32 = 0;
             loop {
                 let n = match local_file.read(&mut buf).await {
@@ -774,7 +1157,7 @@ impl ClientIpc for ClientIpcHandler {
                 };
 
                 let start_time = Instant::now();
-                if let Err(e) = remote_file.write_all(&buf[..n]).await {
+                if let Err(e) = sftp.write(handle.as_str(), offset, buf[..n].to_vec()).await {
                     log::error!("Failed to write to remote file: {}", e);
                     break;
                     //return Err(Status::new(tonic::Code::Internal, format!("Failed to write to remote file: {}", e)));
@@ -783,6 +1166,7 @@ impl ClientIpc for ClientIpcHandler {
 
                 log::info!("Buffer written in {:?}", elapsed_time);
 
+                offset += n as u64;
                 bytes_written += n as i32;
                 let progress = Progress {
                     bytes_read: bytes_written
@@ -794,6 +1178,13 @@ impl ClientIpc for ClientIpcHandler {
 
                 yield file_transfer_status;
             }
+            // The upload is on disk once it's reported done
+            if fsync {
+                if let Err(e) = sftp.fsync(handle.as_str()).await {
+                    log::error!("Failed to sync remote file: {}", e);
+                }
+            }
+            let _ = sftp.close(handle).await;
         };
 
         Ok(Response::new(Box::pin(res) as Self::FileUploadStream))
@@ -805,9 +1196,22 @@ impl ClientIpc for ClientIpcHandler {
     ) -> Result<Response<NewConnectionResponse>, Status> {
         let request = request.into_inner();
 
+        if let Some(address) = request.direct_address {
+            let mut client = self.client.lock().await;
+            return match client
+                .new_direct_connection(address.clone(), request.host_key_fingerprint)
+                .await
+            {
+                Ok(()) => Ok(Response::new(NewConnectionResponse {
+                    connection_id: address,
+                })),
+                Err(e) => Err(Status::new(tonic::Code::Unavailable, format!("{:#}", e))),
+            };
+        }
+
         let (conn_tx, mut conn_rx) = mpsc::channel::<Connection>(1);
 
-        {
+        let holepunch_error = {
             let mut client = self.client.lock().await;
 
             match client
@@ -821,27 +1225,51 @@ impl ClientIpc for ClientIpcHandler {
                             connection_id: request.target_id,
                         }));
                     }
+                    None
                 }
-                Err(e) => {
-                    return Err(Status::new(tonic::Code::Internal, e.to_string()));
-                }
+                Err(e) => Some(e.to_string()),
             }
+        };
+
+        let conn = match holepunch_error {
+            Some(_) => None,
+            None => conn_rx.recv().await,
+        };
+
+        if let Some(conn) = conn {
+            log::info!("CONN OK");
+            let mut client = self.client.lock().await;
+            client.init_connection(request.target_id.clone(), conn);
+            return Ok(Response::new(NewConnectionResponse {
+                connection_id: request.target_id,
+            }));
         }
 
-        let conn = conn_rx.recv().await;
+        let holepunch_error =
+            holepunch_error.unwrap_or_else(|| "Failed to open quic connection.".to_string());
+        warn!("Hole punching to {} failed: {}", request.target_id, holepunch_error);
 
-        match conn {
-            Some(conn) => {
-                log::info!("CONN OK");
-                let mut client = self.client.lock().await;
-                client.init_connection(request.target_id.clone(), conn);
-                Ok(Response::new(NewConnectionResponse {
-                    connection_id: request.target_id,
-                }))
+        //Networks blocking UDP can still reach devices that listen on TCP
+        let endpoint = match device_tcp_endpoint(&request.target_id).await {
+            Ok(Some(endpoint)) => endpoint,
+            Ok(None) => return Err(Status::new(tonic::Code::Internal, holepunch_error)),
+            Err(e) => {
+                warn!("Failed to look up a TCP endpoint of {}: {}", request.target_id, e);
+                return Err(Status::new(tonic::Code::Internal, holepunch_error));
             }
-            None => Err(Status::new(
-                tonic::Code::Internal,
-                "Failed to open quic connection.",
+        };
+
+        let mut client = self.client.lock().await;
+        match client
+            .new_fallback_connection(request.target_id.clone(), endpoint)
+            .await
+        {
+            Ok(()) => Ok(Response::new(NewConnectionResponse {
+                connection_id: request.target_id,
+            })),
+            Err(e) => Err(Status::new(
+                tonic::Code::Unavailable,
+                format!("{}, and the TCP fallback failed: {:#}", holepunch_error, e),
             )),
         }
     }
@@ -1002,7 +1430,7 @@ impl ClientIpc for ClientIpcHandler {
                 while let Some(Ok(msg)) = stream.next().await {
                     match msg.r#type {
                         
-                        Some(Type::ShellRequest(_) | Type::PtyRequest(_)) if !active => {
+                        Some(Type::ShellRequest(_) | Type::PtyRequest(_) | Type::EnvRequest(_)) if !active => {
                             let _ = server_msg_sender.send(msg);
                         }
                         Some(Type::Data(_) | Type::PtyResize(_)) => {
@@ -1046,43 +1474,7 @@ impl ClientIpc for ClientIpcHandler {
         &self,
         request: Request<CoordinatorStatusRequest>,
     ) -> Result<Response<CoordinatorStatusResponse>, Status> {
-        let client = self.client.lock().await;
-        
-        // Get account data for coordinator URL and JWT token
-        let mut config_manager = crate::config_manager::ClientConfigManager::new()
-            .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
-            
-        let settings = config_manager.load_settings().await
-            .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
-
-        let jwt_token = settings.jwt_token
-            .ok_or_else(|| Status::new(tonic::Code::Unauthenticated, "No JWT token available"))?;
-
-        // Make request to coordinator to get device status
-        let http_client = reqwest::Client::new();
-        let devices_url = format!("{}/devices", settings.coordinator_url);
-        
-        let response = http_client
-            .post(&devices_url)
-            .header("Authorization", format!("Bearer {}", jwt_token))
-            .send()
-            .await
-            .map_err(|e| Status::new(tonic::Code::Internal, format!("Failed to connect to coordinator: {}", e)))?;
-
-        info!("Coordinator : {:?}", response);
-
-        if !response.status().is_success() {
-            return Err(Status::new(
-                tonic::Code::Internal,
-                format!("Coordinator returned error: {}", response.status())
-            ));
-        }
-
-        let devices_json: serde_json::Value = response.json()
-            .await
-            .map_err(|e| Status::new(tonic::Code::Internal, format!("Failed to parse coordinator response: {}", e)))?;
-
-        info!("json : {:?}", devices_json);
+        let devices_json = coordinator_devices().await?;
 
         let mut devices = Vec::new();
         
@@ -1099,6 +1491,9 @@ impl ClientIpc for ClientIpcHandler {
                     } else {
                         Vec::new()
                     },
+                    tcp_endpoint: device["metadata"][TCP_ENDPOINT_METADATA]
+                        .as_str()
+                        .map(str::to_string),
                 };
                 devices.push(device_info);
             }
@@ -1108,6 +1503,60 @@ impl ClientIpc for ClientIpcHandler {
     }
 }
 
+/// The devices of the account as listed by the coordinator
+async fn coordinator_devices() -> Result<serde_json::Value, Status> {
+    // Get account data for coordinator URL and JWT token
+    let mut config_manager = crate::config_manager::ClientConfigManager::new()
+        .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
+        
+    let settings = config_manager.load_settings().await
+        .map_err(|e| Status::new(tonic::Code::Internal, e.to_string()))?;
+
+    let jwt_token = settings.jwt_token
+        .ok_or_else(|| Status::new(tonic::Code::Unauthenticated, "No JWT token available"))?;
+
+    // Make request to coordinator to get device status
+    let http_client = reqwest::Client::new();
+    let devices_url = format!("{}/devices", settings.coordinator_url);
+    
+    let response = http_client
+        .post(&devices_url)
+        .header("Authorization", format!("Bearer {}", jwt_token))
+        .send()
+        .await
+        .map_err(|e| Status::new(tonic::Code::Internal, format!("Failed to connect to coordinator: {}", e)))?;
+
+    info!("Coordinator : {:?}", response);
+
+    if !response.status().is_success() {
+        return Err(Status::new(
+            tonic::Code::Internal,
+            format!("Coordinator returned error: {}", response.status())
+        ));
+    }
+
+    let devices_json: serde_json::Value = response.json()
+        .await
+        .map_err(|e| Status::new(tonic::Code::Internal, format!("Failed to parse coordinator response: {}", e)))?;
+
+    info!("json : {:?}", devices_json);
+
+    Ok(devices_json)
+}
+
+/// The TCP fallback endpoint `device_id` advertises in its coordinator metadata, if any
+async fn device_tcp_endpoint(device_id: &str) -> anyhow::Result<Option<TcpEndpoint>> {
+    let devices_json = coordinator_devices().await.map_err(|e| anyhow::anyhow!("{}", e.message()))?;
+    let endpoint = devices_json["devices"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|device| device["device_id"].as_str() == Some(device_id))
+        .and_then(|device| device["metadata"][TCP_ENDPOINT_METADATA].as_str());
+
+    endpoint.map(TcpEndpoint::parse).transpose()
+}
+
 #[cfg(unix)]
 pub async fn start_grpc_server(path_str: &str) {
     use std::{future::Future, time::Duration};
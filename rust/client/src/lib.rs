@@ -2,6 +2,7 @@
 pub mod ipc;
 pub mod client;
 pub mod config_manager;
+pub mod sftp_extensions;
 
 
 use android_logger::Config;
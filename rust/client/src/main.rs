@@ -6,6 +6,7 @@ use log::error;
 mod client;
 pub mod ipc;
 mod config_manager;
+mod sftp_extensions;
 use homedir::my_home;
 
 #[derive(Parser, Debug)]
@@ -0,0 +1,78 @@
+//! Requests of the SFTP extensions the raw session has no method for
+
+use russh_sftp::client::error::Error;
+use russh_sftp::client::RawSftpSession;
+use russh_sftp::protocol::{Packet, StatusCode};
+use serde::Serialize;
+
+use common::utils::sftp_extensions::{CheckFile, CheckFileReply, CopyData, PathPair};
+pub use common::utils::sftp_extensions::{
+    CHECK_FILE_NAME, COPY_DATA, FSYNC, HARDLINK, POSIX_RENAME, STATVFS,
+};
+
+/// Hash algorithms asked for when the caller doesn't name any, strongest first
+pub const DEFAULT_HASH_ALGORITHMS: &[&str] = &["sha256", "sha512", "sha384", "sha224", "sha1", "md5"];
+
+/// Renames `oldpath`, atomically replacing `newpath` if it exists
+pub async fn posix_rename(sftp: &RawSftpSession, oldpath: &str, newpath: &str) -> Result<(), Error> {
+    let request = PathPair {
+        oldpath: oldpath.to_string(),
+        newpath: newpath.to_string(),
+    };
+    into_result(sftp.extended(POSIX_RENAME, encode(&request)?).await?)
+}
+
+/// Copies the whole file open as `read_handle` into the start of `write_handle`
+/// on the server
+pub async fn copy_data(sftp: &RawSftpSession, read_handle: &str, write_handle: &str) -> Result<(), Error> {
+    let request = CopyData {
+        read_handle: read_handle.to_string(),
+        read_offset: 0,
+        length: 0,
+        write_handle: write_handle.to_string(),
+        write_offset: 0,
+    };
+    into_result(sftp.extended(COPY_DATA, encode(&request)?).await?)
+}
+
+/// Hashes the whole file at `path` on the server with the first of
+/// `algorithms` it supports. Returns the algorithm and the hash.
+pub async fn check_file(
+    sftp: &RawSftpSession,
+    path: &str,
+    algorithms: &[String],
+) -> Result<(String, Vec<u8>), Error> {
+    // The whole file as one block
+    let request = CheckFile {
+        target: path.to_string(),
+        algorithms: algorithms.join(","),
+        offset: 0,
+        length: 0,
+        block_size: 0,
+    };
+
+    let reply = match sftp.extended(CHECK_FILE_NAME, encode(&request)?).await? {
+        Packet::ExtendedReply(reply) => reply,
+        Packet::Status(status) if status.status_code != StatusCode::Ok => {
+            return Err(Error::Status(status))
+        }
+        _ => return Err(Error::UnexpectedPacket),
+    };
+    let reply: CheckFileReply = russh_sftp::de::from_bytes(&mut reply.data.into())
+        .map_err(|_| Error::UnexpectedPacket)?;
+    Ok((reply.algorithm, reply.hashes))
+}
+
+fn encode<T: Serialize>(request: &T) -> Result<Vec<u8>, Error> {
+    russh_sftp::ser::to_bytes(request)
+        .map(|bytes| bytes.to_vec())
+        .map_err(|e| Error::UnexpectedBehavior(e.to_string()))
+}
+
+fn into_result(packet: Packet) -> Result<(), Error> {
+    match packet {
+        Packet::Status(status) if status.status_code == StatusCode::Ok => Ok(()),
+        Packet::Status(status) => Err(Error::Status(status)),
+        _ => Err(Error::UnexpectedPacket),
+    }
+}
@@ -0,0 +1,333 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use chrono::{Local, NaiveDateTime, TimeZone};
+use russh::keys::PublicKey;
+
+/// A key from an authorized_keys file together with its OpenSSH options
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthorizedKey {
+    pub key: PublicKey,
+    /// Comment after the key, the device ID in `device-id@os` format
+    pub comment: String,
+    pub options: KeyOptions,
+}
+
+/// Restrictions from the option prefix of an authorized_keys line
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyOptions {
+    /// `command=`, run instead of whatever the client requests
+    pub command: Option<String>,
+    /// `from=` patterns the client address must match, `!` negates a pattern
+    pub from: Vec<String>,
+    pub no_pty: bool,
+    pub no_port_forwarding: bool,
+    pub no_agent_forwarding: bool,
+    /// `permitopen=` host:port destinations allowed for direct-tcpip
+    pub permit_open: Vec<String>,
+    /// `environment=` variables set for commands of the session
+    pub environment: Vec<(String, String)>,
+    /// `expiry-time=` as a unix timestamp
+    pub expiry_time: Option<u64>,
+}
+
+impl KeyOptions {
+    /// Whether the key may be used from `addr` according to `from=`
+    pub fn allows_source(&self, addr: IpAddr) -> bool {
+        if self.from.is_empty() {
+            return true;
+        }
+
+        let addr = addr.to_canonical();
+        let mut allowed = false;
+        for pattern in &self.from {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern.as_str()),
+            };
+            if address_matches(pattern, addr) {
+                if negated {
+                    return false;
+                }
+                allowed = true;
+            }
+        }
+        allowed
+    }
+
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.is_expired_at(now)
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        self.expiry_time.is_some_and(|expiry| now >= expiry)
+    }
+}
+
+/// Parses one authorized_keys line. Returns None for blank lines and comments.
+pub fn parse_authorized_key_line(line: &str) -> anyhow::Result<Option<AuthorizedKey>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (options, rest) = if is_key_type(line.split_whitespace().next().unwrap_or("")) {
+        (KeyOptions::default(), line)
+    } else {
+        let (options, rest) = split_unquoted(line, |c| c.is_whitespace())?;
+        (parse_options(options)?, rest.unwrap_or("").trim_start())
+    };
+
+    let mut split = rest.split_whitespace();
+
+    // Skip the key type (ssh-ed25519, ssh-rsa, etc.)
+    split.next();
+
+    let key_data = split
+        .next()
+        .with_context(|| format!("Missing key data in authorized key {}", line))?;
+    let key = russh::keys::parse_public_key_base64(key_data)
+        .with_context(|| format!("Failed to read authorized public key {}", line))?;
+    let comment = split.next().unwrap_or("").to_string();
+
+    Ok(Some(AuthorizedKey { key, comment, options }))
+}
+
+fn is_key_type(token: &str) -> bool {
+    token.starts_with("ssh-") || token.starts_with("ecdsa-") || token.starts_with("sk-")
+}
+
+/// Splits `s` at the first `separator` outside of double quotes
+fn split_unquoted(s: &str, separator: impl Fn(char) -> bool) -> anyhow::Result<(&str, Option<&str>)> {
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' && in_quotes {
+            escaped = true;
+        } else if c == '"' {
+            in_quotes = !in_quotes;
+        } else if !in_quotes && separator(c) {
+            return Ok((&s[..i], Some(&s[i + c.len_utf8()..])));
+        }
+    }
+
+    if in_quotes {
+        bail!("Unterminated quote in authorized_keys options {}", s);
+    }
+    Ok((s, None))
+}
+
+fn parse_options(mut options: &str) -> anyhow::Result<KeyOptions> {
+    let mut parsed = KeyOptions::default();
+
+    loop {
+        let (option, rest) = split_unquoted(options, |c| c == ',')?;
+        apply_option(&mut parsed, option)?;
+        match rest {
+            Some(rest) => options = rest,
+            None => break,
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn apply_option(options: &mut KeyOptions, option: &str) -> anyhow::Result<()> {
+    let (name, value) = match option.split_once('=') {
+        Some((name, value)) => (name, Some(unquote(value))),
+        None => (option, None),
+    };
+
+    match (name.to_ascii_lowercase().as_str(), value) {
+        ("command", Some(value)) => options.command = Some(value),
+        ("from", Some(value)) => {
+            options.from = value.split(',').map(|p| p.trim().to_string()).collect()
+        }
+        ("permitopen", Some(value)) => options.permit_open.push(value),
+        ("environment", Some(value)) => {
+            let (key, value) = value
+                .split_once('=')
+                .with_context(|| format!("Invalid environment option {}", option))?;
+            options.environment.push((key.to_string(), value.to_string()));
+        }
+        ("expiry-time", Some(value)) => options.expiry_time = Some(parse_expiry_time(&value)?),
+        ("restrict", None) => {
+            options.no_pty = true;
+            options.no_port_forwarding = true;
+            options.no_agent_forwarding = true;
+        }
+        ("no-pty", None) => options.no_pty = true,
+        ("pty", None) => options.no_pty = false,
+        ("no-port-forwarding", None) => options.no_port_forwarding = true,
+        ("port-forwarding", None) => options.no_port_forwarding = false,
+        ("no-agent-forwarding", None) => options.no_agent_forwarding = true,
+        ("agent-forwarding", None) => options.no_agent_forwarding = false,
+        // The server has neither X11 forwarding nor user rc files
+        ("no-x11-forwarding" | "x11-forwarding" | "no-user-rc" | "user-rc", None) => {}
+        _ => bail!("Unsupported authorized_keys option {}", option),
+    }
+
+    Ok(())
+}
+
+/// Strips the surrounding quotes of an option value and unescapes inner quotes
+fn unquote(value: &str) -> String {
+    let value = value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value);
+    value.replace("\\\"", "\"")
+}
+
+/// Parses `YYYYMMDD[HHMM[SS]]` with an optional trailing `Z`.
+/// Like OpenSSH, the time is local time unless it ends in `Z` for UTC.
+fn parse_expiry_time(value: &str) -> anyhow::Result<u64> {
+    let (digits, utc) = match value.strip_suffix(['Z', 'z']) {
+        Some(digits) => (digits, true),
+        None => (value, false),
+    };
+    if !matches!(digits.len(), 8 | 12 | 14) || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("Invalid expiry-time {}", value);
+    }
+
+    // A missing time of day or second is 0
+    let padded = format!("{:0<14}", digits);
+    let time = NaiveDateTime::parse_from_str(&padded, "%Y%m%d%H%M%S")
+        .with_context(|| format!("Invalid expiry-time {}", value))?;
+
+    let timestamp = if utc {
+        time.and_utc().timestamp()
+    } else {
+        // The earlier of ambiguous local times, a skipped one doesn't exist
+        Local
+            .from_local_datetime(&time)
+            .earliest()
+            .with_context(|| format!("Invalid local expiry-time {}", value))?
+            .timestamp()
+    };
+    Ok(timestamp.max(0) as u64)
+}
+
+fn address_matches(pattern: &str, addr: IpAddr) -> bool {
+    if let Some((network, bits)) = pattern.split_once('/') {
+        let (Ok(network), Ok(bits)) = (network.parse::<IpAddr>(), bits.parse::<u32>()) else {
+            return false;
+        };
+        return match (network.to_canonical(), addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) if bits <= 32 => {
+                let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) if bits <= 128 => {
+                let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        };
+    }
+
+    wildcard_match(pattern.to_ascii_lowercase().as_bytes(), addr.to_string().as_bytes())
+}
+
+/// Matches `*` and `?` wildcards like OpenSSH patterns
+pub fn wildcard_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|i| wildcard_match(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && wildcard_match(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && wildcard_match(rest, &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f backup@linux";
+
+    #[test]
+    fn test_plain_key() {
+        let entry = parse_authorized_key_line(KEY).unwrap().unwrap();
+        assert_eq!(entry.comment, "backup@linux");
+        assert_eq!(entry.options, KeyOptions::default());
+
+        assert!(parse_authorized_key_line("").unwrap().is_none());
+        assert!(parse_authorized_key_line("# a comment").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_options() {
+        let line = format!(
+            r#"command="rsync --server \"x y\"",from="10.0.0.0/8,!10.0.0.1",no-pty,permitopen="db:5432",environment="MODE=backup",expiry-time="20300101Z" {}"#,
+            KEY
+        );
+        let options = parse_authorized_key_line(&line).unwrap().unwrap().options;
+
+        assert_eq!(options.command.as_deref(), Some(r#"rsync --server "x y""#));
+        assert_eq!(options.from, vec!["10.0.0.0/8", "!10.0.0.1"]);
+        assert!(options.no_pty);
+        assert!(!options.no_port_forwarding);
+        assert_eq!(options.permit_open, vec!["db:5432"]);
+        assert_eq!(options.environment, vec![("MODE".to_string(), "backup".to_string())]);
+        assert_eq!(options.expiry_time, Some(1893456000));
+    }
+
+    #[test]
+    fn test_restrict_and_unknown_options() {
+        let options = parse_authorized_key_line(&format!("restrict,pty {}", KEY))
+            .unwrap()
+            .unwrap()
+            .options;
+        assert!(!options.no_pty);
+        assert!(options.no_port_forwarding);
+
+        assert!(parse_authorized_key_line(&format!("bogus {}", KEY)).is_err());
+        assert!(parse_authorized_key_line(&format!(r#"command="unterminated {}"#, KEY)).is_err());
+    }
+
+    #[test]
+    fn test_from_patterns() {
+        let options = KeyOptions {
+            from: vec!["192.168.1.*".into(), "10.0.0.0/8".into(), "!10.0.0.1".into(), "::1".into()],
+            ..Default::default()
+        };
+
+        assert!(options.allows_source("192.168.1.20".parse().unwrap()));
+        assert!(options.allows_source("10.20.30.40".parse().unwrap()));
+        assert!(options.allows_source("::ffff:10.20.30.40".parse().unwrap()));
+        assert!(options.allows_source("::1".parse().unwrap()));
+        assert!(!options.allows_source("10.0.0.1".parse().unwrap()));
+        assert!(!options.allows_source("172.16.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_expiry_time() {
+        assert_eq!(parse_expiry_time("19700101Z").unwrap(), 0);
+        assert_eq!(parse_expiry_time("200002291230Z").unwrap(), 951827400);
+        assert_eq!(parse_expiry_time("20240315081530z").unwrap(), 1710490530);
+
+        // Without Z the time is local
+        let local = Local
+            .with_ymd_and_hms(2024, 3, 15, 8, 15, 30)
+            .unwrap()
+            .timestamp();
+        assert_eq!(parse_expiry_time("20240315081530").unwrap(), local as u64);
+        assert!(parse_expiry_time("2024").is_err());
+        assert!(parse_expiry_time("20241301").is_err());
+
+        let options = KeyOptions {
+            expiry_time: Some(100),
+            ..Default::default()
+        };
+        assert!(!options.is_expired_at(99));
+        assert!(options.is_expired_at(100));
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use url::Url;
 
@@ -81,12 +82,94 @@ pub struct ServerSettings {
     pub auth_rejection_time: Option<u64>,
     /// Maximum concurrent connections
     pub max_concurrent_connections: Option<u32>,
+    /// Seconds to wait for sessions to end after a shutdown disconnected them
+    pub shutdown_timeout: Option<u64>,
+    /// Failed authentications from an address or with a key before it is banned, 0 never bans
+    pub max_auth_failures: Option<u32>,
+    /// Seconds of the first ban, each further ban of the same offender lasts twice as long
+    pub auth_ban_time: Option<u64>,
+    /// Longest ban in seconds
+    pub auth_ban_max_time: Option<u64>,
     /// Authorized keys sync interval in seconds
     pub authorized_keys_sync_interval: Option<u64>,
     /// Enable/disable SFTP subsystem
     pub enable_sftp: Option<bool>,
+    /// Directory SFTP sessions are confined to: `home` for the login user's home
+    /// directory, an absolute path, or `none` for the whole file system
+    pub sftp_root: Option<String>,
+    /// SFTP roots of login users, overriding `sftp_root`
+    pub sftp_root_users: Option<BTreeMap<String, String>>,
+    /// SFTP roots of authorized keys by fingerprint (`SHA256:...`), overriding the user's root
+    pub sftp_root_keys: Option<BTreeMap<String, String>>,
+    /// Reject every SFTP operation that changes files
+    pub sftp_read_only: Option<bool>,
+    /// Login users whose SFTP sessions are read-only, `*` matches everyone
+    pub sftp_read_only_users: Option<Vec<String>>,
+    /// Fingerprints of authorized keys whose SFTP sessions are read-only
+    pub sftp_read_only_keys: Option<Vec<String>>,
+    /// Files and directories an SFTP session may have open at once
+    pub sftp_max_open_handles: Option<u32>,
     /// Enable/disable port forwarding
     pub enable_port_forwarding: Option<bool>,
+    /// Enable/disable SSH agent forwarding
+    pub enable_agent_forwarding: Option<bool>,
+    /// Addresses remote forwards listen on, like OpenSSH's `GatewayPorts`: `no` binds
+    /// the loopback addresses only, `yes` all addresses and `clientspecified` the
+    /// address the client asks for. Unset is `no`
+    pub gateway_ports: Option<String>,
+    /// Maximum concurrent SSH sessions (QUIC streams) per connection
+    pub max_streams_per_connection: Option<u32>,
+    /// host:port patterns direct-tcpip may connect to, `*` matches any host or port.
+    /// Unset allows every destination.
+    pub permit_open: Option<Vec<String>>,
+    /// Environment variables clients may set in sessions, `*` matches any characters.
+    /// Unset accepts `LANG` and `LC_*`
+    pub accept_env: Option<Vec<String>>,
+    /// Persistent PTY sessions a login user may have running at once
+    pub max_persistent_sessions: Option<u32>,
+    /// Seconds a persistent session keeps running with no client attached, 0 keeps it forever
+    pub persistent_session_timeout: Option<u64>,
+    /// Path of the JSON Lines audit log, `~/.sessio/audit.jsonl` when unset
+    pub audit_log_path: Option<PathBuf>,
+    /// Size in bytes after which the audit log is rotated
+    pub audit_log_max_size: Option<u64>,
+    /// Number of rotated audit logs to keep
+    pub audit_log_max_files: Option<u32>,
+    /// Login users whose PTY sessions are recorded, `*` records everyone
+    pub session_recording_users: Option<Vec<String>>,
+    /// Fingerprints (`SHA256:...`) of authorized keys whose PTY sessions are recorded
+    pub session_recording_keys: Option<Vec<String>>,
+    /// Directory of the session recordings, `~/.sessio/recordings` when unset
+    pub session_recording_path: Option<PathBuf>,
+    /// Days after which session recordings are deleted
+    pub session_recording_retention_days: Option<u64>,
+    /// Subsystems served by running a command with the login shell, by name.
+    /// These take precedence over the built-in `sftp` subsystem. An `sftp` command
+    /// ignores the `sftp_root*` and `sftp_read_only*` settings, so it is only accepted
+    /// with `sftp_root` set to `none` and no read-only settings.
+    pub subsystems: Option<BTreeMap<String, String>>,
+    /// Banner shown to clients before authentication
+    pub banner: Option<String>,
+    /// File the banner is read from when `banner` is unset
+    pub banner_file: Option<PathBuf>,
+    /// Show the message of the day when a shell starts
+    pub print_motd: Option<bool>,
+    /// Path of the message of the day, `/etc/motd` when unset
+    pub motd_path: Option<PathBuf>,
+    /// Show the time and source of the previous login when a shell starts
+    pub print_last_login: Option<bool>,
+    /// Address of an additional SSH listener over TCP, for clients on networks that
+    /// block UDP, e.g. `[::]:443`. Unset disables it
+    pub tcp_listen: Option<SocketAddr>,
+    /// Wrap the TCP listener in TLS
+    pub tcp_tls: Option<bool>,
+    /// PEM certificate chain of the TLS listener, a self-signed one is used when unset
+    pub tcp_tls_cert_path: Option<PathBuf>,
+    /// PEM private key of `tcp_tls_cert_path`
+    pub tcp_tls_key_path: Option<PathBuf>,
+    /// host:port clients reach the TCP listener at, advertised through the coordinator.
+    /// Unset advertises `tcp_listen` unless it is an unspecified address
+    pub tcp_advertise: Option<String>,
 }
 
 impl Default for ServerSettings {
@@ -99,9 +182,45 @@ impl Default for ServerSettings {
             ssh_inactivity_timeout: Some(3600), // 1 hour
             auth_rejection_time: Some(3),
             max_concurrent_connections: Some(100),
+            shutdown_timeout: Some(30),
+            max_auth_failures: Some(5),
+            auth_ban_time: Some(60),
+            auth_ban_max_time: Some(24 * 60 * 60), // 1 day
             authorized_keys_sync_interval: Some(300), // 5 minutes
             enable_sftp: Some(true),
+            sftp_root: Some("home".to_string()),
+            sftp_root_users: None,
+            sftp_root_keys: None,
+            sftp_read_only: Some(false),
+            sftp_read_only_users: None,
+            sftp_read_only_keys: None,
+            sftp_max_open_handles: Some(256),
             enable_port_forwarding: Some(true),
+            enable_agent_forwarding: Some(true),
+            gateway_ports: Some("no".to_string()),
+            max_streams_per_connection: Some(16),
+            permit_open: None,
+            accept_env: Some(vec!["LANG".to_string(), "LC_*".to_string()]),
+            max_persistent_sessions: Some(10),
+            persistent_session_timeout: Some(7 * 24 * 60 * 60), // 1 week
+            audit_log_path: None,
+            audit_log_max_size: Some(10 * 1024 * 1024), // 10 MiB
+            audit_log_max_files: Some(5),
+            session_recording_users: None,
+            session_recording_keys: None,
+            session_recording_path: None,
+            session_recording_retention_days: Some(30),
+            subsystems: None,
+            banner: None,
+            banner_file: None,
+            print_motd: Some(true),
+            motd_path: None,
+            print_last_login: Some(true),
+            tcp_listen: None,
+            tcp_tls: Some(false),
+            tcp_tls_cert_path: None,
+            tcp_tls_key_path: None,
+            tcp_advertise: None,
         }
     }
 }
@@ -128,6 +247,7 @@ pub enum SessionType {
     Pty,
     Sftp,
     LocalPortForward,
+    RemotePortForward,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -138,6 +258,12 @@ pub enum SessionSpecificData {
         remote_host: String,
         remote_port: u32,
     },
+    RemotePortForward {
+        remote_host: String,
+        remote_port: u32,
+        local_host: String,
+        local_port: u32,
+    },
 }
 
 /// User preferences
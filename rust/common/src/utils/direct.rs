@@ -0,0 +1,55 @@
+//! Connections to servers running in direct mode, without a coordinator
+
+/// UDP port a server in direct mode listens on unless configured otherwise
+pub const DEFAULT_DIRECT_PORT: u16 = 2222;
+
+/// Splits `host:port`, `[v6 address]:port` or a bare host into host and port.
+/// A missing port is `DEFAULT_DIRECT_PORT`.
+pub fn split_host_port(address: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| anyhow::anyhow!("Missing ] in address {}", address))?;
+        let port = match rest {
+            "" => None,
+            rest => Some(
+                rest.strip_prefix(':')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid address {}", address))?,
+            ),
+        };
+        (host, port)
+    } else {
+        match address.rsplit_once(':') {
+            // More than one colon is a bare IPv6 address
+            Some((host, _)) if host.contains(':') => (address, None),
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        }
+    };
+
+    if host.is_empty() {
+        anyhow::bail!("Missing host in address {}", address);
+    }
+    let port = match port {
+        Some(port) => port
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid port in address {}", address))?,
+        None => DEFAULT_DIRECT_PORT,
+    };
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(split_host_port("lab-server:2200").unwrap(), ("lab-server".to_string(), 2200));
+        assert_eq!(split_host_port("192.168.1.5").unwrap(), ("192.168.1.5".to_string(), 2222));
+        assert_eq!(split_host_port("[fe80::1]:22").unwrap(), ("fe80::1".to_string(), 22));
+        assert_eq!(split_host_port("fe80::1").unwrap(), ("fe80::1".to_string(), 2222));
+        assert!(split_host_port("host:port").is_err());
+        assert!(split_host_port(":2222").is_err());
+    }
+}
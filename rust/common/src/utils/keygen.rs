@@ -1,78 +1,109 @@
 use anyhow::Context;
-use log::info;
+use crate::utils::authorized_keys::{parse_authorized_key_line, AuthorizedKey};
+use log::{info, warn};
 use rand::rngs::OsRng;
 use russh::keys::PublicKey;
 use russh::keys::ssh_key::{Algorithm, LineEnding, PrivateKey};
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Once;
 use tokio::io::{self, AsyncReadExt};
 
-pub async fn read_authorized_keys(user: Option<&str>) -> anyhow::Result<Vec<PublicKey>> {
-    read_authorized_keys_with_filter(user, None).await
+/// Path of the authorized_keys file of a login user, `<home>/.sessio/authorized_keys`
+/// unless overridden with `AUTHORIZED_KEYS_PATH`. In the override `%h` stands for the
+/// login user's home directory and `%%` for `%`. An override without `%h` names one
+/// file for every account, so it is only used when `single_user` is set, that is
+/// when every login runs as the same unprivileged account.
+pub fn authorized_keys_path(home: &Path, single_user: bool) -> PathBuf {
+    static IGNORED: Once = Once::new();
+
+    let default = home.join(".sessio/authorized_keys");
+    let Ok(template) = std::env::var("AUTHORIZED_KEYS_PATH") else {
+        return default;
+    };
+    match expand_home(&template, home) {
+        (path, true) => path,
+        (path, false) if single_user => path,
+        (_, false) => {
+            IGNORED.call_once(|| {
+                warn!("Ignoring AUTHORIZED_KEYS_PATH without %h, it would authorize the same keys for every account");
+            });
+            default
+        }
+    }
 }
 
-pub async fn read_authorized_keys_with_filter(user: Option<&str>, match_user: Option<&str>) -> anyhow::Result<Vec<PublicKey>> {
-    let path = match std::env::var("AUTHORIZED_KEYS_PATH").ok() {
-        Some(path) => PathBuf::from(path),
-        None => {
-            let path = if let Some(user) = user {
-                homedir::home(user)?
-            } else {
-                homedir::my_home()?
-            };
-            path.with_context(|| String::from("Home directory not found for user"))?
-                .join(".sessio/authorized_keys")
+/// Replaces `%h` in `template` with `home` and `%%` with `%`.
+/// Returns whether `%h` was replaced.
+fn expand_home(template: &str, home: &Path) -> (PathBuf, bool) {
+    let mut path = String::new();
+    let mut expanded = false;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
         }
-    };
-
-    if !path.exists() {
-        // Create the file and its parent directories if they don't exist
-        if let Some(parent) = path.parent() {
-            tokio::fs::create_dir_all(parent).await?;
+        match chars.next() {
+            Some('h') => {
+                path.push_str(&home.to_string_lossy());
+                expanded = true;
+            }
+            Some('%') => path.push('%'),
+            Some(other) => {
+                path.push('%');
+                path.push(other);
+            }
+            None => path.push('%'),
         }
-
-        tokio::fs::File::create(&path).await?;
     }
+    (PathBuf::from(path), expanded)
+}
 
-    let mut file = tokio::fs::File::open(&path).await?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).await?;
-
-    let mut keys = Vec::new();
+/// Reads the authorized keys at `path`, with their options.
+/// A missing file is not created and means no keys are authorized.
+pub async fn read_authorized_key_file(path: &Path) -> anyhow::Result<Vec<AuthorizedKey>> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+    };
 
-    for line in contents.lines() {
-        let mut split = line.split_whitespace();
+    Ok(parse_authorized_key_entries(&contents, None))
+}
 
-        // Skip the key type (ssh-ed25519, ssh-rsa, etc.)
-        split.next();
+/// Parses authorized_keys contents including the OpenSSH options of every key,
+/// optionally keeping only the keys of one device.
+/// Malformed lines are skipped with a warning, so that one bad line does not
+/// lock out every other key. Skipping never grants access, a key whose options
+/// cannot be parsed is left out rather than used without its restrictions.
+pub fn parse_authorized_key_entries(contents: &str, match_user: Option<&str>) -> Vec<AuthorizedKey> {
+    let mut keys = Vec::new();
 
-        // Get the public key data
-        let key_data = match split.next() {
-            Some(data) => data,
-            None => continue, // Skip malformed lines
+    for (number, line) in contents.lines().enumerate() {
+        let entry = match parse_authorized_key_line(line) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => continue,
+            Err(e) => {
+                warn!("Skipping line {} of authorized_keys: {:#}", number + 1, e);
+                continue;
+            }
         };
 
-        // Get the comment/device_id (third field)
-        let comment = split.next().unwrap_or("");
-
         // If match_user is specified, only include keys for that user/device_id
         if let Some(target_user) = match_user {
             // Extract device_id from "device-id@os" format
-            let device_id = comment.split('@').next().unwrap_or("");
+            let device_id = entry.comment.split('@').next().unwrap_or("");
             if device_id != target_user {
                 continue; // Skip this key as it doesn't match the target user
             }
         }
 
-        if let Ok(public_key) = russh::keys::parse_public_key_base64(key_data) {
-            keys.push(public_key);
-        } else {
-            anyhow::bail!("Failed to read authorized public key {}", line)
-        }
+        keys.push(entry);
     }
 
-    Ok(keys)
+    keys
 }
 
 pub async fn read_known_hosts(user: Option<&str>) -> anyhow::Result<Vec<PublicKey>> {
@@ -222,3 +253,36 @@ pub fn generate_keypair<P: AsRef<Path>>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+
+    #[test]
+    fn test_malformed_lines_are_skipped() {
+        let contents = format!(
+            "{KEY} laptop@linux\nssh-ed25519 not-base64 broken@linux\nfrom=\"unterminated {KEY} phone@android\n{KEY} desktop@windows\n"
+        );
+        let keys = parse_authorized_key_entries(&contents, None);
+        let comments: Vec<_> = keys.iter().map(|entry| entry.comment.as_str()).collect();
+        assert_eq!(comments, vec!["laptop@linux", "desktop@windows"]);
+
+        let keys = parse_authorized_key_entries(&contents, Some("desktop"));
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = Path::new("/home/alice");
+        assert_eq!(
+            expand_home("%h/.ssh/authorized_keys", home),
+            (PathBuf::from("/home/alice/.ssh/authorized_keys"), true)
+        );
+        assert_eq!(
+            expand_home("/etc/sessio/100%%/keys", home),
+            (PathBuf::from("/etc/sessio/100%/keys"), false)
+        );
+    }
+}
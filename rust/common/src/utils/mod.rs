@@ -7,6 +7,11 @@ pub mod events;
 pub mod quinn_utils;
 pub mod file_manager;
 pub mod config_types;
+pub mod persistent_sessions;
+pub mod authorized_keys;
+pub mod direct;
+pub mod tcp_fallback;
+pub mod sftp_extensions;
 
 pub fn map_ipv4_to_ipv6(socket_addr: SocketAddr) -> SocketAddr {
     match socket_addr {
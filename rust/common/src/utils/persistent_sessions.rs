@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+/// Environment variable a client sets on a session channel before the shell request
+/// to start a persistent PTY session with that ID, or to reattach to it.
+pub const PERSISTENT_SESSION_ENV: &str = "SESSIO_SESSION_ID";
+
+/// Subsystem that lists the persistent PTY sessions of the logged in user as JSON
+pub const PERSISTENT_SESSIONS_SUBSYSTEM: &str = "persistent-sessions@sessio";
+
+/// A persistent PTY session kept alive by the server
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct PersistentSessionInfo {
+    pub id: String,
+    /// Unix timestamp of when the session was started
+    pub created_at: u64,
+    /// Whether a channel is currently attached to the session
+    pub attached: bool,
+}
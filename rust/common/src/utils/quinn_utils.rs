@@ -22,6 +22,15 @@ pub fn configure_client() -> anyhow::Result<ClientConfig> {
     Ok(client_config)
 }
 
+/// TLS configuration of connections to a server's TCP fallback listener
+pub fn configure_tls_client() -> rustls::ClientConfig {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(SkipServerVerification::new())
+        .with_no_client_auth()
+}
+
 /// Enables MTUD if supported by the operating system
 #[cfg(unix)]
 pub fn enable_mtud_if_supported() -> quinn::TransportConfig {
@@ -0,0 +1,68 @@
+//! Wire format of the SFTP extensions the server supports beyond version 3,
+//! shared by the server and the client. Encoded with `russh_sftp::ser` and
+//! decoded with `russh_sftp::de`.
+
+use russh_sftp::de::data_deserialize;
+use russh_sftp::ser::data_serialize;
+use serde::{Deserialize, Serialize};
+
+pub use russh_sftp::extensions::{FSYNC, HARDLINK, STATVFS};
+
+pub const POSIX_RENAME: &str = "posix-rename@openssh.com";
+pub const FSTATVFS: &str = "fstatvfs@openssh.com";
+pub const COPY_DATA: &str = "copy-data";
+pub const CHECK_FILE_HANDLE: &str = "check-file-handle";
+pub const CHECK_FILE_NAME: &str = "check-file-name";
+
+/// Request of `posix-rename@openssh.com` and `hardlink@openssh.com`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathPair {
+    pub oldpath: String,
+    pub newpath: String,
+}
+
+/// Request of `statvfs@openssh.com`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathRequest {
+    pub path: String,
+}
+
+/// Request of `fstatvfs@openssh.com` and `fsync@openssh.com`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HandleRequest {
+    pub handle: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyData {
+    pub read_handle: String,
+    pub read_offset: u64,
+    /// 0 copies up to the end of the file
+    pub length: u64,
+    pub write_handle: String,
+    pub write_offset: u64,
+}
+
+/// Request of `check-file-handle` and `check-file-name`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFile {
+    /// Handle or path of the file
+    pub target: String,
+    /// Comma separated hash algorithms the client accepts
+    pub algorithms: String,
+    pub offset: u64,
+    /// 0 hashes up to the end of the file
+    pub length: u64,
+    /// 0 hashes the whole range at once
+    pub block_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CheckFileReply {
+    /// Always `check-file`
+    pub name: String,
+    pub algorithm: String,
+    /// The hashes of the blocks, one after the other
+    #[serde(serialize_with = "data_serialize", deserialize_with = "data_deserialize")]
+    pub hashes: Vec<u8>,
+}
@@ -0,0 +1,94 @@
+//! SSH over plain TCP or TLS, for networks that block UDP and so QUIC
+
+use std::fmt;
+use std::sync::Arc;
+
+use quinn::rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use crate::utils::direct::split_host_port;
+use crate::utils::quinn_utils::configure_tls_client;
+
+/// Key of the fallback endpoint in the coordinator's device metadata
+pub const TCP_ENDPOINT_METADATA: &str = "tcp_endpoint";
+
+/// A stream an SSH session runs over
+pub trait SshStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> SshStream for T {}
+
+/// Where a server's fallback listener is reached, written as `tcp://host:port`
+/// or `tls://host:port`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpEndpoint {
+    pub tls: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl TcpEndpoint {
+    pub fn parse(endpoint: &str) -> anyhow::Result<Self> {
+        let (tls, address) = if let Some(address) = endpoint.strip_prefix("tls://") {
+            (true, address)
+        } else if let Some(address) = endpoint.strip_prefix("tcp://") {
+            (false, address)
+        } else {
+            anyhow::bail!("Invalid TCP endpoint {}, expected tcp://host:port or tls://host:port", endpoint);
+        };
+        let (host, port) = split_host_port(address)?;
+        Ok(TcpEndpoint { tls, host, port })
+    }
+
+    /// Opens a connection to the endpoint. The TLS certificate is not
+    /// verified, the server is authenticated by its SSH host key.
+    pub async fn connect(&self) -> anyhow::Result<Box<dyn SshStream>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        stream.set_nodelay(true)?;
+        if !self.tls {
+            return Ok(Box::new(stream));
+        }
+
+        let connector = TlsConnector::from(Arc::new(configure_tls_client()));
+        let server_name = ServerName::try_from(self.host.clone())?;
+        Ok(Box::new(connector.connect(server_name, stream).await?))
+    }
+}
+
+impl fmt::Display for TcpEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls { "tls" } else { "tcp" };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_endpoint() {
+        let endpoint = TcpEndpoint::parse("tls://ssh.example.com:443").unwrap();
+        assert_eq!(
+            endpoint,
+            TcpEndpoint {
+                tls: true,
+                host: "ssh.example.com".to_string(),
+                port: 443,
+            }
+        );
+        assert_eq!(endpoint.to_string(), "tls://ssh.example.com:443");
+
+        let endpoint = TcpEndpoint::parse("tcp://[2001:db8::1]:2222").unwrap();
+        assert!(!endpoint.tls);
+        assert_eq!(endpoint.to_string(), "tcp://[2001:db8::1]:2222");
+
+        assert!(TcpEndpoint::parse("ssh.example.com:443").is_err());
+        assert!(TcpEndpoint::parse("udp://ssh.example.com:443").is_err());
+    }
+}
@@ -43,6 +43,8 @@ pub struct HeartbeatRequest {
     pub device_id: String,
     pub jwt_token: String,
     pub version: Option<String>,  // Client/server version
+    /// TCP fallback listener of a server, as `tcp://host:port` or `tls://host:port`
+    pub tcp_endpoint: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -50,6 +50,10 @@ pub struct CoordinatorClient {
     
     // Connection state tracking
     is_connected: Arc<AtomicBool>,
+
+    /// TCP fallback listener of a server, sent with every heartbeat so that the
+    /// coordinator lists it in the device metadata
+    pub tcp_endpoint: Option<String>,
 }
 
 // HTTP/WebSocket doesn't need MTUD configuration - removed legacy functions
@@ -205,6 +209,7 @@ impl CoordinatorClient {
         jwt_token: String,
         ipv4: Option<SocketAddr>,
         ipv6: Option<SocketAddr>,
+        tcp_endpoint: Option<String>,
     ) -> Result<Self> {
         let http_client = HttpClient::new();
 
@@ -351,6 +356,7 @@ impl CoordinatorClient {
             server_packet_sender,
             client_packet_bus,
             is_connected,
+            tcp_endpoint,
         };
 
         // Start heartbeat task
@@ -393,6 +399,7 @@ impl CoordinatorClient {
         let id_own = self.id_own.clone();
         let token = self.token.clone();
         let is_connected = self.is_connected.clone();
+        let tcp_endpoint = self.tcp_endpoint.clone();
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
@@ -409,6 +416,7 @@ impl CoordinatorClient {
                     device_id: device_id.clone(),
                     jwt_token: jwt_token.clone(),
                     version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                    tcp_endpoint: tcp_endpoint.clone(),
                 };
 
                 let heartbeat_packet = ServerPacket {
@@ -432,6 +440,7 @@ impl CoordinatorClient {
     /// Starts a background task that syncs authorized keys with cryptographic verification
     /// This replaces the less secure implementations in client and server
     /// passkey_json: Full JSON-serialized Passkey for signature verification (from webauthn_credentials table)
+    /// on_update: Called after every write of the authorized keys file
     pub async fn start_authorized_keys_sync_task(
         &self,
         jwt_token: String,
@@ -439,6 +448,7 @@ impl CoordinatorClient {
         sync_interval_secs: u64,
         authorized_keys_path: std::path::PathBuf,
         _include_unverified: bool,
+        on_update: impl Fn() + Send + 'static,
     ) {
         let http_client = self.http_client.clone();
         let coordinator_url = self.coordinator_url.clone();
@@ -552,6 +562,11 @@ impl CoordinatorClient {
                 }
 
                 // Write authorized_keys file
+                if let Some(parent) = authorized_keys_path.parent() {
+                    if let Err(e) = tokio::fs::create_dir_all(parent).await {
+                        error!("Failed to create {:?}: {}", parent, e);
+                    }
+                }
                 let content = authorized_entries.join("\n") + "\n";
                 match tokio::fs::write(&authorized_keys_path, content).await {
                     Ok(_) => {
@@ -559,6 +574,7 @@ impl CoordinatorClient {
                             "Authorized keys updated: {} verified, {} unverified, {} total entries written to {:?}",
                             verified_count, unverified_count, authorized_entries.len(), authorized_keys_path
                         );
+                        on_update();
                     }
                     Err(e) => {
                         error!("Failed to write authorized keys file {:?}: {}", authorized_keys_path, e);
@@ -1015,6 +1031,20 @@ impl CoordinatorClient {
                     }
                 }
 
+                // Keep hosts pinned by address, like servers in direct mode. Synced
+                // entries start with the key type instead of a host pattern
+                if let Ok(existing) = tokio::fs::read_to_string(&known_hosts_path).await {
+                    let pinned = existing.lines().filter(|line| {
+                        let first = line.split_whitespace().next().unwrap_or("");
+                        !first.is_empty()
+                            && !first.starts_with('#')
+                            && !first.starts_with("ssh-")
+                            && !first.starts_with("ecdsa-")
+                            && !first.starts_with("sk-")
+                    });
+                    known_hosts_entries.extend(pinned.map(str::to_string));
+                }
+
                 // Write known_hosts file
                 let content = known_hosts_entries.join("\n") + "\n";
                 match tokio::fs::write(&known_hosts_path, content).await {
@@ -27,6 +27,9 @@ pub struct HolepunchService {
 
     // Quinn endpoint for P2P connections
     pub endpoint: Endpoint,
+
+    // TCP fallback listener advertised in the heartbeats, servers only
+    tcp_endpoint: Option<String>,
 }
 
 impl HolepunchService {
@@ -36,6 +39,7 @@ impl HolepunchService {
         ipv4: Option<SocketAddr>,
         ipv6: Option<SocketAddr>,
         id_own: String,
+        tcp_endpoint: Option<String>,
     ) -> Result<CoordinatorClient> {
         let c_client = loop {
             match CoordinatorClient::connect(
@@ -44,6 +48,7 @@ impl HolepunchService {
                 jwt_token.clone(),
                 ipv4,
                 ipv6,
+                tcp_endpoint.clone(),
             )
                 .await
             {
@@ -67,6 +72,7 @@ impl HolepunchService {
     }
 
     ///Account ID is used to authenticate with the coordinator
+    ///tcp_endpoint is the TCP fallback listener a server advertises to clients
     pub async fn new(
         coordinator_url: Url,
         jwt_token: String,
@@ -74,9 +80,10 @@ impl HolepunchService {
         ipv6: Option<SocketAddr>,
         id_own: String,
         endpoint: Endpoint,
+        tcp_endpoint: Option<String>,
     ) -> Result<Self> {
         let c_client =
-            HolepunchService::connect(&coordinator_url, jwt_token.clone(), ipv4.clone(), ipv6.clone(), id_own.clone()).await?;
+            HolepunchService::connect(&coordinator_url, jwt_token.clone(), ipv4.clone(), ipv6.clone(), id_own.clone(), tcp_endpoint.clone()).await?;
         let mut service = HolepunchService {
             c_client,
             coordinator_url,
@@ -84,6 +91,7 @@ impl HolepunchService {
             ipv4,
             ipv6,
             endpoint,
+            tcp_endpoint,
         };
         service.start_connection_update_task();
         Ok(service)
@@ -98,6 +106,7 @@ impl HolepunchService {
             self.ipv4,
             self.ipv6,
             self.c_client.id_own.clone(),
+            self.tcp_endpoint.clone(),
         )
             .await?;
         Ok(())
@@ -262,6 +262,28 @@ impl DatabaseRepository {
         Ok(())
     }
 
+    /// Stores the TCP fallback endpoint a server advertises in its metadata, or removes it
+    pub async fn update_device_tcp_endpoint(&self, device_id: &str, account_id: Uuid, tcp_endpoint: Option<&str>) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE devices
+            SET metadata = CASE
+                WHEN $3::text IS NULL THEN COALESCE(metadata, '{}'::jsonb) - 'tcp_endpoint'
+                ELSE COALESCE(metadata, '{}'::jsonb) || jsonb_build_object('tcp_endpoint', $3::text)
+            END
+            WHERE device_id = $1 AND account_id = $2
+              AND metadata->>'tcp_endpoint' IS DISTINCT FROM $3::text
+            "#
+        )
+        .bind(device_id)
+        .bind(account_id)
+        .bind(tcp_endpoint)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
     pub async fn delete_device(&self, device_id: &str, account_id: Uuid) -> Result<bool> {
         let result = sqlx::query(
             "DELETE FROM devices WHERE device_id = $1 AND account_id = $2"
@@ -372,6 +372,8 @@ async fn handle_heartbeat(
     // Update last seen and version for the device
     server.db.update_device_heartbeat(&client.id, account_uuid, data.version.clone()).await
         .context("Failed to update device heartbeat")?;
+    server.db.update_device_tcp_endpoint(&client.id, account_uuid, data.tcp_endpoint.as_deref()).await
+        .context("Failed to update device TCP endpoint")?;
     
     // Log version if provided
     if let Some(version) = &data.version {
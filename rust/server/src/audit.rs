@@ -0,0 +1,455 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::error;
+use russh::ChannelId;
+use serde::Serialize;
+
+use crate::config_manager::AuditConfig;
+
+/// An entry of the audit log. The `event` tag and field names are a stable schema,
+/// new fields may be added but existing ones are not renamed or removed.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A ConnectTo request relayed by the coordinator
+    ConnectTo {
+        session_id: String,
+        target: String,
+        fingerprint: Option<String>,
+        accepted: bool,
+        reason: Option<String>,
+    },
+    SessionStart,
+    SessionEnd {
+        duration_ms: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+    AuthSuccess {
+        method: &'static str,
+        fingerprint: String,
+        login: String,
+    },
+    AuthFailure {
+        method: &'static str,
+        fingerprint: String,
+        reason: String,
+    },
+    ChannelOpen {
+        channel: u32,
+        channel_type: &'static str,
+    },
+    ChannelClose {
+        channel: u32,
+        channel_type: &'static str,
+        duration_ms: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+    },
+    Exec {
+        channel: u32,
+        command: String,
+    },
+    Shell {
+        channel: u32,
+    },
+    Subsystem {
+        channel: u32,
+        name: String,
+    },
+    DirectTcpip {
+        channel: u32,
+        host: String,
+        port: u32,
+        originator: String,
+        accepted: bool,
+    },
+    DirectStreamlocal {
+        channel: u32,
+        socket_path: String,
+        accepted: bool,
+    },
+    AgentForward {
+        channel: u32,
+        accepted: bool,
+    },
+    TcpipForward {
+        address: String,
+        port: u32,
+        accepted: bool,
+    },
+    CancelTcpipForward {
+        address: String,
+        port: u32,
+    },
+    /// An address or key was banned after repeated authentication failures
+    Ban {
+        offender: String,
+        duration_secs: u64,
+    },
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_ms: u64,
+    remote: Option<String>,
+    user: Option<&'a str>,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+/// Append-only JSON Lines audit trail, rotated once it grows past the configured size.
+/// A default `AuditLog` is disabled and drops every event.
+#[derive(Default)]
+pub struct AuditLog {
+    writer: Mutex<Option<AuditWriter>>,
+}
+
+struct AuditWriter {
+    config: AuditConfig,
+    file: File,
+    size: u64,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        Ok(AuditLog {
+            writer: Mutex::new(Some(AuditWriter::open(config)?)),
+        })
+    }
+
+    /// Continues the log with reloaded settings, which may name another file.
+    /// The current file is kept if the new one can't be opened.
+    pub fn reconfigure(&self, config: AuditConfig) -> io::Result<()> {
+        let writer = AuditWriter::open(config)?;
+        *self.lock() = Some(writer);
+        Ok(())
+    }
+
+    pub fn record(&self, remote: Option<SocketAddr>, user: Option<&str>, event: &AuditEvent) {
+        let mut writer = self.lock();
+        let Some(writer) = writer.as_mut() else {
+            return;
+        };
+
+        let record = AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            remote: remote.map(|addr| addr.to_string()),
+            user,
+            event,
+        };
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize audit event: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if let Err(e) = writer.write(&line) {
+            error!("Failed to write audit log: {}", e);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<AuditWriter>> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl AuditWriter {
+    fn open(config: AuditConfig) -> io::Result<Self> {
+        if let Some(parent) = config.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = open_log_file(&config.path)?;
+        let size = file.metadata()?.len();
+        Ok(AuditWriter { config, file, size })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.config.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `audit.jsonl.N` to `audit.jsonl.N+1`, dropping the oldest,
+    /// and starts a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        let path = &self.config.path;
+        let max_files = self.config.max_files;
+
+        if max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(path, max_files));
+            for n in (1..max_files).rev() {
+                let from = rotated_path(path, n);
+                if from.exists() {
+                    fs::rename(&from, rotated_path(path, n + 1))?;
+                }
+            }
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+
+        self.file = open_log_file(path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .mode(0o600)
+        .open(path)
+}
+
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Audit context of one SSH session: who is connected from where,
+/// the channels still open and the traffic of the channels closed so far
+pub struct SessionAudit {
+    log: Arc<AuditLog>,
+    remote: Option<SocketAddr>,
+    started: Instant,
+    user: Mutex<Option<String>>,
+    open: Mutex<HashMap<u32, Arc<ChannelStats>>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl Default for SessionAudit {
+    fn default() -> Self {
+        SessionAudit::new(Arc::default(), None)
+    }
+}
+
+impl SessionAudit {
+    pub fn new(log: Arc<AuditLog>, remote: Option<SocketAddr>) -> Self {
+        SessionAudit {
+            log,
+            remote,
+            started: Instant::now(),
+            user: Mutex::new(None),
+            open: Mutex::new(HashMap::new()),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_user(&self, user: &str) {
+        *self.user.lock().unwrap_or_else(|e| e.into_inner()) = Some(user.to_string());
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let user = self.user.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        self.log.record(self.remote, user.as_deref(), &event);
+    }
+
+    /// Records a channel open and returns the counters for its traffic
+    pub fn open_channel(&self, channel: ChannelId, channel_type: &'static str) -> Arc<ChannelStats> {
+        self.open_channel_number(channel.into(), channel_type)
+    }
+
+    /// Records a channel close with its traffic. A channel is recorded once,
+    /// later calls for it do nothing.
+    pub fn close_channel(&self, channel: ChannelId, stats: &ChannelStats) {
+        self.close_channel_number(channel.into(), stats);
+    }
+
+    fn open_channel_number(&self, channel: u32, channel_type: &'static str) -> Arc<ChannelStats> {
+        self.record(AuditEvent::ChannelOpen {
+            channel,
+            channel_type,
+        });
+        let stats = Arc::new(ChannelStats {
+            channel_type,
+            opened: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+        });
+        self.lock_open().insert(channel, stats.clone());
+        stats
+    }
+
+    fn close_channel_number(&self, channel: u32, stats: &ChannelStats) {
+        if self.lock_open().remove(&channel).is_some() {
+            self.record_close(channel, stats);
+        }
+    }
+
+    fn record_close(&self, channel: u32, stats: &ChannelStats) {
+        let bytes_in = stats.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = stats.bytes_out.load(Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+
+        self.record(AuditEvent::ChannelClose {
+            channel,
+            channel_type: stats.channel_type,
+            duration_ms: stats.opened.elapsed().as_millis() as u64,
+            bytes_in,
+            bytes_out,
+        });
+    }
+
+    /// Records the end of the SSH session with its total traffic.
+    /// Channels still open are closed with it.
+    pub fn finish(&self) {
+        let mut open: Vec<(u32, Arc<ChannelStats>)> = self.lock_open().drain().collect();
+        open.sort_by_key(|(channel, _)| *channel);
+        for (channel, stats) in open {
+            self.record_close(channel, &stats);
+        }
+
+        self.record(AuditEvent::SessionEnd {
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        });
+    }
+
+    fn lock_open(&self) -> std::sync::MutexGuard<'_, HashMap<u32, Arc<ChannelStats>>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Traffic of a single channel, from the client's point of view
+pub struct ChannelStats {
+    channel_type: &'static str,
+    opened: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+impl ChannelStats {
+    pub fn add_in(&self, n: u64) {
+        self.bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, n: u64) {
+        self.bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_records_are_json_lines() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::open(AuditConfig {
+            path: path.clone(),
+            max_size: 1024 * 1024,
+            max_files: 1,
+        })
+        .unwrap();
+
+        log.record(None, Some("alice"), &AuditEvent::SessionStart);
+        log.record(
+            Some("[::1]:2222".parse().unwrap()),
+            Some("alice"),
+            &AuditEvent::Exec {
+                channel: 2,
+                command: "ls".to_string(),
+            },
+        );
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["event"], "session_start");
+        assert_eq!(lines[0]["user"], "alice");
+        assert_eq!(lines[1]["event"], "exec");
+        assert_eq!(lines[1]["remote"], "[::1]:2222");
+        assert_eq!(lines[1]["command"], "ls");
+        assert_eq!(lines[1]["channel"], 2);
+    }
+
+    #[test]
+    fn test_rotation_keeps_max_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = AuditLog::open(AuditConfig {
+            path: path.clone(),
+            max_size: 1,
+            max_files: 2,
+        })
+        .unwrap();
+
+        for _ in 0..4 {
+            log.record(None, None, &AuditEvent::SessionStart);
+        }
+
+        assert!(path.exists());
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+
+    #[test]
+    fn test_finish_closes_open_channels() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let log = Arc::new(
+            AuditLog::open(AuditConfig {
+                path: path.clone(),
+                max_size: 1024 * 1024,
+                max_files: 1,
+            })
+            .unwrap(),
+        );
+        let audit = SessionAudit::new(log, None);
+
+        let closed = audit.open_channel_number(0, "session");
+        closed.add_in(1);
+        audit.close_channel_number(0, &closed);
+        let open = audit.open_channel_number(1, "direct-tcpip");
+        open.add_out(10);
+        audit.finish();
+        // Closing after the session ended doesn't record the channel again
+        audit.close_channel_number(1, &open);
+
+        let events: Vec<serde_json::Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let names: Vec<&str> = events.iter().map(|event| event["event"].as_str().unwrap()).collect();
+        assert_eq!(
+            names,
+            vec!["channel_open", "channel_close", "channel_open", "channel_close", "session_end"]
+        );
+        assert_eq!(events[3]["channel"], 1);
+        assert_eq!(events[4]["bytes_in"], 1);
+        assert_eq!(events[4]["bytes_out"], 10);
+    }
+}
@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config_manager::SshConfig;
+
+/// Who authentication failures are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Offender {
+    Address(IpAddr),
+    /// SHA256 fingerprint of a public key
+    Key(String),
+}
+
+impl Offender {
+    /// An address offender. IPv4-mapped IPv6 addresses count as the IPv4 address,
+    /// so a client can't dodge its ban by switching between the two.
+    pub fn address(ip: IpAddr) -> Self {
+        Offender::Address(ip.to_canonical())
+    }
+}
+
+impl fmt::Display for Offender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Offender::Address(ip) => write!(f, "address {}", ip),
+            Offender::Key(fingerprint) => write!(f, "key {}", fingerprint),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FailureRecord {
+    failures: u32,
+    /// Bans applied so far, each one lasts twice as long as the previous
+    bans: u32,
+    banned_until: Option<Instant>,
+    last_failure: Instant,
+}
+
+/// When offenders are banned and for how long
+#[derive(Debug, Clone, Copy, Default)]
+struct BanLimits {
+    max_failures: u32,
+    ban_time: Duration,
+    max_ban_time: Duration,
+}
+
+impl BanLimits {
+    fn from_config(config: &SshConfig) -> Self {
+        BanLimits {
+            max_failures: config.max_auth_failures,
+            ban_time: Duration::from_secs(config.auth_ban_time),
+            max_ban_time: Duration::from_secs(config.auth_ban_max_time),
+        }
+    }
+}
+
+/// Counts failed authentications per address and key, and bans offenders for a
+/// while once they fail too often. A default `AuthFailures` never bans.
+#[derive(Default)]
+pub struct AuthFailures {
+    limits: Mutex<BanLimits>,
+    records: Mutex<HashMap<Offender, FailureRecord>>,
+    audit: Arc<AuditLog>,
+}
+
+impl AuthFailures {
+    pub fn new(config: &SshConfig, audit: Arc<AuditLog>) -> Self {
+        AuthFailures {
+            limits: Mutex::new(BanLimits::from_config(config)),
+            records: Mutex::new(HashMap::new()),
+            audit,
+        }
+    }
+
+    /// Applies reloaded ban settings. Running bans keep their length.
+    pub fn reconfigure(&self, config: &SshConfig) {
+        *self.limits.lock().unwrap_or_else(|e| e.into_inner()) = BanLimits::from_config(config);
+    }
+
+    /// Time left on the ban of `offender`, if it is banned
+    pub fn banned(&self, offender: &Offender) -> Option<Duration> {
+        self.banned_at(offender, Instant::now())
+    }
+
+    /// Whether any of `offenders` is banned
+    pub fn any_banned(&self, offenders: &[Offender]) -> bool {
+        offenders.iter().any(|offender| self.banned(offender).is_some())
+    }
+
+    /// Counts a failure against each of `offenders`, banning the ones that
+    /// reach the limit. `remote` is the address recorded with ban events.
+    pub fn record_failure(&self, offenders: &[Offender], remote: Option<SocketAddr>) {
+        for offender in offenders {
+            if let Some(duration) = self.record_failure_at(offender, Instant::now()) {
+                warn!("Banned {} for {}s after repeated authentication failures", offender, duration.as_secs());
+                self.audit.record(
+                    remote,
+                    None,
+                    &AuditEvent::Ban {
+                        offender: offender.to_string(),
+                        duration_secs: duration.as_secs(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Clears the failures of `offenders` after a successful authentication.
+    /// Past bans are still counted, so a later ban lasts longer.
+    pub fn record_success(&self, offenders: &[Offender]) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        for offender in offenders {
+            if let Some(record) = records.get_mut(offender) {
+                record.failures = 0;
+            }
+        }
+    }
+
+    fn banned_at(&self, offender: &Offender, now: Instant) -> Option<Duration> {
+        let records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let until = records.get(offender)?.banned_until?;
+        until.checked_duration_since(now).filter(|left| !left.is_zero())
+    }
+
+    /// Returns the length of the ban if this failure caused one
+    fn record_failure_at(&self, offender: &Offender, now: Instant) -> Option<Duration> {
+        let limits = *self.limits.lock().unwrap_or_else(|e| e.into_inner());
+        if limits.max_failures == 0 {
+            return None;
+        }
+
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        // Offenders that behaved for as long as the longest ban start over
+        records.retain(|_, record| {
+            let quiet_since = record
+                .banned_until
+                .map_or(record.last_failure, |until| until.max(record.last_failure));
+            now.saturating_duration_since(quiet_since) < limits.max_ban_time
+        });
+
+        let record = records.entry(offender.clone()).or_insert(FailureRecord {
+            failures: 0,
+            bans: 0,
+            banned_until: None,
+            last_failure: now,
+        });
+        record.failures += 1;
+        record.last_failure = now;
+        if record.failures < limits.max_failures {
+            return None;
+        }
+
+        let duration = limits
+            .ban_time
+            .saturating_mul(2u32.saturating_pow(record.bans))
+            .min(limits.max_ban_time);
+        record.failures = 0;
+        record.bans += 1;
+        record.banned_until = Some(now + duration);
+        Some(duration)
+    }
+}
+
+/// The offenders an authentication attempt from `remote` with the key of
+/// `fingerprint` is counted against
+pub fn offenders(remote: Option<SocketAddr>, fingerprint: Option<String>) -> Vec<Offender> {
+    remote
+        .map(|addr| Offender::address(addr.ip()))
+        .into_iter()
+        .chain(fingerprint.map(Offender::Key))
+        .collect()
+}
+
+/// The offenders a failed authentication is counted against. Anyone can offer
+/// or name a public key, so the key only counts once the client proved it
+/// holds it with a signature.
+pub fn failure_offenders(
+    remote: Option<SocketAddr>,
+    fingerprint: Option<String>,
+    signed: bool,
+) -> Vec<Offender> {
+    offenders(remote, fingerprint.filter(|_| signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failures() -> AuthFailures {
+        AuthFailures {
+            limits: Mutex::new(BanLimits {
+                max_failures: 3,
+                ban_time: Duration::from_secs(60),
+                max_ban_time: Duration::from_secs(200),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ban_after_max_failures() {
+        let failures = failures();
+        let offender = Offender::Address("192.0.2.1".parse().unwrap());
+        let now = Instant::now();
+
+        assert_eq!(failures.record_failure_at(&offender, now), None);
+        assert_eq!(failures.record_failure_at(&offender, now), None);
+        assert_eq!(
+            failures.record_failure_at(&offender, now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            failures.banned_at(&offender, now + Duration::from_secs(10)),
+            Some(Duration::from_secs(50))
+        );
+        assert_eq!(failures.banned_at(&offender, now + Duration::from_secs(60)), None);
+        assert_eq!(
+            failures.banned_at(&Offender::Key("SHA256:abc".to_string()), now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bans_double_up_to_max() {
+        let failures = failures();
+        let offender = Offender::Key("SHA256:abc".to_string());
+        let mut now = Instant::now();
+
+        let mut bans = Vec::new();
+        for _ in 0..3 {
+            for _ in 0..3 {
+                if let Some(ban) = failures.record_failure_at(&offender, now) {
+                    bans.push(ban.as_secs());
+                    now += ban;
+                }
+            }
+        }
+        assert_eq!(bans, vec![60, 120, 200]);
+    }
+
+    #[test]
+    fn test_mapped_addresses_are_canonical() {
+        let failures = failures();
+        let mapped = offenders(Some("[::ffff:192.0.2.1]:22".parse().unwrap()), None);
+        let now = Instant::now();
+        for _ in 0..3 {
+            failures.record_failure_at(&mapped[0], now);
+        }
+        assert!(failures
+            .banned_at(&Offender::address("192.0.2.1".parse().unwrap()), now)
+            .is_some());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let failures = AuthFailures::default();
+        let offender = Offender::Address("192.0.2.1".parse().unwrap());
+        for _ in 0..10 {
+            assert_eq!(failures.record_failure_at(&offender, Instant::now()), None);
+        }
+        assert!(!failures.any_banned(&[offender]));
+    }
+
+    #[test]
+    fn test_reconfigure() {
+        let failures = failures();
+        let offender = Offender::Address("192.0.2.1".parse().unwrap());
+        let now = Instant::now();
+
+        failures.reconfigure(&SshConfig {
+            max_auth_failures: 1,
+            auth_ban_time: 10,
+            ..Default::default()
+        });
+        assert_eq!(
+            failures.record_failure_at(&offender, now),
+            Some(Duration::from_secs(10))
+        );
+    }
+}
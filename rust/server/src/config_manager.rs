@@ -1,13 +1,18 @@
 use anyhow::{Context, Result};
 use common::utils::config_types::{ServerSettings, ServerAccountData};
+use common::utils::direct::split_host_port;
 use common::utils::file_manager::FileManager;
+use common::utils::tcp_fallback::TcpEndpoint;
 use dirs;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 use url::Url;
 
 /// Server configuration manager with improved file handling
 pub struct ServerConfigManager {
+    sessio_dir: PathBuf,
     file_manager: FileManager,
     settings_cache: Option<ServerSettings>,
     account_data_cache: Option<ServerAccountData>,
@@ -23,7 +28,8 @@ impl ServerConfigManager {
             .join(".sessio");
 
         Ok(Self {
-            file_manager: FileManager::new(sessio_dir),
+            file_manager: FileManager::new(sessio_dir.clone()),
+            sessio_dir,
             settings_cache: None,
             account_data_cache: None,
             last_settings_check: None,
@@ -204,15 +210,67 @@ impl ServerConfigManager {
     /// Get SSH configuration
     pub async fn get_ssh_config(&mut self) -> Result<SshConfig> {
         let settings = self.load_settings().await?;
-        Ok(SshConfig {
-            inactivity_timeout: settings.ssh_inactivity_timeout.unwrap_or(3600),
-            auth_rejection_time: settings.auth_rejection_time.unwrap_or(3),
-            max_concurrent_connections: settings.max_concurrent_connections.unwrap_or(100),
-            enable_sftp: settings.enable_sftp.unwrap_or(true),
-            enable_port_forwarding: settings.enable_port_forwarding.unwrap_or(true),
+        Ok(SshConfig::from_settings(&settings))
+    }
+
+    /// Get audit log configuration
+    pub async fn get_audit_config(&mut self) -> Result<AuditConfig> {
+        let settings = self.load_settings().await?;
+        Ok(AuditConfig {
+            path: settings
+                .audit_log_path
+                .unwrap_or_else(|| self.sessio_dir.join("audit.jsonl")),
+            max_size: settings.audit_log_max_size.unwrap_or(10 * 1024 * 1024),
+            max_files: settings.audit_log_max_files.unwrap_or(5),
+        })
+    }
+
+    /// Get session recording configuration
+    pub async fn get_recording_config(&mut self) -> Result<RecordingConfig> {
+        let settings = self.load_settings().await?;
+        Ok(RecordingConfig {
+            path: settings
+                .session_recording_path
+                .unwrap_or_else(|| self.sessio_dir.join("recordings")),
+            users: settings.session_recording_users.unwrap_or_default(),
+            keys: settings.session_recording_keys.unwrap_or_default(),
+            retention_days: settings.session_recording_retention_days.unwrap_or(30),
         })
     }
 
+    /// Get the TCP fallback listener configuration, None if it is disabled
+    pub async fn get_tcp_fallback_config(&mut self) -> Result<Option<TcpFallbackConfig>> {
+        let settings = self.load_settings().await?;
+        let Some(listen) = settings.tcp_listen else {
+            return Ok(None);
+        };
+        let tls = settings.tcp_tls.unwrap_or(false);
+        let advertise = match &settings.tcp_advertise {
+            Some(address) => {
+                let (host, port) = split_host_port(address)?;
+                Some(TcpEndpoint { tls, host, port })
+            }
+            None if !listen.ip().is_unspecified() => Some(TcpEndpoint {
+                tls,
+                host: listen.ip().to_string(),
+                port: listen.port(),
+            }),
+            None => None,
+        };
+        Ok(Some(TcpFallbackConfig {
+            listen,
+            tls,
+            cert_path: settings.tcp_tls_cert_path,
+            key_path: settings.tcp_tls_key_path,
+            advertise,
+        }))
+    }
+
+    /// Path of the file storing the last login of each user
+    pub fn last_login_path(&self) -> PathBuf {
+        self.sessio_dir.join("lastlog.json")
+    }
+
     /// Get authorized keys sync interval
     pub async fn get_authorized_keys_sync_interval(&mut self) -> Result<u64> {
         let settings = self.load_settings().await?;
@@ -276,6 +334,63 @@ impl ServerConfigManager {
             }
         }
 
+        if let (Some(ban_time), Some(max_ban_time)) = (settings.auth_ban_time, settings.auth_ban_max_time) {
+            if ban_time > max_ban_time {
+                return Err(anyhow::anyhow!("Auth ban time cannot exceed the auth ban max time"));
+            }
+        }
+
+        if settings.tcp_tls_cert_path.is_some() != settings.tcp_tls_key_path.is_some() {
+            return Err(anyhow::anyhow!("The TLS certificate and key paths must be set together"));
+        }
+
+        if let Some(address) = &settings.tcp_advertise {
+            if let Err(e) = split_host_port(address) {
+                return Err(anyhow::anyhow!("Invalid TCP advertise address: {}", e));
+            }
+        }
+
+        let sftp_roots = settings
+            .sftp_root
+            .iter()
+            .chain(settings.sftp_root_users.iter().flat_map(|roots| roots.values()))
+            .chain(settings.sftp_root_keys.iter().flat_map(|roots| roots.values()));
+        for root in sftp_roots {
+            if let Err(e) = SftpRoot::parse(root) {
+                return Err(anyhow::anyhow!("Invalid SFTP root: {}", e));
+            }
+        }
+
+        if let Some(gateway_ports) = &settings.gateway_ports {
+            if let Err(e) = GatewayPorts::parse(gateway_ports) {
+                return Err(anyhow::anyhow!("Invalid gateway ports: {}", e));
+            }
+        }
+
+        if settings.sftp_max_open_handles == Some(0) {
+            return Err(anyhow::anyhow!("SFTP max open handles must be greater than 0"));
+        }
+
+        // Validate subsystems
+        for (name, command) in settings.subsystems.iter().flatten() {
+            if name.is_empty() || command.trim().is_empty() {
+                return Err(anyhow::anyhow!("Subsystem {:?} needs a name and a command", name));
+            }
+        }
+
+        // A configured sftp command replaces the built-in server, and with it the
+        // SFTP roots and read-only settings, so it may only be used without them
+        let sftp_overridden = settings
+            .subsystems
+            .as_ref()
+            .is_some_and(|subsystems| subsystems.contains_key("sftp"));
+        if sftp_overridden && sftp_restricted(settings) {
+            return Err(anyhow::anyhow!(
+                "Subsystem \"sftp\" would bypass the SFTP root and read-only settings. \
+                 Set sftp_root to none and remove the read-only settings to use it"
+            ));
+        }
+
         Ok(())
     }
 }
@@ -286,8 +401,209 @@ pub struct SshConfig {
     pub inactivity_timeout: u64,
     pub auth_rejection_time: u64,
     pub max_concurrent_connections: u32,
+    pub shutdown_timeout: u64,
+    /// 0 disables bans
+    pub max_auth_failures: u32,
+    pub auth_ban_time: u64,
+    pub auth_ban_max_time: u64,
     pub enable_sftp: bool,
+    pub sftp: SftpConfig,
     pub enable_port_forwarding: bool,
+    pub enable_agent_forwarding: bool,
+    pub gateway_ports: GatewayPorts,
+    pub max_streams_per_connection: u32,
+    /// Empty allows every direct-tcpip destination
+    pub permit_open: Vec<String>,
+    /// Patterns of the environment variables clients may set
+    pub accept_env: Vec<String>,
+    pub max_persistent_sessions: u32,
+    /// 0 keeps detached persistent sessions forever
+    pub persistent_session_timeout: u64,
+    /// Commands of the subsystems configured in the settings, by name
+    pub subsystems: BTreeMap<String, String>,
+    pub banner: Option<String>,
+    pub banner_file: Option<PathBuf>,
+    pub print_motd: bool,
+    pub motd_path: PathBuf,
+    pub print_last_login: bool,
+}
+
+impl SshConfig {
+    pub fn from_settings(settings: &ServerSettings) -> Self {
+        SshConfig {
+            inactivity_timeout: settings.ssh_inactivity_timeout.unwrap_or(3600),
+            auth_rejection_time: settings.auth_rejection_time.unwrap_or(3),
+            max_concurrent_connections: settings.max_concurrent_connections.unwrap_or(100),
+            shutdown_timeout: settings.shutdown_timeout.unwrap_or(30),
+            max_auth_failures: settings.max_auth_failures.unwrap_or(5),
+            auth_ban_time: settings.auth_ban_time.unwrap_or(60),
+            auth_ban_max_time: settings.auth_ban_max_time.unwrap_or(24 * 60 * 60),
+            enable_sftp: settings.enable_sftp.unwrap_or(true),
+            sftp: SftpConfig::from_settings(settings),
+            enable_port_forwarding: settings.enable_port_forwarding.unwrap_or(true),
+            enable_agent_forwarding: settings.enable_agent_forwarding.unwrap_or(true),
+            gateway_ports: settings
+                .gateway_ports
+                .as_deref()
+                .and_then(|value| GatewayPorts::parse(value).ok())
+                .unwrap_or(GatewayPorts::No),
+            max_streams_per_connection: settings.max_streams_per_connection.unwrap_or(16),
+            permit_open: settings.permit_open.clone().unwrap_or_default(),
+            accept_env: settings
+                .accept_env
+                .clone()
+                .unwrap_or_else(|| vec!["LANG".to_string(), "LC_*".to_string()]),
+            max_persistent_sessions: settings.max_persistent_sessions.unwrap_or(10),
+            persistent_session_timeout: settings
+                .persistent_session_timeout
+                .unwrap_or(7 * 24 * 60 * 60),
+            subsystems: settings.subsystems.clone().unwrap_or_default(),
+            banner: settings.banner.clone(),
+            banner_file: settings.banner_file.clone(),
+            print_motd: settings.print_motd.unwrap_or(true),
+            motd_path: settings
+                .motd_path
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("/etc/motd")),
+            print_last_login: settings.print_last_login.unwrap_or(true),
+        }
+    }
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        SshConfig::from_settings(&ServerSettings::default())
+    }
+}
+
+/// Whether the settings confine SFTP sessions to a root or make any read-only
+fn sftp_restricted(settings: &ServerSettings) -> bool {
+    let restricting_root = |root: &String| root != "none";
+    settings.sftp_root.iter().any(restricting_root)
+        || settings.sftp_root_users.iter().flat_map(|roots| roots.values()).any(restricting_root)
+        || settings.sftp_root_keys.iter().flat_map(|roots| roots.values()).any(restricting_root)
+        || settings.sftp_read_only == Some(true)
+        || settings.sftp_read_only_users.as_ref().is_some_and(|users| !users.is_empty())
+        || settings.sftp_read_only_keys.as_ref().is_some_and(|keys| !keys.is_empty())
+}
+
+/// Addresses remote forwards may listen on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GatewayPorts {
+    /// Only the loopback addresses
+    No,
+    /// All addresses, whatever the client asks for
+    Yes,
+    /// The address the client asks for
+    ClientSpecified,
+}
+
+impl GatewayPorts {
+    /// Parses `no`, `yes` or `clientspecified`
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "no" => Ok(GatewayPorts::No),
+            "yes" => Ok(GatewayPorts::Yes),
+            "clientspecified" => Ok(GatewayPorts::ClientSpecified),
+            _ => Err(anyhow::anyhow!("{:?} is not no, yes or clientspecified", value)),
+        }
+    }
+}
+
+/// Directory an SFTP session is confined to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SftpRoot {
+    /// The login user's home directory
+    Home,
+    Directory(PathBuf),
+    /// The whole file system, relative paths start at the home directory
+    Unrestricted,
+}
+
+impl SftpRoot {
+    /// Parses `home`, `none` or an absolute path
+    pub fn parse(root: &str) -> Result<Self> {
+        match root {
+            "home" => Ok(SftpRoot::Home),
+            "none" => Ok(SftpRoot::Unrestricted),
+            path if Path::new(path).is_absolute() => Ok(SftpRoot::Directory(PathBuf::from(path))),
+            _ => Err(anyhow::anyhow!("{:?} is not home, none or an absolute path", root)),
+        }
+    }
+}
+
+/// SFTP access configuration extracted from server settings
+#[derive(Debug, Clone)]
+pub struct SftpConfig {
+    pub root: SftpRoot,
+    /// Roots of login users
+    pub user_roots: BTreeMap<String, SftpRoot>,
+    /// Roots of key fingerprints, taking precedence over the user's root
+    pub key_roots: BTreeMap<String, SftpRoot>,
+    pub read_only: bool,
+    /// Login users with read-only access, `*` matches everyone
+    pub read_only_users: Vec<String>,
+    /// Key fingerprints with read-only access
+    pub read_only_keys: Vec<String>,
+    /// Files and directories a session may have open at once
+    pub max_open_handles: u32,
+}
+
+impl SftpConfig {
+    /// Invalid roots were rejected when the settings were loaded. Should one
+    /// get here anyway, it falls back to the home directory.
+    fn from_settings(settings: &ServerSettings) -> Self {
+        let parse = |root: &String| SftpRoot::parse(root).unwrap_or(SftpRoot::Home);
+        let parse_all = |roots: &Option<BTreeMap<String, String>>| {
+            roots
+                .iter()
+                .flatten()
+                .map(|(name, root)| (name.clone(), parse(root)))
+                .collect()
+        };
+        SftpConfig {
+            root: settings.sftp_root.as_ref().map_or(SftpRoot::Home, parse),
+            user_roots: parse_all(&settings.sftp_root_users),
+            key_roots: parse_all(&settings.sftp_root_keys),
+            read_only: settings.sftp_read_only.unwrap_or(false),
+            read_only_users: settings.sftp_read_only_users.clone().unwrap_or_default(),
+            read_only_keys: settings.sftp_read_only_keys.clone().unwrap_or_default(),
+            max_open_handles: settings.sftp_max_open_handles.unwrap_or(256),
+        }
+    }
+}
+
+/// Audit log configuration extracted from server settings
+#[derive(Debug, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// Size in bytes after which the log is rotated
+    pub max_size: u64,
+    /// Number of rotated logs to keep
+    pub max_files: u32,
+}
+
+/// TCP fallback listener configuration extracted from server settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpFallbackConfig {
+    pub listen: SocketAddr,
+    pub tls: bool,
+    /// PEM certificate chain and key, a self-signed certificate is used without them
+    pub cert_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    /// The endpoint reported to the coordinator, None if clients can't be told one
+    pub advertise: Option<TcpEndpoint>,
+}
+
+/// Session recording configuration extracted from server settings
+#[derive(Debug, Clone, Default)]
+pub struct RecordingConfig {
+    pub path: PathBuf,
+    /// Login users to record, `*` matches everyone
+    pub users: Vec<String>,
+    /// Key fingerprints to record
+    pub keys: Vec<String>,
+    pub retention_days: u64,
 }
 
 #[cfg(test)]
@@ -327,4 +643,24 @@ mod tests {
         assert_eq!(account_data.device_id, "server-1");
         assert!(account_data.is_registered);
     }
+
+    #[test]
+    fn test_sftp_subsystem_override() {
+        let manager = ServerConfigManager::new().unwrap();
+        let mut settings = ServerSettings {
+            subsystems: Some(BTreeMap::from([(
+                "sftp".to_string(),
+                "/usr/lib/openssh/sftp-server".to_string(),
+            )])),
+            ..Default::default()
+        };
+        // The default home directory root would be bypassed
+        assert!(manager.validate_settings_without_key(&settings).is_err());
+
+        settings.sftp_root = Some("none".to_string());
+        assert!(manager.validate_settings_without_key(&settings).is_ok());
+
+        settings.sftp_read_only_users = Some(vec!["backup".to_string()]);
+        assert!(manager.validate_settings_without_key(&settings).is_err());
+    }
 }
\ No newline at end of file
@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{chown, DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, error, info};
+use rand::Rng;
+use russh::server::Handle;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::process::Child;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::config_manager::GatewayPorts;
+use crate::user::UserInfo;
+
+/// Pause after a failed accept, doubled on every failure in a row
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Listeners opened for `tcpip-forward` requests of a single SSH session.
+/// Every listener is torn down when this is dropped together with the session.
+#[derive(Default)]
+pub struct RemoteForwards {
+    listeners: Mutex<HashMap<(String, u32), Vec<JoinHandle<()>>>>,
+}
+
+impl RemoteForwards {
+    /// Binds the requested address and starts opening a `forwarded-tcpip` channel
+    /// for every accepted connection. Returns the port that was bound.
+    pub async fn start(
+        &self,
+        address: &str,
+        port: u32,
+        gateway_ports: GatewayPorts,
+        handle: Handle,
+    ) -> io::Result<u32> {
+        let port_u16 = u16::try_from(port)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid port"))?;
+
+        let listeners = bind_listeners(listen_address(address, gateway_ports), port_u16).await?;
+        let bound_port = listeners[0].local_addr()?.port() as u32;
+
+        info!("Remote forward listening on {}:{}", address, bound_port);
+
+        let tasks = listeners
+            .into_iter()
+            .map(|listener| {
+                let handle = handle.clone();
+                let connected_address = address.to_string();
+                tokio::spawn(async move {
+                    let mut retry_delay = ACCEPT_RETRY_DELAY;
+                    loop {
+                        let (stream, peer) = match listener.accept().await {
+                            Ok(conn) => conn,
+                            Err(e) => {
+                                if retry_accept("Remote forward", &e, &mut retry_delay).await {
+                                    continue;
+                                }
+                                break;
+                            }
+                        };
+                        retry_delay = ACCEPT_RETRY_DELAY;
+
+                        let handle = handle.clone();
+                        let connected_address = connected_address.clone();
+                        tokio::spawn(async move {
+                            forward_connection(handle, stream, peer, connected_address, bound_port).await;
+                        });
+                    }
+                })
+            })
+            .collect();
+
+        self.listeners
+            .lock()
+            .await
+            .insert((address.to_string(), bound_port), tasks);
+
+        Ok(bound_port)
+    }
+
+    /// Stops a listener started by `start`. Returns false if there was none.
+    pub async fn cancel(&self, address: &str, port: u32) -> bool {
+        match self.listeners.lock().await.remove(&(address.to_string(), port)) {
+            Some(tasks) => {
+                tasks.iter().for_each(JoinHandle::abort);
+                info!("Remote forward on {}:{} cancelled", address, port);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for RemoteForwards {
+    fn drop(&mut self) {
+        for task in self.listeners.get_mut().drain().flat_map(|(_, tasks)| tasks) {
+            task.abort();
+        }
+    }
+}
+
+/// The address to listen on for a requested one, like OpenSSH's `GatewayPorts`
+fn listen_address(address: &str, gateway_ports: GatewayPorts) -> &str {
+    match gateway_ports {
+        GatewayPorts::No => "localhost",
+        GatewayPorts::Yes => "",
+        GatewayPorts::ClientSpecified => address,
+    }
+}
+
+/// Handles a failed accept of a listener named `what`. Errors that won't go
+/// away stop the listener, others are retried after `delay`, so running out of
+/// file descriptors doesn't spin. Returns whether to keep accepting.
+async fn retry_accept(what: &str, e: &io::Error, delay: &mut Duration) -> bool {
+    if matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP)
+    ) {
+        error!("{} stopped accepting connections: {:?}", what, e);
+        return false;
+    }
+
+    error!("{} accept error, retrying in {}ms: {:?}", what, delay.as_millis(), e);
+    tokio::time::sleep(*delay).await;
+    *delay = (*delay * 2).min(MAX_ACCEPT_RETRY_DELAY);
+    true
+}
+
+/// Binds listeners following the address conventions of RFC 4254 7.1
+async fn bind_listeners(address: &str, port: u16) -> io::Result<Vec<TcpListener>> {
+    match address {
+        // All protocol families
+        "" | "*" => match TcpListener::bind(("::", port)).await {
+            Ok(listener) => Ok(vec![listener]),
+            Err(_) => Ok(vec![TcpListener::bind(("0.0.0.0", port)).await?]),
+        },
+        // The loopback addresses of both families, on the same port
+        "localhost" => {
+            let ipv4 = TcpListener::bind(("127.0.0.1", port)).await?;
+            let port = ipv4.local_addr()?.port();
+            match TcpListener::bind(("::1", port)).await {
+                Ok(ipv6) => Ok(vec![ipv4, ipv6]),
+                // Like sshd, one family is enough, e.g. on a host without IPv6
+                Err(e) => {
+                    debug!("Failed to listen on [::1]:{}: {:?}", port, e);
+                    Ok(vec![ipv4])
+                }
+            }
+        }
+        address => Ok(vec![TcpListener::bind((address, port)).await?]),
+    }
+}
+
+async fn forward_connection(
+    handle: Handle,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    connected_address: String,
+    connected_port: u32,
+) {
+    let channel = match handle
+        .channel_open_forwarded_tcpip(
+            connected_address,
+            connected_port,
+            peer.ip().to_string(),
+            peer.port() as u32,
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("Failed to open forwarded-tcpip channel: {:?}", e);
+            return;
+        }
+    };
+
+    let mut channel_stream = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream).await {
+        debug!("Remote forward connection from {} ended: {:?}", peer, e);
+    }
+}
+
+/// Written by the Unix socket relay helper once it is connected, so the server
+/// knows whether to accept the channel before any data flows
+pub const STREAMLOCAL_CONNECTED: u8 = 0;
+
+/// The local end of a direct-streamlocal channel
+pub enum StreamLocal {
+    Direct(UnixStream),
+    /// A relay helper running as the login user, connected through its stdio
+    Helper(Child),
+}
+
+/// Connects to the Unix socket at `path` and relays stdin/stdout to it until the
+/// socket is closed. The server spawns this in a separate process when the socket
+/// has to be opened with the permissions of a different user than the server itself.
+pub async fn relay_stdio_to_socket(path: &Path) -> anyhow::Result<()> {
+    let socket = UnixStream::connect(path).await?;
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&[STREAMLOCAL_CONNECTED]).await?;
+    stdout.flush().await?;
+
+    let (mut socket_read, mut socket_write) = socket.into_split();
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let _ = tokio::io::copy(&mut stdin, &mut socket_write).await;
+        // Pass the end of the client's data on to the socket
+        let _ = socket_write.shutdown().await;
+    });
+
+    tokio::io::copy(&mut socket_read, &mut stdout).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// The agent socket of a session with agent forwarding. Every connection to it is
+/// forwarded to the client's agent through an `auth-agent@openssh.com` channel.
+/// The socket and its directory are removed when this is dropped with the session.
+pub struct AgentForward {
+    dir: PathBuf,
+    socket: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl AgentForward {
+    /// Creates the socket in a new directory only `login` can access
+    pub fn start(login: &UserInfo, handle: Handle) -> io::Result<Self> {
+        let dir = create_agent_dir(login)?;
+        let socket = dir.join(format!("agent.{}", std::process::id()));
+
+        let listener = match bind_agent_socket(&socket, login) {
+            Ok(listener) => listener,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&dir);
+                return Err(e);
+            }
+        };
+
+        let task = tokio::spawn(async move {
+            let mut retry_delay = ACCEPT_RETRY_DELAY;
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        if retry_accept("Agent socket", &e, &mut retry_delay).await {
+                            continue;
+                        }
+                        break;
+                    }
+                };
+                retry_delay = ACCEPT_RETRY_DELAY;
+                tokio::spawn(forward_agent_connection(handle.clone(), stream));
+            }
+        });
+
+        Ok(AgentForward { dir, socket, task })
+    }
+
+    /// The value of `SSH_AUTH_SOCK` for the session's programs
+    pub fn socket_path(&self) -> &Path {
+        &self.socket
+    }
+}
+
+impl Drop for AgentForward {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn create_agent_dir(login: &UserInfo) -> io::Result<PathBuf> {
+    let mut attempts = 0;
+    loop {
+        let dir = std::env::temp_dir().join(format!("sessio-{:08x}", rand::thread_rng().gen::<u32>()));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {
+                if let Err(e) = chown(&dir, Some(login.uid), Some(login.gid)) {
+                    let _ = fs::remove_dir(&dir);
+                    return Err(e);
+                }
+                return Ok(dir);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 8 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn bind_agent_socket(path: &Path, login: &UserInfo) -> io::Result<UnixListener> {
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    chown(path, Some(login.uid), Some(login.gid))?;
+    Ok(listener)
+}
+
+async fn forward_agent_connection(handle: Handle, mut stream: UnixStream) {
+    let channel = match handle.channel_open_agent().await {
+        Ok(channel) => channel,
+        Err(e) => {
+            error!("Failed to open agent channel: {:?}", e);
+            return;
+        }
+    };
+
+    let mut channel_stream = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut channel_stream).await {
+        debug!("Agent connection ended: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_listeners() {
+        assert_eq!(listen_address("0.0.0.0", GatewayPorts::No), "localhost");
+        assert_eq!(listen_address("localhost", GatewayPorts::Yes), "");
+        assert_eq!(listen_address("10.0.0.1", GatewayPorts::ClientSpecified), "10.0.0.1");
+
+        // Every loopback listener shares the port of the first one
+        let listeners = bind_listeners("localhost", 0).await.unwrap();
+        let port = listeners[0].local_addr().unwrap().port();
+        for listener in &listeners {
+            let local = listener.local_addr().unwrap();
+            assert!(local.ip().is_loopback());
+            assert_eq!(local.port(), port);
+        }
+        TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use common::utils::authorized_keys::AuthorizedKey;
+use common::utils::keygen::{authorized_keys_path, read_authorized_key_file};
+use log::{debug, info};
+
+use crate::user::single_user;
+
+#[cfg(target_os = "linux")]
+use inotify::{Inotify, WATCH_MASK};
+#[cfg(target_os = "linux")]
+use log::{error, warn};
+
+/// Parsed authorized_keys files, kept in memory until inotify reports a change
+/// of the file or `invalidate` is called.
+/// A default store has no watcher and reads the file on every lookup.
+#[derive(Default)]
+pub struct AuthorizedKeyStore {
+    cache: Mutex<HashMap<PathBuf, Arc<Vec<AuthorizedKey>>>>,
+    /// Bumped on every invalidation, so that a read racing with a change is not cached
+    generation: AtomicU64,
+    #[cfg(target_os = "linux")]
+    inotify: Option<Inotify>,
+    /// Watched directories by watch descriptor
+    #[cfg(target_os = "linux")]
+    watches: Mutex<HashMap<i32, PathBuf>>,
+}
+
+impl AuthorizedKeyStore {
+    /// Without inotify the store reads the file on every lookup
+    #[cfg(not(target_os = "linux"))]
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    /// Creates a store that watches the files it caches. Without inotify the
+    /// store still works, it just doesn't cache.
+    #[cfg(target_os = "linux")]
+    pub fn new() -> Arc<Self> {
+        let inotify = match Inotify::init() {
+            Ok(inotify) => Some(inotify),
+            Err(e) => {
+                warn!("Failed to watch authorized_keys files, they are read on every login: {}", e);
+                None
+            }
+        };
+
+        let store = Arc::new(AuthorizedKeyStore {
+            inotify,
+            ..Default::default()
+        });
+        if store.inotify.is_some() {
+            tokio::spawn(store.clone().watch());
+        }
+        store
+    }
+
+    /// The authorized keys of the login user whose home directory is `home`
+    pub async fn user_keys(&self, home: &Path) -> anyhow::Result<Arc<Vec<AuthorizedKey>>> {
+        self.keys(&authorized_keys_path(home, single_user())).await
+    }
+
+    /// The keys of the authorized_keys file at `path`. A missing file has no keys.
+    pub async fn keys(&self, path: &Path) -> anyhow::Result<Arc<Vec<AuthorizedKey>>> {
+        if let Some(keys) = self.lock_cache().get(path) {
+            return Ok(keys.clone());
+        }
+
+        // Watch before reading, so a change right after the read is not missed
+        let watched = self.watch_parent(path);
+        let generation = self.generation.load(Ordering::Acquire);
+        let keys = Arc::new(read_authorized_key_file(path).await?);
+
+        let mut cache = self.lock_cache();
+        if watched && self.generation.load(Ordering::Acquire) == generation {
+            debug!("Cached {} authorized keys from {:?}", keys.len(), path);
+            cache.insert(path.to_path_buf(), keys.clone());
+        }
+        Ok(keys)
+    }
+
+    /// Drops the cached keys of `path`, they are read again on the next lookup
+    pub fn invalidate(&self, path: &Path) {
+        let mut cache = self.lock_cache();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        if cache.remove(path).is_some() {
+            info!("Reloading authorized keys from {:?}", path);
+        }
+    }
+
+    /// Drops every cached file
+    pub fn invalidate_all(&self) {
+        let mut cache = self.lock_cache();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        cache.clear();
+    }
+
+    fn lock_cache(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Arc<Vec<AuthorizedKey>>>> {
+        self.cache.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Changes are never reported without inotify
+    #[cfg(not(target_os = "linux"))]
+    fn watch_parent(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Watches the directory of `path`. Returns whether changes of `path` are reported.
+    #[cfg(target_os = "linux")]
+    fn watch_parent(&self, path: &Path) -> bool {
+        let (Some(inotify), Some(dir)) = (self.inotify.as_ref(), path.parent()) else {
+            return false;
+        };
+
+        let mut watches = self.watches.lock().unwrap_or_else(|e| e.into_inner());
+        if watches.values().any(|watched| watched == dir) {
+            return true;
+        }
+        match inotify.add_watch(dir, WATCH_MASK) {
+            Ok(wd) => {
+                watches.insert(wd, dir.to_path_buf());
+                true
+            }
+            Err(e) => {
+                // Usually the directory doesn't exist yet
+                debug!("Not caching {:?}, failed to watch {:?}: {}", path, dir, e);
+                false
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn watch(self: Arc<Self>) {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let Some(inotify) = self.inotify.as_ref() else {
+            return;
+        };
+
+        let mut buffer = [0u8; 4096];
+        loop {
+            let events = match inotify.read_events(&mut buffer).await {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Stopped watching authorized_keys files: {}", e);
+                    // Nothing reports changes anymore
+                    self.watches.lock().unwrap_or_else(|e| e.into_inner()).clear();
+                    self.invalidate_all();
+                    return;
+                }
+            };
+
+            for event in events {
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    warn!("Missed authorized_keys changes, reloading every file");
+                    self.invalidate_all();
+                    continue;
+                }
+
+                let dir = self
+                    .watches
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get(&event.wd)
+                    .cloned();
+                let Some(dir) = dir else {
+                    continue;
+                };
+
+                if event.mask & (libc::IN_DELETE_SELF | libc::IN_MOVE_SELF | libc::IN_IGNORED) != 0 {
+                    // The directory is gone, its files are watched again on the next lookup
+                    self.watches.lock().unwrap_or_else(|e| e.into_inner()).remove(&event.wd);
+                    let _ = inotify.remove_watch(event.wd);
+                    self.lock_cache().retain(|path, _| path.parent() != Some(dir.as_path()));
+                    self.generation.fetch_add(1, Ordering::AcqRel);
+                } else if let Some(name) = event.name {
+                    self.invalidate(&dir.join(OsStr::from_bytes(&name)));
+                }
+            }
+        }
+    }
+}
+
+/// Minimal inotify bindings
+#[cfg(target_os = "linux")]
+mod inotify {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use tokio::io::unix::AsyncFd;
+
+    /// Changes in a watched directory that may replace an authorized_keys file
+    pub const WATCH_MASK: u32 = libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_TO
+        | libc::IN_MOVED_FROM
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    pub struct InotifyEvent {
+        pub wd: i32,
+        pub mask: u32,
+        /// Name of the file in the watched directory the event is about
+        pub name: Option<Vec<u8>>,
+    }
+
+    pub struct Inotify {
+        fd: AsyncFd<OwnedFd>,
+    }
+
+    impl Inotify {
+        pub fn init() -> io::Result<Self> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            Ok(Inotify { fd: AsyncFd::new(fd)? })
+        }
+
+        pub fn add_watch(&self, path: &Path, mask: u32) -> io::Result<i32> {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), mask) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(wd)
+        }
+
+        pub fn remove_watch(&self, wd: i32) -> io::Result<()> {
+            if unsafe { libc::inotify_rm_watch(self.fd.as_raw_fd(), wd) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Waits for the next batch of events
+        pub async fn read_events(&self, buffer: &mut [u8]) -> io::Result<Vec<InotifyEvent>> {
+            loop {
+                let mut guard = self.fd.readable().await?;
+                let read = guard.try_io(|fd| {
+                    let n = unsafe { libc::read(fd.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) };
+                    if n < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(n as usize)
+                });
+                match read {
+                    Ok(Ok(n)) => return Ok(parse_events(&buffer[..n])),
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(Err(e)) => return Err(e),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    /// Parses `struct inotify_event` records, each followed by a NUL padded name
+    fn parse_events(mut data: &[u8]) -> Vec<InotifyEvent> {
+        const HEADER: usize = 16;
+        let field = |data: &[u8], at: usize| u32::from_ne_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+
+        let mut events = Vec::new();
+        while data.len() >= HEADER {
+            let wd = field(data, 0) as i32;
+            let mask = field(data, 4);
+            let len = field(data, 12) as usize;
+            let Some(name) = data.get(HEADER..HEADER + len) else {
+                break;
+            };
+            let name: Vec<u8> = name.iter().copied().take_while(|&b| b != 0).collect();
+            events.push(InotifyEvent {
+                wd,
+                mask,
+                name: (!name.is_empty()).then_some(name),
+            });
+            data = &data[HEADER + len..];
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
+
+    async fn wait_for_keys(store: &AuthorizedKeyStore, path: &Path, count: usize) -> bool {
+        for _ in 0..100 {
+            if store.keys(path).await.unwrap().len() == count {
+                return true;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_reloads_on_change() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("authorized_keys");
+
+        let store = AuthorizedKeyStore::new();
+        assert!(store.keys(&path).await.unwrap().is_empty());
+
+        std::fs::write(&path, format!("{KEY} laptop@linux\n")).unwrap();
+        assert!(wait_for_keys(&store, &path, 1).await);
+
+        // Replaced like editors and sync tools do
+        let tmp = dir.path().join("authorized_keys.tmp");
+        std::fs::write(&tmp, format!("{KEY} laptop@linux\n{KEY} phone@android\n")).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+        assert!(wait_for_keys(&store, &path, 2).await);
+    }
+}
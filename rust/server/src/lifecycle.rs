@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use russh::server::Handle;
+use russh::{server, Disconnect};
+use tokio::sync::Notify;
+
+use crate::config_manager::{AuditConfig, RecordingConfig, SshConfig, TcpFallbackConfig};
+
+/// QUIC application error code of connections closed because the server shuts down
+pub const SHUTDOWN_CODE: u32 = 0x11;
+
+/// Requests to the accept loop of the server
+pub enum ServerCommand {
+    /// Use the reloaded settings for new sessions, existing ones keep theirs
+    Reload(Box<ReloadedConfig>),
+    /// Stop accepting sessions, disconnect the active ones and close the endpoint
+    Shutdown,
+}
+
+/// The settings SIGHUP reloads
+pub struct ReloadedConfig {
+    pub russh: Arc<server::Config>,
+    pub ssh: SshConfig,
+    pub recording: Arc<RecordingConfig>,
+    pub audit: AuditConfig,
+    pub tcp_fallback: Option<TcpFallbackConfig>,
+}
+
+/// The running SSH sessions of the server, so they can be told to disconnect
+#[derive(Default)]
+pub struct ActiveSessions {
+    sessions: Mutex<HashMap<u64, Handle>>,
+    next_id: AtomicU64,
+    closing: AtomicBool,
+    idle: Notify,
+}
+
+impl ActiveSessions {
+    /// Tracks a session until `remove` is called with the returned ID.
+    /// Returns None once the server is shutting down.
+    pub fn insert(&self, handle: Handle) -> Option<u64> {
+        let mut sessions = self.lock();
+        if self.closing.load(Ordering::SeqCst) {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        sessions.insert(id, handle);
+        Some(id)
+    }
+
+    pub fn remove(&self, id: u64) {
+        let mut sessions = self.lock();
+        sessions.remove(&id);
+        if sessions.is_empty() {
+            self.idle.notify_waiters();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Refuses new sessions and sends a disconnect to every active one
+    pub async fn disconnect_all(&self, description: &str) {
+        let handles: Vec<Handle> = {
+            let sessions = self.lock();
+            self.closing.store(true, Ordering::SeqCst);
+            sessions.values().cloned().collect()
+        };
+
+        for handle in handles {
+            if handle
+                .disconnect(Disconnect::ByApplication, description.to_string(), String::new())
+                .await
+                .is_err()
+            {
+                debug!("Session ended before it could be disconnected");
+            }
+        }
+    }
+
+    /// Waits until every session has ended or `timeout` passed.
+    /// Returns whether all sessions ended.
+    pub async fn wait_idle(&self, timeout: Duration) -> bool {
+        let wait = async {
+            loop {
+                let idle = self.idle.notified();
+                if self.lock().is_empty() {
+                    return;
+                }
+                idle.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.is_ok()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Handle>> {
+        self.sessions.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Sends a state like `READY=1` to systemd. Does nothing when the server
+/// was not started by a unit with `Type=notify`.
+#[cfg(target_os = "linux")]
+pub fn sd_notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notify(&path, state) {
+        log::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// There is no systemd to notify
+#[cfg(not(target_os = "linux"))]
+pub fn sd_notify(_state: &str) {}
+
+#[cfg(target_os = "linux")]
+fn send_notify(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // Abstract socket namespace
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// `CLOCK_MONOTONIC` in microseconds, as systemd expects with `RELOADING=1`
+pub fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_sd_notify() {
+        use std::os::unix::net::UnixDatagram;
+        use tempfile::TempDir;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notify.sock");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+
+        send_notify(path.as_os_str(), "READY=1\nSTATUS=Accepting connections").unwrap();
+        let mut buffer = [0u8; 128];
+        let n = systemd.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..n], b"READY=1\nSTATUS=Accepting connections");
+    }
+
+    #[tokio::test]
+    async fn test_wait_idle_without_sessions() {
+        let sessions = ActiveSessions::default();
+        assert!(sessions.wait_idle(Duration::from_millis(10)).await);
+        sessions.disconnect_all("Server shutting down").await;
+        assert_eq!(sessions.len(), 0);
+    }
+}
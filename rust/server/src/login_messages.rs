@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config_manager::SshConfig;
+
+/// The banner sent before authentication, the inline text or else the banner file
+pub async fn load_banner(config: &SshConfig) -> Option<String> {
+    if let Some(banner) = &config.banner {
+        return Some(banner.clone());
+    }
+
+    let path = config.banner_file.as_ref()?;
+    match tokio::fs::read_to_string(path).await {
+        Ok(banner) => Some(banner),
+        Err(e) => {
+            warn!("Failed to read banner file {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// The message of the day, if it is enabled and not empty
+pub async fn load_motd(config: &SshConfig) -> Option<String> {
+    if !config.print_motd {
+        return None;
+    }
+    let motd = tokio::fs::read_to_string(&config.motd_path).await.ok()?;
+    (!motd.trim().is_empty()).then_some(motd)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastLogin {
+    /// Unix timestamp
+    pub time: u64,
+    /// Device ID of the client, or its address if the device is unknown
+    pub source: String,
+}
+
+/// The last login of every user, stored as JSON.
+/// A default `LastLogins` is disabled and stores nothing.
+#[derive(Default)]
+pub struct LastLogins {
+    path: Option<PathBuf>,
+    lock: Mutex<()>,
+}
+
+impl LastLogins {
+    pub fn new(path: PathBuf) -> Self {
+        LastLogins {
+            path: Some(path),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Stores a login of `user` from `source` and returns the previous one
+    pub fn record(&self, user: &str, source: &str) -> Option<LastLogin> {
+        let path = self.path.as_ref()?;
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut logins: HashMap<String, LastLogin> = fs::read(path)
+            .ok()
+            .and_then(|contents| serde_json::from_slice(&contents).ok())
+            .unwrap_or_default();
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let previous = logins.insert(
+            user.to_string(),
+            LastLogin {
+                time,
+                source: source.to_string(),
+            },
+        );
+
+        if let Err(e) = write_logins(path, &logins) {
+            error!("Failed to store last login of {}: {}", user, e);
+        }
+        previous
+    }
+}
+
+fn write_logins(path: &Path, logins: &HashMap<String, LastLogin>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(&serde_json::to_vec(logins)?)
+}
+
+/// The text printed before a shell starts. Line endings are CRLF, as the
+/// terminal is in raw mode on the client.
+pub fn welcome_message(motd: Option<&str>, last_login: Option<&LastLogin>) -> String {
+    let mut message = String::new();
+    if let Some(motd) = motd {
+        message.push_str(motd);
+        if !motd.ends_with('\n') {
+            message.push('\n');
+        }
+    }
+    if let Some(last) = last_login {
+        message.push_str(&format!(
+            "Last login: {} from {}\n",
+            format_utc(last.time),
+            last.source
+        ));
+    }
+    message.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+fn format_utc(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// Proleptic Gregorian date of a day count since the unix epoch
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_utc(1700000000), "2023-11-14 22:13:20 UTC");
+    }
+
+    #[test]
+    fn test_welcome_message() {
+        let last = LastLogin {
+            time: 0,
+            source: "laptop".to_string(),
+        };
+        assert_eq!(
+            welcome_message(Some("Welcome\nBe nice"), Some(&last)),
+            "Welcome\r\nBe nice\r\nLast login: 1970-01-01 00:00:00 UTC from laptop\r\n"
+        );
+        assert_eq!(welcome_message(None, None), "");
+    }
+
+    #[test]
+    fn test_record_returns_previous_login() {
+        let dir = TempDir::new().unwrap();
+        let logins = LastLogins::new(dir.path().join("lastlog.json"));
+
+        assert_eq!(logins.record("alice", "laptop"), None);
+        let previous = logins.record("alice", "phone").unwrap();
+        assert_eq!(previous.source, "laptop");
+        assert_eq!(logins.record("bob", "laptop"), None);
+        assert_eq!(LastLogins::default().record("alice", "laptop"), None);
+    }
+}
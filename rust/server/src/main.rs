@@ -1,12 +1,29 @@
-use std::{fs, path::PathBuf};
+use std::{fs, net::{Ipv6Addr, SocketAddr}, path::PathBuf};
 
 use clap::{Parser, Subcommand};
+use common::utils::direct::DEFAULT_DIRECT_PORT;
 use serde::Deserialize;
 use url::Url;
 use dirs;
 mod server;
+mod audit;
+mod bans;
 mod sftp;
+mod sftp_extensions;
+mod sftp_jail;
 mod config_manager;
+mod forward;
+mod key_store;
+mod lifecycle;
+mod login_messages;
+mod persistent;
+mod policy;
+mod process;
+mod pty_io;
+mod recording;
+mod subsystem;
+mod tcp_fallback;
+mod user;
 
 use config_manager::ServerConfigManager;
 
@@ -35,6 +52,18 @@ enum Commands {
         // Optional configuration file
         #[clap(long, short = 'f')]
         config: Option<PathBuf>,
+
+        /// Run without a coordinator, clients connect to the listen address directly
+        #[clap(long)]
+        direct: bool,
+
+        /// UDP address to listen on in direct mode
+        #[clap(
+            long,
+            requires = "direct",
+            default_value_t = SocketAddr::from((Ipv6Addr::UNSPECIFIED, DEFAULT_DIRECT_PORT))
+        )]
+        listen: SocketAddr,
     },
     /// Install the server with an install key
     Install {
@@ -54,6 +83,46 @@ enum Commands {
         #[clap(long, short = 'f')]
         config: Option<PathBuf>,
     },
+    /// Manage recorded terminal sessions
+    Recordings {
+        #[clap(subcommand)]
+        action: RecordingsAction,
+    },
+    /// Serve SFTP over stdin/stdout, spawned by the server to run the subsystem as a login user
+    #[clap(hide = true)]
+    SftpServer {
+        #[clap(long)]
+        user: String,
+        /// Directory the session is confined to, the whole file system if not set
+        #[clap(long)]
+        root: Option<PathBuf>,
+        #[clap(long)]
+        read_only: bool,
+        /// Files and directories the session may have open at once
+        #[clap(long, default_value_t = 256)]
+        max_handles: u32,
+    },
+    /// Relay stdin/stdout to a Unix socket, spawned by the server to connect as a login user
+    #[clap(hide = true)]
+    StreamlocalRelay {
+        #[clap(long)]
+        path: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RecordingsAction {
+    /// List the stored recordings
+    List,
+    /// Export a recording as an asciinema v2 file
+    Export {
+        /// The recording ID from `recordings list`
+        id: String,
+
+        /// Where to write the recording, stdout if not set
+        #[clap(long, short = 'o')]
+        output: Option<PathBuf>,
+    },
 }
 
 #[tokio::main]
@@ -61,8 +130,43 @@ async fn main() {
     let opt = Opt::parse();
 
     match opt.command {
-        Commands::Run { .. } => {
-            server::run().await;
+        Commands::Run { direct, listen, .. } => {
+            server::run(direct.then_some(listen)).await;
+        }
+        Commands::Recordings { action } => {
+            let mut config_manager = ServerConfigManager::new()
+                .expect("Failed to initialize configuration manager");
+            let config = config_manager.get_recording_config().await
+                .expect("Failed to get session recording configuration");
+
+            if let Err(e) = run_recordings_action(action, &config.path) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::SftpServer { user, root, read_only, max_handles } => {
+            env_logger::Builder::from_default_env()
+                .filter_level(log::LevelFilter::Info)
+                .init();
+
+            if let Err(e) = sftp::serve_stdio(user, root, read_only, max_handles).await {
+                log::error!("SFTP server failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Commands::StreamlocalRelay { path } => {
+            env_logger::Builder::from_default_env()
+                .filter_level(log::LevelFilter::Info)
+                .init();
+
+            // Exiting right away, the runtime would wait for the blocking stdin read otherwise
+            match forward::relay_stdio_to_socket(&path).await {
+                Ok(()) => std::process::exit(0),
+                Err(e) => {
+                    log::error!("Unix socket relay to {:?} failed: {}", path, e);
+                    std::process::exit(1);
+                }
+            }
         }
         Commands::Install { install_key, coordinator, id, config } => {
             let mut config_manager = ServerConfigManager::new()
@@ -80,6 +184,27 @@ async fn main() {
     }
 }
 
+fn run_recordings_action(action: RecordingsAction, dir: &std::path::Path) -> anyhow::Result<()> {
+    match action {
+        RecordingsAction::List => {
+            let recordings = recording::list_recordings(dir)?;
+            if recordings.is_empty() {
+                println!("No recordings in {:?}", dir);
+            }
+            for recording in recordings {
+                println!(
+                    "{}\tuser: {}\tstarted: {}\tsize: {} bytes",
+                    recording.id, recording.user, recording.started_at, recording.size
+                );
+            }
+        }
+        RecordingsAction::Export { id, output } => {
+            recording::export_recording(dir, &id, output.as_deref())?;
+        }
+    }
+    Ok(())
+}
+
 async fn install_server(install_key: String, coordinator: Url, id: String, config: Option<PathBuf>, config_manager: &mut ServerConfigManager) -> Result<(), Box<dyn std::error::Error>> {
     use serde_json::json;
     use std::path::Path;
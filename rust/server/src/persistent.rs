@@ -0,0 +1,302 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use common::utils::persistent_sessions::PersistentSessionInfo;
+use anyhow::bail;
+use log::info;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec};
+use tokio::sync::{Mutex, Notify};
+
+use crate::audit::ChannelStats;
+use crate::process::{report_exit, ChildExit};
+use crate::server::PtyStream;
+use crate::subsystem::{Subsystem, SubsystemIo};
+use crate::user::UserInfo;
+
+/// How much recent output is kept for replaying on reattach
+const SCROLLBACK_LIMIT: usize = 256 * 1024;
+
+/// A PTY session that outlives the SSH session which started it.
+/// Output is kept in a scrollback buffer and forwarded to whichever channel is attached.
+pub struct PersistentPty {
+    pub id: String,
+    /// Name of the login user that owns the session
+    pub owner: String,
+    pub created_at: u64,
+    pub pty: Arc<PtyStream>,
+    state: Mutex<AttachState>,
+    /// Signalled when another channel attaches
+    reattached: Notify,
+}
+
+#[derive(Default)]
+struct AttachState {
+    scrollback: VecDeque<u8>,
+    attached: Option<Attached>,
+    /// Attachments so far, numbering each one
+    attachments: u64,
+    /// When the last channel detached, None while one is attached
+    detached_since: Option<Instant>,
+}
+
+impl AttachState {
+    fn detach(&mut self, id: &str) {
+        info!("Persistent session {} detached", id);
+        self.attached = None;
+        self.detached_since = Some(Instant::now());
+    }
+}
+
+#[derive(Clone)]
+struct Attached {
+    /// Tells attachments apart, channel IDs of different SSH sessions may be equal
+    number: u64,
+    handle: Handle,
+    channel_id: ChannelId,
+    stats: Option<Arc<ChannelStats>>,
+}
+
+impl PersistentPty {
+    pub fn new(id: String, owner: String, pty: Arc<PtyStream>) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        PersistentPty {
+            id,
+            owner,
+            created_at,
+            pty,
+            state: Mutex::new(AttachState::default()),
+            reattached: Notify::new(),
+        }
+    }
+
+    /// Records PTY output and sends it to the attached channel, if any.
+    /// A channel that can't be written to anymore is detached.
+    pub async fn output(&self, data: &[u8]) {
+        // Sending waits for window space of the channel, which never comes once the
+        // client is gone, so the lock is released first to let a new channel attach
+        let (attached, reattached) = {
+            let mut state = self.state.lock().await;
+            state.scrollback.extend(data);
+            let overflow = state.scrollback.len().saturating_sub(SCROLLBACK_LIMIT);
+            state.scrollback.drain(..overflow);
+            (state.attached.clone(), self.reattached.notified())
+        };
+        let Some(attached) = attached else {
+            return;
+        };
+
+        let sent = tokio::select! {
+            sent = attached.handle.data(attached.channel_id, CryptoVec::from_slice(data)) => sent,
+            // The new channel gets this output replayed from the scrollback
+            _ = reattached => return,
+        };
+        match sent {
+            Ok(()) => {
+                if let Some(stats) = attached.stats.as_ref() {
+                    stats.add_out(data.len() as u64);
+                }
+            }
+            Err(_) => {
+                let mut state = self.state.lock().await;
+                if matches!(&state.attached, Some(current) if current.number == attached.number) {
+                    state.detach(&self.id);
+                }
+            }
+        }
+    }
+
+    /// Attaches a channel to the session and replays the scrollback to it.
+    /// A channel that was attached before is closed. Returns the number of
+    /// the attachment, for detaching it later.
+    pub async fn attach(
+        &self,
+        handle: Handle,
+        channel_id: ChannelId,
+        stats: Option<Arc<ChannelStats>>,
+    ) -> u64 {
+        let mut state = self.state.lock().await;
+
+        if let Some(old) = state.attached.take() {
+            // The old channel may be stuck, don't wait for it with the lock held
+            tokio::spawn(async move {
+                let _ = old.handle.close(old.channel_id).await;
+            });
+        }
+
+        let (front, back) = state.scrollback.as_slices();
+        let mut replay = CryptoVec::from_slice(front);
+        replay.extend(back);
+        if !replay.is_empty() {
+            let replayed = replay.len() as u64;
+            if handle.data(channel_id, replay).await.is_ok() {
+                if let Some(stats) = stats.as_ref() {
+                    stats.add_out(replayed);
+                }
+            }
+        }
+
+        info!("Persistent session {} attached", self.id);
+        state.attachments += 1;
+        state.detached_since = None;
+        state.attached = Some(Attached {
+            number: state.attachments,
+            handle,
+            channel_id,
+            stats,
+        });
+        self.reattached.notify_waiters();
+        state.attachments
+    }
+
+    /// Detaches the channel of `attachment` if it's the one currently attached
+    pub async fn detach(&self, attachment: u64) {
+        let mut state = self.state.lock().await;
+        if matches!(&state.attached, Some(attached) if attached.number == attachment) {
+            state.detach(&self.id);
+        }
+    }
+
+    /// Reports the exit of the session's process to the attached channel
+    pub async fn finish(&self, exit: ChildExit) {
+        let attached = self.state.lock().await.attached.take();
+        if let Some(attached) = attached {
+            report_exit(&attached.handle, attached.channel_id, exit).await;
+        }
+    }
+
+    /// Whether no channel has been attached for longer than `timeout`.
+    /// A session busy attaching a channel is not.
+    fn detached_longer_than(&self, timeout: Duration) -> bool {
+        let Ok(state) = self.state.try_lock() else {
+            return false;
+        };
+        state.attached.is_none()
+            && state
+                .detached_since
+                .is_some_and(|since| since.elapsed() > timeout)
+    }
+
+    pub async fn info(&self) -> PersistentSessionInfo {
+        PersistentSessionInfo {
+            id: self.id.clone(),
+            created_at: self.created_at,
+            attached: self.state.lock().await.attached.is_some(),
+        }
+    }
+}
+
+/// All persistent PTY sessions of the server, shared by every connection.
+/// Session IDs are chosen by clients, so each login user has their own.
+#[derive(Default)]
+pub struct PersistentSessions {
+    sessions: Mutex<HashMap<(String, String), Arc<PersistentPty>>>,
+    /// Seconds a session keeps running without an attached channel, 0 forever
+    detached_timeout: AtomicU64,
+}
+
+impl PersistentSessions {
+    /// Returns the running session `id` of `owner` and true, or registers a new
+    /// session on `pty` and returns false. Fails if `owner` already has `max`
+    /// sessions running.
+    pub async fn open(
+        &self,
+        id: String,
+        owner: &str,
+        pty: &Arc<PtyStream>,
+        max: u32,
+    ) -> anyhow::Result<(Arc<PersistentPty>, bool)> {
+        let mut sessions = self.sessions.lock().await;
+        let key = (owner.to_string(), id);
+        if let Some(existing) = sessions.get(&key) {
+            return Ok((existing.clone(), true));
+        }
+
+        let running = sessions.keys().filter(|(user, _)| user == owner).count();
+        if running >= max as usize {
+            bail!("Too many persistent sessions, {} of {} are running", running, max);
+        }
+
+        let session = Arc::new(PersistentPty::new(key.1.clone(), key.0.clone(), pty.clone()));
+        sessions.insert(key, session.clone());
+        Ok((session, false))
+    }
+
+    /// Unregisters `session`, unless another session has replaced it
+    pub async fn remove(&self, session: &Arc<PersistentPty>) {
+        let mut sessions = self.sessions.lock().await;
+        let key = (session.owner.clone(), session.id.clone());
+        if sessions.get(&key).is_some_and(|current| Arc::ptr_eq(current, session)) {
+            sessions.remove(&key);
+        }
+    }
+
+    pub fn set_detached_timeout(&self, seconds: u64) {
+        self.detached_timeout.store(seconds, Ordering::Relaxed);
+    }
+
+    /// Unregisters and returns the sessions no channel has been attached to for
+    /// longer than the detached timeout
+    pub async fn remove_detached(&self) -> Vec<Arc<PersistentPty>> {
+        let timeout = match self.detached_timeout.load(Ordering::Relaxed) {
+            0 => return Vec::new(),
+            seconds => Duration::from_secs(seconds),
+        };
+
+        let mut sessions = self.sessions.lock().await;
+        let expired: Vec<(String, String)> = sessions
+            .iter()
+            .filter(|(_, session)| session.detached_longer_than(timeout))
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|key| sessions.remove(key))
+            .collect()
+    }
+
+    pub async fn list(&self, owner: &str) -> Vec<PersistentSessionInfo> {
+        let sessions: Vec<Arc<PersistentPty>> = self
+            .sessions
+            .lock()
+            .await
+            .iter()
+            .filter(|((user, _), _)| user == owner)
+            .map(|(_, session)| session.clone())
+            .collect();
+
+        let mut infos = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            infos.push(session.info().await);
+        }
+        infos
+    }
+}
+
+/// Lists the persistent sessions of the login user as JSON
+pub struct PersistentSessionsSubsystem {
+    sessions: Arc<PersistentSessions>,
+}
+
+impl PersistentSessionsSubsystem {
+    pub fn new(sessions: Arc<PersistentSessions>) -> Self {
+        PersistentSessionsSubsystem { sessions }
+    }
+}
+
+#[async_trait::async_trait]
+impl Subsystem for PersistentSessionsSubsystem {
+    async fn start(&self, login: &Arc<UserInfo>, _fingerprint: &str) -> anyhow::Result<SubsystemIo> {
+        let listing = serde_json::to_vec(&self.sessions.list(&login.name).await)?;
+        Ok(SubsystemIo::stream(tokio::io::join(
+            std::io::Cursor::new(listing),
+            tokio::io::sink(),
+        )))
+    }
+}
@@ -0,0 +1,98 @@
+use common::utils::authorized_keys::wildcard_match;
+
+/// Whether direct-tcpip may connect to `host:port` under a permit-open allowlist.
+/// Patterns are `host:port`, where either side may be `*` and IPv6 hosts are
+/// written in brackets. An empty allowlist permits every destination.
+pub fn permit_open_allows(patterns: &[String], host: &str, port: u32) -> bool {
+    if patterns.is_empty() {
+        return true;
+    }
+
+    patterns
+        .iter()
+        .any(|pattern| pattern_matches(pattern, host, port))
+}
+
+fn pattern_matches(pattern: &str, host: &str, port: u32) -> bool {
+    let Some((pattern_host, pattern_port)) = pattern.trim().rsplit_once(':') else {
+        return false;
+    };
+
+    let pattern_host = pattern_host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(pattern_host);
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+
+    let host_matches = pattern_host == "*" || pattern_host.eq_ignore_ascii_case(host);
+    let port_matches = pattern_port == "*" || pattern_port.parse::<u32>() == Ok(port);
+
+    host_matches && port_matches
+}
+
+/// Whether a client may set the environment variable `name` under an AcceptEnv
+/// allowlist. Patterns match whole names, `*` matches any characters and `?` one.
+pub fn accept_env_allows(patterns: &[String], name: &str) -> bool {
+    !name.is_empty()
+        && patterns
+            .iter()
+            .any(|pattern| wildcard_match(pattern.trim().as_bytes(), name.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patterns(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_empty_allowlist_permits_everything() {
+        assert!(permit_open_allows(&[], "example.com", 443));
+    }
+
+    #[test]
+    fn test_exact_and_wildcard_patterns() {
+        let list = patterns(&["localhost:8080", "*:22", "db.internal:*"]);
+
+        assert!(permit_open_allows(&list, "LOCALHOST", 8080));
+        assert!(!permit_open_allows(&list, "localhost", 8081));
+        assert!(permit_open_allows(&list, "10.0.0.5", 22));
+        assert!(permit_open_allows(&list, "db.internal", 5432));
+        assert!(!permit_open_allows(&list, "example.com", 443));
+    }
+
+    #[test]
+    fn test_ipv6_patterns() {
+        let list = patterns(&["[::1]:5432"]);
+
+        assert!(permit_open_allows(&list, "::1", 5432));
+        assert!(permit_open_allows(&list, "[::1]", 5432));
+        assert!(!permit_open_allows(&list, "::1", 5433));
+    }
+
+    #[test]
+    fn test_malformed_pattern_matches_nothing() {
+        let list = patterns(&["localhost"]);
+        assert!(!permit_open_allows(&list, "localhost", 80));
+    }
+
+    #[test]
+    fn test_accept_env_patterns() {
+        let list = patterns(&["LANG", "LC_*", "GIT_?UTHOR_NAME"]);
+
+        assert!(accept_env_allows(&list, "LANG"));
+        assert!(accept_env_allows(&list, "LC_ALL"));
+        assert!(accept_env_allows(&list, "LC_"));
+        assert!(accept_env_allows(&list, "GIT_AUTHOR_NAME"));
+        assert!(!accept_env_allows(&list, "LANGUAGE"));
+        assert!(!accept_env_allows(&list, "lang"));
+        assert!(!accept_env_allows(&list, "LD_PRELOAD"));
+        assert!(!accept_env_allows(&[], "LANG"));
+        assert!(!accept_env_allows(&patterns(&["*"]), ""));
+    }
+}
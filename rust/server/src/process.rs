@@ -0,0 +1,444 @@
+use std::ffi::CStr;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::{error, info};
+use portable_pty::MasterPty;
+use russh::server::Handle;
+use russh::{ChannelId, CryptoVec, Pty, Sig};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::user::UserInfo;
+
+/// SSH_EXTENDED_DATA_STDERR from RFC 4254
+pub const EXTENDED_DATA_STDERR: u32 = 1;
+
+/// How a process spawned for a channel terminated
+#[derive(Debug)]
+pub enum ChildExit {
+    Code(u32),
+    Signal { signal: Sig, core_dumped: bool },
+}
+
+impl From<std::process::ExitStatus> for ChildExit {
+    fn from(status: std::process::ExitStatus) -> Self {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+
+            if let Some(signum) = status.signal() {
+                return ChildExit::Signal {
+                    signal: sig_from_signum(signum),
+                    core_dumped: status.core_dumped(),
+                };
+            }
+        }
+
+        ChildExit::Code(status.code().map(|c| c as u32).unwrap_or(1))
+    }
+}
+
+/// Maps a unix signal number to its RFC 4254 signal name
+#[cfg(unix)]
+pub fn sig_from_signum(signum: i32) -> Sig {
+    match signum {
+        libc::SIGABRT => Sig::ABRT,
+        libc::SIGALRM => Sig::ALRM,
+        libc::SIGFPE => Sig::FPE,
+        libc::SIGHUP => Sig::HUP,
+        libc::SIGILL => Sig::ILL,
+        libc::SIGINT => Sig::INT,
+        libc::SIGKILL => Sig::KILL,
+        libc::SIGPIPE => Sig::PIPE,
+        libc::SIGQUIT => Sig::QUIT,
+        libc::SIGSEGV => Sig::SEGV,
+        libc::SIGTERM => Sig::TERM,
+        libc::SIGUSR1 => Sig::USR1,
+        libc::SIGUSR2 => Sig::Custom("USR2".to_string()),
+        other => Sig::Custom(other.to_string()),
+    }
+}
+
+/// Maps an RFC 4254 signal name to the unix signal number
+pub fn signum_from_sig(signal: &Sig) -> Option<i32> {
+    let signum = match signal {
+        Sig::ABRT => libc::SIGABRT,
+        Sig::ALRM => libc::SIGALRM,
+        Sig::FPE => libc::SIGFPE,
+        Sig::HUP => libc::SIGHUP,
+        Sig::ILL => libc::SIGILL,
+        Sig::INT => libc::SIGINT,
+        Sig::KILL => libc::SIGKILL,
+        Sig::PIPE => libc::SIGPIPE,
+        Sig::QUIT => libc::SIGQUIT,
+        Sig::SEGV => libc::SIGSEGV,
+        Sig::TERM => libc::SIGTERM,
+        Sig::USR1 => libc::SIGUSR1,
+        Sig::Custom(name) => match name.as_str() {
+            "USR2" => libc::SIGUSR2,
+            _ => return None,
+        },
+    };
+    Some(signum)
+}
+
+/// Sends a signal to a process, or to a process group when `pid` is negative
+pub fn send_signal(pid: i32, signum: i32) -> io::Result<()> {
+    if unsafe { libc::kill(pid, signum) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// What a session channel runs on behalf of the logged in user
+pub enum SessionProgram {
+    /// The user's shell, started as a login shell
+    LoginShell,
+    /// A command string run through the user's shell
+    Command(String),
+}
+
+/// Builds the command for a session program. It starts in the user's home
+/// directory with a clean login environment instead of the server's.
+pub fn build_command(user: &UserInfo, program: &SessionProgram) -> Command {
+    let mut command = Command::new(&user.shell);
+    match program {
+        SessionProgram::LoginShell => {
+            // A leading dash in argv[0] tells the shell to act as a login shell
+            let name = user
+                .shell
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "sh".to_string());
+            command.arg0(format!("-{}", name));
+        }
+        SessionProgram::Command(cmd) => {
+            command.arg("-c").arg(cmd);
+        }
+    }
+
+    let home = if user.home.is_dir() {
+        user.home.as_path()
+    } else {
+        Path::new("/")
+    };
+    command.env_clear().envs(user.login_env()).current_dir(home);
+    command
+}
+
+/// Makes the command switch to `user` right before it executes
+pub fn run_as(command: &mut Command, user: &UserInfo) {
+    if !user.needs_switch() {
+        return;
+    }
+    let user = user.clone();
+    unsafe {
+        command.pre_exec(move || user.switch_to());
+    }
+}
+
+/// Spawns the command as a session leader with the PTY behind `master`
+/// as its controlling terminal, running as `user`.
+pub fn spawn_on_pty(
+    mut command: Command,
+    master: &dyn MasterPty,
+    user: &UserInfo,
+) -> io::Result<std::process::Child> {
+    let fd = master
+        .as_raw_fd()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "PTY has no file descriptor"))?;
+    let tty = slave_path(fd)?;
+
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(&tty)?;
+
+    // The terminal has to belong to the user for tools like mesg and write
+    if user.needs_switch() {
+        std::os::unix::fs::chown(&tty, Some(user.uid), None)?;
+    }
+
+    command
+        .stdin(slave.try_clone()?)
+        .stdout(slave.try_clone()?)
+        .stderr(slave);
+
+    unsafe {
+        command.pre_exec(|| {
+            // Clear out signal dispositions inherited from the server
+            for signo in [
+                libc::SIGCHLD,
+                libc::SIGHUP,
+                libc::SIGINT,
+                libc::SIGQUIT,
+                libc::SIGTERM,
+                libc::SIGALRM,
+                libc::SIGPIPE,
+            ] {
+                libc::signal(signo, libc::SIG_DFL);
+            }
+
+            if libc::setsid() == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    run_as(&mut command, user);
+
+    command.spawn()
+}
+
+/// Applies the terminal modes encoded in a pty-req (RFC 4254 section 8)
+/// to the slave side of the PTY behind `master`.
+pub fn apply_terminal_modes(master: &dyn MasterPty, modes: &[(Pty, u32)]) -> io::Result<()> {
+    if modes.is_empty() {
+        return Ok(());
+    }
+
+    let fd = master
+        .as_raw_fd()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "PTY has no file descriptor"))?;
+    let slave = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(slave_path(fd)?)?;
+    let slave_fd = std::os::unix::io::AsRawFd::as_raw_fd(&slave);
+
+    let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(slave_fd, &mut termios) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    for (mode, value) in modes {
+        apply_terminal_mode(&mut termios, *mode as u8, *value);
+    }
+
+    if unsafe { libc::tcsetattr(slave_fd, libc::TCSANOW, &termios) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn apply_terminal_mode(termios: &mut libc::termios, opcode: u8, value: u32) {
+    let set_flag = |flags: &mut libc::tcflag_t, flag: libc::tcflag_t| {
+        if value != 0 {
+            *flags |= flag;
+        } else {
+            *flags &= !flag;
+        }
+    };
+
+    match opcode {
+        // Special characters
+        1 => termios.c_cc[libc::VINTR] = value as libc::cc_t,
+        2 => termios.c_cc[libc::VQUIT] = value as libc::cc_t,
+        3 => termios.c_cc[libc::VERASE] = value as libc::cc_t,
+        4 => termios.c_cc[libc::VKILL] = value as libc::cc_t,
+        5 => termios.c_cc[libc::VEOF] = value as libc::cc_t,
+        6 => termios.c_cc[libc::VEOL] = value as libc::cc_t,
+        7 => termios.c_cc[libc::VEOL2] = value as libc::cc_t,
+        8 => termios.c_cc[libc::VSTART] = value as libc::cc_t,
+        9 => termios.c_cc[libc::VSTOP] = value as libc::cc_t,
+        10 => termios.c_cc[libc::VSUSP] = value as libc::cc_t,
+        12 => termios.c_cc[libc::VREPRINT] = value as libc::cc_t,
+        13 => termios.c_cc[libc::VWERASE] = value as libc::cc_t,
+        14 => termios.c_cc[libc::VLNEXT] = value as libc::cc_t,
+        18 => termios.c_cc[libc::VDISCARD] = value as libc::cc_t,
+
+        // Input modes
+        30 => set_flag(&mut termios.c_iflag, libc::IGNPAR),
+        31 => set_flag(&mut termios.c_iflag, libc::PARMRK),
+        32 => set_flag(&mut termios.c_iflag, libc::INPCK),
+        33 => set_flag(&mut termios.c_iflag, libc::ISTRIP),
+        34 => set_flag(&mut termios.c_iflag, libc::INLCR),
+        35 => set_flag(&mut termios.c_iflag, libc::IGNCR),
+        36 => set_flag(&mut termios.c_iflag, libc::ICRNL),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        37 => set_flag(&mut termios.c_iflag, libc::IUCLC),
+        38 => set_flag(&mut termios.c_iflag, libc::IXON),
+        39 => set_flag(&mut termios.c_iflag, libc::IXANY),
+        40 => set_flag(&mut termios.c_iflag, libc::IXOFF),
+        41 => set_flag(&mut termios.c_iflag, libc::IMAXBEL),
+        #[cfg(any(target_os = "linux", target_os = "android", target_vendor = "apple"))]
+        42 => set_flag(&mut termios.c_iflag, libc::IUTF8),
+
+        // Local modes
+        50 => set_flag(&mut termios.c_lflag, libc::ISIG),
+        51 => set_flag(&mut termios.c_lflag, libc::ICANON),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        52 => set_flag(&mut termios.c_lflag, libc::XCASE),
+        53 => set_flag(&mut termios.c_lflag, libc::ECHO),
+        54 => set_flag(&mut termios.c_lflag, libc::ECHOE),
+        55 => set_flag(&mut termios.c_lflag, libc::ECHOK),
+        56 => set_flag(&mut termios.c_lflag, libc::ECHONL),
+        57 => set_flag(&mut termios.c_lflag, libc::NOFLSH),
+        58 => set_flag(&mut termios.c_lflag, libc::TOSTOP),
+        59 => set_flag(&mut termios.c_lflag, libc::IEXTEN),
+        60 => set_flag(&mut termios.c_lflag, libc::ECHOCTL),
+        61 => set_flag(&mut termios.c_lflag, libc::ECHOKE),
+        62 => set_flag(&mut termios.c_lflag, libc::PENDIN),
+
+        // Output modes
+        70 => set_flag(&mut termios.c_oflag, libc::OPOST),
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        71 => set_flag(&mut termios.c_oflag, libc::OLCUC),
+        72 => set_flag(&mut termios.c_oflag, libc::ONLCR),
+        73 => set_flag(&mut termios.c_oflag, libc::OCRNL),
+        74 => set_flag(&mut termios.c_oflag, libc::ONOCR),
+        75 => set_flag(&mut termios.c_oflag, libc::ONLRET),
+
+        // Control modes
+        90 => {
+            if value != 0 {
+                termios.c_cflag = (termios.c_cflag & !libc::CSIZE) | libc::CS7;
+            }
+        }
+        91 => {
+            if value != 0 {
+                termios.c_cflag = (termios.c_cflag & !libc::CSIZE) | libc::CS8;
+            }
+        }
+        92 => set_flag(&mut termios.c_cflag, libc::PARENB),
+        93 => set_flag(&mut termios.c_cflag, libc::PARODD),
+
+        // Line speeds have no meaning for a PTY, other opcodes are not supported here
+        _ => {}
+    }
+}
+
+/// Path of the slave device for a PTY master
+fn slave_path(master_fd: libc::c_int) -> io::Result<PathBuf> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        let mut buf = vec![0 as libc::c_char; 128];
+        let rc = unsafe { libc::ptsname_r(master_fd, buf.as_mut_ptr(), buf.len()) };
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        Ok(PathBuf::from(name.to_string_lossy().into_owned()))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let name = unsafe { libc::ptsname(master_fd) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let name = unsafe { CStr::from_ptr(name) };
+        Ok(PathBuf::from(name.to_string_lossy().into_owned()))
+    }
+}
+
+/// Blocks until a child spawned on a PTY has exited.
+/// portable_pty only exposes a description of the terminating signal,
+/// so on unix the child is reaped directly to keep the signal number.
+pub fn wait_pty_child(mut child: Box<dyn portable_pty::Child + Send + Sync>) -> io::Result<ChildExit> {
+    #[cfg(unix)]
+    if let Some(pid) = child.process_id() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let mut status: libc::c_int = 0;
+        loop {
+            let res = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            return Ok(std::process::ExitStatus::from_raw(status).into());
+        }
+    }
+
+    let status = child.wait()?;
+    Ok(ChildExit::Code(status.exit_code()))
+}
+
+/// Streams everything read from `reader` to the channel, either as
+/// regular data or as extended data of type `ext`. Returns the number of bytes sent.
+pub async fn forward_output<R>(mut reader: R, handle: Handle, channel_id: ChannelId, ext: Option<u32>) -> u64
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0; 8192];
+    let mut sent = 0;
+    loop {
+        let n = match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                error!("Failed to read process output: {:?}", e);
+                break;
+            }
+        };
+
+        let data = CryptoVec::from_slice(&buffer[..n]);
+        let res = match ext {
+            Some(code) => handle.extended_data(channel_id, code, data).await,
+            None => handle.data(channel_id, data).await,
+        };
+        if res.is_err() {
+            error!("Error sending process output to client");
+            break;
+        }
+        sent += n as u64;
+    }
+    sent
+}
+
+/// Writes the chunks received from the channel into the process stdin, so a
+/// process that isn't reading never blocks the session handler. The stdin is
+/// closed once every sender has been dropped or the process stops reading.
+pub async fn write_exec_stdin<W>(mut writer: W, mut chunks: mpsc::UnboundedReceiver<Vec<u8>>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(chunk) = chunks.recv().await {
+        if let Err(e) = writer.write_all(&chunk).await {
+            error!("Failed to write to process stdin: {:?}", e);
+            break;
+        }
+    }
+}
+
+/// Sends the exit status (or exit signal) of a finished process and closes the channel
+pub async fn report_exit(handle: &Handle, channel_id: ChannelId, exit: ChildExit) {
+    match exit {
+        ChildExit::Code(code) => {
+            if code == 0 {
+                info!("Child process exited successfully.");
+            } else {
+                error!("Child process exited with status: {}", code);
+            }
+            let _ = handle.exit_status_request(channel_id, code).await;
+        }
+        ChildExit::Signal { signal, core_dumped } => {
+            error!("Child process terminated by signal: {:?}", signal);
+            let _ = handle
+                .exit_signal_request(
+                    channel_id,
+                    signal,
+                    core_dumped,
+                    String::new(),
+                    "en-US".to_string(),
+                )
+                .await;
+        }
+    }
+    let _ = handle.eof(channel_id).await;
+    let _ = handle.close(channel_id).await;
+}
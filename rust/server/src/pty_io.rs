@@ -0,0 +1,235 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use portable_pty::MasterPty;
+use tokio::io::unix::AsyncFd;
+
+/// Size of the buffer PTY output is read into. Output available at once is
+/// coalesced into reads of up to this size.
+pub const PTY_READ_BUFFER: usize = 32 * 1024;
+
+/// Non-blocking access to a PTY master, driven by the tokio reactor
+/// instead of a blocking read per chunk.
+pub struct AsyncPty {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl AsyncPty {
+    /// Duplicates the master's file descriptor and switches it to non-blocking mode.
+    /// The mode is shared with every other descriptor of the master, so its
+    /// blocking reader and writer must not be used anymore.
+    pub fn new(master: &dyn MasterPty) -> io::Result<Self> {
+        let raw = master
+            .as_raw_fd()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "PTY has no file descriptor"))?;
+
+        let fd = unsafe { libc::fcntl(raw, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(AsyncPty { fd: AsyncFd::new(fd)? })
+    }
+
+    /// Waits for output and reads as much of it as fits into `buf`.
+    /// Returns 0 once the terminal has hung up.
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| read_available(fd.get_ref(), buf)) {
+                Ok(result) => return result,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    /// Reads output that is available right now, without waiting.
+    /// Fails with `WouldBlock` if there is none.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        read_available(self.fd.get_ref(), buf)
+    }
+
+    pub async fn write_all(&self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            let written = match guard.try_io(|fd| {
+                let n = unsafe { libc::write(fd.as_raw_fd(), data.as_ptr().cast(), data.len()) };
+                if n < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(n as usize)
+                }
+            }) {
+                Ok(result) => result?,
+                Err(_would_block) => continue,
+            };
+            data = &data[written..];
+        }
+        Ok(())
+    }
+}
+
+/// Reads until `buf` is full or no more output is available, so bursts of output
+/// go out as one chunk instead of one per kernel read
+fn read_available(fd: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let n = unsafe { libc::read(fd.as_raw_fd(), rest.as_mut_ptr().cast(), rest.len()) };
+        if n > 0 {
+            filled += n as usize;
+            continue;
+        }
+        if n == 0 {
+            break;
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            // Linux reports a hung up terminal as EIO
+            Some(libc::EIO) => break,
+            _ if filled > 0 && err.kind() == io::ErrorKind::WouldBlock => break,
+            _ => return Err(err),
+        }
+    }
+
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use portable_pty::{native_pty_system, CommandBuilder, PtySize};
+    use tokio::net::UdpSocket;
+    use tokio::sync::Mutex;
+
+    const BENCH_BYTES: u64 = 64 * 1024 * 1024;
+
+    fn open_pty_running(program: &str, args: &[&str]) -> Box<dyn MasterPty + Send> {
+        let pair = native_pty_system()
+            .openpty(PtySize::default())
+            .unwrap();
+        let mut command = CommandBuilder::new(program);
+        command.args(args);
+        pair.slave.spawn_command(command).unwrap();
+        pair.master
+    }
+
+    #[tokio::test]
+    async fn test_reads_and_writes() {
+        let master = open_pty_running("cat", &[]);
+        let pty = AsyncPty::new(&*master).unwrap();
+
+        pty.write_all(b"hello\n").await.unwrap();
+
+        let mut output = Vec::new();
+        let mut buf = vec![0; PTY_READ_BUFFER];
+        // The echo of the input and the output of cat
+        while String::from_utf8_lossy(&output).matches("hello").count() < 2 {
+            let n = pty.read(&mut buf).await.unwrap();
+            assert!(n > 0);
+            output.extend_from_slice(&buf[..n]);
+        }
+
+        assert!(matches!(pty.try_read(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock));
+    }
+
+    /// Sends `BENCH_BYTES` of PTY output over a local QUIC `BiStream` and returns MiB/s.
+    /// `pump` moves the output from the PTY to the stream.
+    async fn quic_throughput<F, Fut>(pump: F) -> f64
+    where
+        F: FnOnce(Box<dyn MasterPty + Send>, quinn::SendStream) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let server = crate::server::make_server_endpoint(server_socket).unwrap();
+
+        let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(common::utils::quinn_utils::configure_client().unwrap());
+
+        let connecting = client.connect(server_addr, "localhost").unwrap();
+        let (server_conn, client_conn) = tokio::join!(
+            async { server.accept().await.unwrap().await.unwrap() },
+            async { connecting.await.unwrap() }
+        );
+
+        // Streams are announced to the peer with their first data
+        let (mut client_send, mut client_recv) = client_conn.open_bi().await.unwrap();
+        client_send.write_all(&[0]).await.unwrap();
+        let (server_send, mut server_recv) = server_conn.accept_bi().await.unwrap();
+        server_recv.read_exact(&mut [0]).await.unwrap();
+
+        let count = BENCH_BYTES.to_string();
+        let master = open_pty_running("head", &["-c", &count, "/dev/zero"]);
+
+        let started = Instant::now();
+        tokio::spawn(pump(master, server_send));
+
+        let mut received = 0;
+        let mut buf = vec![0; 64 * 1024];
+        while received < BENCH_BYTES {
+            match client_recv.read(&mut buf).await.unwrap() {
+                Some(n) => received += n as u64,
+                None => break,
+            }
+        }
+        assert_eq!(received, BENCH_BYTES);
+
+        received as f64 / (1024.0 * 1024.0) / started.elapsed().as_secs_f64()
+    }
+
+    /// Run with `cargo test -p sessio-server --release -- --ignored --nocapture bench_pty`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_pty_throughput_over_quic() {
+        // The previous design: a fresh 1 KiB buffer and a spawn_blocking call per read
+        let blocking = quic_throughput(|master, mut send| async move {
+            let reader = Arc::new(Mutex::new(master.try_clone_reader().unwrap()));
+            loop {
+                let reader = reader.clone();
+                let read = tokio::task::spawn_blocking(move || {
+                    let mut buffer = vec![0; 1024];
+                    reader.blocking_lock().read(&mut buffer).map(|n| (n, buffer))
+                })
+                .await
+                .unwrap();
+                match read {
+                    Ok((0, _)) | Err(_) => break,
+                    Ok((n, buffer)) => send.write_all(&buffer[..n]).await.unwrap(),
+                }
+            }
+            drop(master);
+        })
+        .await;
+
+        let non_blocking = quic_throughput(|master, mut send| async move {
+            let pty = AsyncPty::new(&*master).unwrap();
+            let mut buffer = vec![0; PTY_READ_BUFFER];
+            loop {
+                match pty.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => send.write_all(&buffer[..n]).await.unwrap(),
+                }
+            }
+            drop(master);
+        })
+        .await;
+
+        println!("spawn_blocking, 1 KiB reads: {:.1} MiB/s", blocking);
+        println!("AsyncFd, coalesced reads:   {:.1} MiB/s", non_blocking);
+        println!("Speedup: {:.2}x", non_blocking / blocking);
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context};
+use log::{error, info};
+use rand::Rng;
+use serde_json::json;
+
+use crate::config_manager::RecordingConfig;
+
+const CAST_EXTENSION: &str = "cast";
+
+impl RecordingConfig {
+    /// Whether PTY sessions of `login` authenticated with the key `fingerprint` are recorded
+    pub fn should_record(&self, login: &str, fingerprint: &str) -> bool {
+        self.users.iter().any(|user| user == "*" || user == login)
+            || self.keys.iter().any(|key| key == fingerprint)
+    }
+}
+
+/// Records a PTY session to an asciinema v2 `.cast` file. The events are
+/// written by a thread of the recording, so recording never blocks the session.
+pub struct SessionRecorder {
+    started: Instant,
+    events: mpsc::Sender<Event>,
+}
+
+/// An event of the session, with the seconds since the recording started
+enum Event {
+    Output(f64, Vec<u8>),
+    Input(f64, Vec<u8>),
+    Resize(f64, u32, u32),
+}
+
+impl SessionRecorder {
+    /// Starts a new recording in `dir` and writes the asciinema header
+    pub fn create(dir: &Path, login: &str, term: &str, width: u16, height: u16) -> io::Result<Self> {
+        let recording = Recording::create(dir, login, term, width, height)?;
+        let (events, received) = mpsc::channel();
+        thread::Builder::new()
+            .name("session-recorder".to_string())
+            .spawn(move || recording.write_all(received))?;
+
+        Ok(SessionRecorder {
+            started: Instant::now(),
+            events,
+        })
+    }
+
+    pub fn output(&self, data: &[u8]) {
+        self.send(Event::Output(self.elapsed(), data.to_vec()));
+    }
+
+    pub fn input(&self, data: &[u8]) {
+        self.send(Event::Input(self.elapsed(), data.to_vec()));
+    }
+
+    pub fn resize(&self, width: u32, height: u32) {
+        self.send(Event::Resize(self.elapsed(), width, height));
+    }
+
+    fn send(&self, event: Event) {
+        // The writer only stops once writing failed, which it reported
+        let _ = self.events.send(event);
+    }
+
+    fn elapsed(&self) -> f64 {
+        (self.started.elapsed().as_micros() as f64 / 1000.0).round() / 1000.0
+    }
+}
+
+/// The file of a recording, written to by the recording's thread
+struct Recording {
+    writer: LineWriter<File>,
+    /// Trailing bytes of an incomplete UTF-8 sequence, per event type
+    pending_output: Vec<u8>,
+    pending_input: Vec<u8>,
+}
+
+impl Recording {
+    fn create(dir: &Path, login: &str, term: &str, width: u16, height: u16) -> io::Result<Self> {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let id = format!("{}-{}-{:08x}", timestamp, login, rand::thread_rng().gen::<u32>());
+        let path = dir.join(&id).with_extension(CAST_EXTENSION);
+
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .mode(0o600)
+            .open(&path)?;
+        let mut writer = LineWriter::new(file);
+
+        let header = json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": timestamp,
+            "title": format!("Session of {}", login),
+            "env": { "TERM": term },
+        });
+        writeln!(writer, "{}", header)?;
+
+        info!("Recording session of {} to {:?}", login, path);
+        Ok(Recording {
+            writer,
+            pending_output: Vec::new(),
+            pending_input: Vec::new(),
+        })
+    }
+
+    /// Writes the events until the recorder is dropped or writing fails
+    fn write_all(mut self, events: mpsc::Receiver<Event>) {
+        for event in events {
+            if let Err(e) = self.write(event) {
+                error!("Failed to write session recording: {}", e);
+                break;
+            }
+        }
+    }
+
+    fn write(&mut self, event: Event) -> io::Result<()> {
+        let (elapsed, kind, text) = match event {
+            Event::Output(elapsed, data) => {
+                self.pending_output.extend_from_slice(&data);
+                (elapsed, "o", take_utf8(&mut self.pending_output))
+            }
+            Event::Input(elapsed, data) => {
+                self.pending_input.extend_from_slice(&data);
+                (elapsed, "i", take_utf8(&mut self.pending_input))
+            }
+            Event::Resize(elapsed, width, height) => {
+                (elapsed, "r", format!("{}x{}", width, height))
+            }
+        };
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        writeln!(self.writer, "{}", json!([elapsed, kind, text]))
+    }
+}
+
+/// Decodes the complete UTF-8 prefix of `buffer` and leaves an incomplete
+/// trailing sequence in it. Invalid bytes become U+FFFD.
+fn take_utf8(buffer: &mut Vec<u8>) -> String {
+    let mut text = String::new();
+    let mut rest = buffer.as_slice();
+
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                text.push_str(valid);
+                rest = &[];
+                break;
+            }
+            Err(e) => {
+                let (valid, after) = rest.split_at(e.valid_up_to());
+                text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                match e.error_len() {
+                    Some(len) => {
+                        text.push(char::REPLACEMENT_CHARACTER);
+                        rest = &after[len..];
+                    }
+                    // The sequence continues in the next chunk
+                    None => {
+                        rest = after;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let remaining = rest.to_vec();
+    *buffer = remaining;
+    text
+}
+
+/// A recording stored on disk
+pub struct RecordingInfo {
+    pub id: String,
+    pub user: String,
+    /// Unix timestamp of when the session started
+    pub started_at: u64,
+    pub size: u64,
+}
+
+pub fn list_recordings(dir: &Path) -> io::Result<Vec<RecordingInfo>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut recordings = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(CAST_EXTENSION) {
+            continue;
+        }
+        let Some(id) = path.file_stem().and_then(|s| s.to_str()) else {
+            continue;
+        };
+
+        // IDs are `<timestamp>-<login>-<random>`
+        let mut parts = id.splitn(2, '-');
+        let started_at = parts.next().and_then(|t| t.parse().ok()).unwrap_or(0);
+        let user = parts
+            .next()
+            .and_then(|rest| rest.rsplit_once('-'))
+            .map(|(user, _)| user.to_string())
+            .unwrap_or_default();
+
+        recordings.push(RecordingInfo {
+            id: id.to_string(),
+            user,
+            started_at,
+            size: entry.metadata()?.len(),
+        });
+    }
+
+    recordings.sort_by(|a, b| a.started_at.cmp(&b.started_at).then(a.id.cmp(&b.id)));
+    Ok(recordings)
+}
+
+/// Path of the recording with the given ID
+pub fn recording_path(dir: &Path, id: &str) -> anyhow::Result<PathBuf> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+        bail!("Invalid recording ID {}", id);
+    }
+
+    let path = dir.join(id).with_extension(CAST_EXTENSION);
+    if !path.is_file() {
+        bail!("Recording {} not found", id);
+    }
+    Ok(path)
+}
+
+/// Copies a recording to `output`, or to stdout if there is none
+pub fn export_recording(dir: &Path, id: &str, output: Option<&Path>) -> anyhow::Result<()> {
+    let path = recording_path(dir, id)?;
+    let mut recording = File::open(&path).with_context(|| format!("Failed to open {:?}", path))?;
+
+    match output {
+        Some(output) => {
+            let mut file = File::create(output).with_context(|| format!("Failed to create {:?}", output))?;
+            io::copy(&mut recording, &mut file)?;
+        }
+        None => {
+            io::copy(&mut recording, &mut io::stdout().lock())?;
+        }
+    }
+    Ok(())
+}
+
+/// Deletes recordings last written more than `retention_days` ago.
+/// Returns how many were deleted.
+pub fn prune_recordings(dir: &Path, retention_days: u64) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+
+    let max_age = Duration::from_secs(retention_days * 24 * 60 * 60);
+    let mut deleted = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(CAST_EXTENSION) {
+            continue;
+        }
+
+        let age = fs::metadata(&path)?
+            .modified()?
+            .elapsed()
+            .unwrap_or_default();
+        if age > max_age {
+            fs::remove_file(&path)?;
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_take_utf8_keeps_incomplete_sequences() {
+        // "é" is 0xC3 0xA9
+        let mut buffer = vec![b'a', 0xC3];
+        assert_eq!(take_utf8(&mut buffer), "a");
+        assert_eq!(buffer, vec![0xC3]);
+
+        buffer.push(0xA9);
+        assert_eq!(take_utf8(&mut buffer), "é");
+        assert!(buffer.is_empty());
+
+        let mut invalid = vec![b'x', 0xFF, b'y'];
+        assert_eq!(take_utf8(&mut invalid), "x\u{FFFD}y");
+    }
+
+    #[test]
+    fn test_should_record() {
+        let config = RecordingConfig {
+            path: PathBuf::new(),
+            users: vec!["alice".to_string()],
+            keys: vec!["SHA256:abc".to_string()],
+            retention_days: 30,
+        };
+
+        assert!(config.should_record("alice", "SHA256:xyz"));
+        assert!(config.should_record("bob", "SHA256:abc"));
+        assert!(!config.should_record("bob", "SHA256:xyz"));
+
+        let everyone = RecordingConfig {
+            users: vec!["*".to_string()],
+            keys: Vec::new(),
+            ..config
+        };
+        assert!(everyone.should_record("bob", "SHA256:xyz"));
+    }
+
+    #[test]
+    fn test_recording_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+
+        // The events go through the channel, but are written on this thread
+        let recording = Recording::create(dir, "alice", "xterm", 80, 24).unwrap();
+        let (events, received) = mpsc::channel();
+        let recorder = SessionRecorder {
+            started: Instant::now(),
+            events,
+        };
+        recorder.output(b"hello\r\n");
+        recorder.input(b"ls\r");
+        recorder.resize(100, 30);
+        drop(recorder);
+        recording.write_all(received);
+
+        let recordings = list_recordings(dir).unwrap();
+        assert_eq!(recordings.len(), 1);
+        assert_eq!(recordings[0].user, "alice");
+
+        let contents = fs::read_to_string(recording_path(dir, &recordings[0].id).unwrap()).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "hello\r\n");
+        assert_eq!(lines[2][1], "i");
+        assert_eq!(lines[3][1], "r");
+        assert_eq!(lines[3][2], "100x30");
+
+        assert!(recording_path(dir, "../etc/passwd").is_err());
+        assert_eq!(prune_recordings(dir, 1).unwrap(), 0);
+    }
+}
@@ -1,11 +1,10 @@
 use std::collections::HashMap;
 use std::f32::consts::E;
-use std::ffi::OsString;
 use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use common::utils::map_ipv4_to_ipv6;
 use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
@@ -19,7 +18,7 @@ use tokio::process::Command as TokioCommand;
 use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 
 use dirs;
-use tokio::net::{TcpStream, UdpSocket};
+use tokio::net::{TcpStream, UdpSocket, UnixStream};
 use tokio::sync::broadcast::Receiver;
 
 use clap::Parser;
@@ -27,23 +26,24 @@ use log::{debug, error, info, warn};
 use quinn::{crypto, Connection, Endpoint, EndpointConfig, ServerConfig, VarInt};
 use rand::rngs::OsRng;
 use rand::{seq, CryptoRng};
-use russh::server::{Msg, Server as _, Session};
+use russh::server::{Handle, Msg, Server as _, Session};
 use russh::*;
 use russh::MethodKind;
 use russh::keys::{load_secret_key, PublicKeyBase64};
 use russh::keys::ssh_key::{Algorithm, HashAlg, LineEnding, PrivateKey, PublicKey};
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener};
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
 use std::str;
 use tokio::fs::read_to_string;
-use tokio::sync::{mpsc, mpsc::Sender, Mutex};
+use tokio::sync::{mpsc, mpsc::Sender, oneshot, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::AbortHandle;
 use tokio::{select, time};
 use toml::ser;
 
 use anyhow::{bail, Context, Error};
-use portable_pty::{
-    native_pty_system, CommandBuilder, MasterPty, PtyPair, PtySize, PtySystem, SlavePty,
-};
+use portable_pty::{native_pty_system, MasterPty, PtyPair, PtySize, PtySystem, SlavePty};
 use russh::Channel;
 use serde::Deserialize;
 use std::pin::Pin;
@@ -51,12 +51,38 @@ use std::task::Poll;
 use std::time::{Duration, Instant};
 
 use crate::{sftp::*, Opt};
-use crate::config_manager::ServerConfigManager;
-use common::utils::keygen::generate_keypair;
+use crate::process::{
+    apply_terminal_modes, build_command, forward_output, report_exit, run_as, send_signal,
+    signum_from_sig, spawn_on_pty, wait_pty_child, write_exec_stdin, ChildExit, SessionProgram,
+    EXTENDED_DATA_STDERR,
+};
+use crate::user::{resolve_login_user, single_user, UserInfo};
+use crate::audit::{AuditEvent, AuditLog, ChannelStats, SessionAudit};
+use crate::config_manager::{RecordingConfig, ServerConfigManager, SshConfig, TcpFallbackConfig};
+use crate::pty_io::{AsyncPty, PTY_READ_BUFFER};
+use crate::recording::{prune_recordings, SessionRecorder};
+use crate::policy::{accept_env_allows, permit_open_allows};
+use crate::forward::{AgentForward, RemoteForwards, StreamLocal, STREAMLOCAL_CONNECTED};
+use crate::persistent::{PersistentPty, PersistentSessions, PersistentSessionsSubsystem};
+use crate::subsystem::{serve_subsystem, SubsystemHandler, Subsystems};
+use crate::bans::{failure_offenders, offenders, AuthFailures, Offender};
+use crate::key_store::AuthorizedKeyStore;
+use crate::lifecycle::{
+    monotonic_usec, sd_notify, ActiveSessions, ReloadedConfig, ServerCommand, SHUTDOWN_CODE,
+};
+use crate::login_messages::{load_banner, load_motd, welcome_message, LastLogin, LastLogins};
+use crate::tcp_fallback::{accept_fallback, TcpFallback};
+use common::utils::persistent_sessions::{PERSISTENT_SESSIONS_SUBSYSTEM, PERSISTENT_SESSION_ENV};
+use common::utils::authorized_keys::{AuthorizedKey, KeyOptions};
+use common::utils::keygen::{authorized_keys_path, generate_keypair};
 use sessio_coordinator_common::coordinator_client::*;
 use url::Url;
 use common::utils::quinn_utils::configure_client;
 use common::utils::streams::BiStream;
+use common::utils::tcp_fallback::{SshStream, TcpEndpoint};
+
+/// QUIC application error code for connections and streams refused by policy
+const POLICY_REJECTION_CODE: u32 = 0x10;
 
 /// Returns default server configuration along with its certificate.
 fn configure_server() -> anyhow::Result<ServerConfig> {
@@ -122,7 +148,15 @@ struct PeerChangeMsg {
     pub old_ip: SocketAddr,
 }
 
-async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchService, host_key: russh::keys::ssh_key::PrivateKey) {
+async fn listen_to_coordinator(
+    endpoint: Endpoint,
+    mut holepuncher: HolepunchService,
+    host_key: russh::keys::ssh_key::PrivateKey,
+    audit: Arc<AuditLog>,
+    auth_failures: Arc<AuthFailures>,
+    authorized_keys: Arc<AuthorizedKeyStore>,
+    authorized_keys_path: PathBuf,
+) {
     let mut receiver: Receiver<Packet> = holepuncher.c_client.subscribe_to_packets().await;
     let mut sender = holepuncher.c_client.new_packet_sender();
 
@@ -162,6 +196,24 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                     match packet {
                         Packet::ConnectTo(data) => {
                             log::info!("connect to received");
+
+                            // `signed` once the sender's signature was verified, only then
+                            // is a rejection counted against its key
+                            let record_decision = |fingerprint: Option<String>, reason: Option<&str>, signed: bool| {
+                                if reason.is_some() {
+                                    auth_failures.record_failure(
+                                        &failure_offenders(Some(data.target), fingerprint.clone(), signed),
+                                        Some(data.target),
+                                    );
+                                }
+                                audit.record(None, None, &AuditEvent::ConnectTo {
+                                    session_id: data.session_id.clone(),
+                                    target: data.target.to_string(),
+                                    fingerprint,
+                                    accepted: reason.is_none(),
+                                    reason: reason.map(str::to_string),
+                                });
+                            };
                             
                             // Verify cryptographic signature
                             if !data.target_public_key.is_empty() && !data.signed_data.is_empty() && !data.signature.is_empty() {
@@ -173,9 +225,23 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                                     Ok(key) => key,
                                     Err(e) => {
                                         error!("Failed to parse sender public key: {}", e);
+                                        record_decision(None, Some("Invalid public key"), false);
                                         continue;
                                     }
                                 };
+                                let fingerprint = Some(sender_public_key.fingerprint(HashAlg::Sha256).to_string());
+
+                                if auth_failures.any_banned(&offenders(Some(data.target), fingerprint.clone())) {
+                                    warn!("Connection denied: {} or its key is banned", data.target);
+                                    audit.record(None, None, &AuditEvent::ConnectTo {
+                                        session_id: data.session_id.clone(),
+                                        target: data.target.to_string(),
+                                        fingerprint,
+                                        accepted: false,
+                                        reason: Some("Banned".to_string()),
+                                    });
+                                    continue;
+                                }
                                 
                                 // Decode the signature
                                 use base64::{Engine, engine::general_purpose};
@@ -183,6 +249,7 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                                     Ok(bytes) => bytes,
                                     Err(e) => {
                                         error!("Failed to decode signature: {}", e);
+                                        record_decision(fingerprint, Some("Invalid signature encoding"), false);
                                         continue;
                                     }
                                 };
@@ -193,6 +260,7 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                                     Ok(sig) => sig,
                                     Err(e) => {
                                         error!("Failed to parse signature: {}", e);
+                                        record_decision(fingerprint, Some("Invalid signature"), false);
                                         continue;
                                     }
                                 };
@@ -204,6 +272,7 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                                 let expected_challenge = format!("CONNECTION:{}", our_public_key);
                                 if data.signed_data != expected_challenge {
                                     error!("Invalid challenge format. Expected: {}, Got: {}", expected_challenge, data.signed_data);
+                                    record_decision(fingerprint, Some("Invalid challenge"), false);
                                     continue;
                                 }
                                 
@@ -212,18 +281,19 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
                                 
                                 if !signature_valid {
                                     error!("Signature verification failed - connection denied");
+                                    record_decision(fingerprint, Some("Signature verification failed"), false);
                                     continue;
                                 }
 
                                 // Then we check that the public key is authorized
 
-                                let authorized_keys = common::utils::keygen::read_authorized_keys(None).await.unwrap_or_else(|e| {
-                                    error!("Failed to read authorized_keys: {}", e);
-                                    Vec::new()
+                                let keys = authorized_keys.keys(&authorized_keys_path).await.unwrap_or_else(|e| {
+                                    error!("Failed to read authorized_keys: {:#}", e);
+                                    Default::default()
                                 });
 
                                 let is_authorized = match parse_public_key_base64(&data.target_public_key) {
-                                    Ok(target_key) => authorized_keys.contains(&target_key),
+                                    Ok(target_key) => keys.iter().any(|entry| entry.key == target_key),
                                     Err(e) => {
                                         error!("Failed to parse target public key: {}", e);
                                         false
@@ -232,13 +302,16 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
 
                                 if !is_authorized {
                                     warn!("Connection denied: target public key {} not found in authorized_keys", data.target_public_key);
+                                    record_decision(fingerprint, Some("Key not authorized"), true);
                                     continue;
                                 }
 
                                 info!("Cryptographic signature verified successfully");
+                                record_decision(fingerprint, None, true);
                             }
                             else {
                                 error!("Connection denied: Signature missing");
+                                record_decision(None, Some("Signature missing"), false);
                                 continue;
                             }
 
@@ -313,7 +386,10 @@ async fn listen_to_coordinator(endpoint: Endpoint, mut holepuncher: HolepunchSer
     });
 }
 
-pub async fn run() {
+/// Runs the server. With `direct_listen` the server listens on that address
+/// and runs without a coordinator, clients connect to it by address and only
+/// the local authorized_keys files are used.
+pub async fn run(direct_listen: Option<SocketAddr>) {
     let mut builder = env_logger::Builder::from_default_env();
     if cfg!(debug_assertions) {
         // Debug mode
@@ -332,22 +408,129 @@ pub async fn run() {
         .expect("Failed to load server settings");
     
     // Check if server is registered
-    if !config_manager.is_registered().await.unwrap_or(false) {
-        eprintln!("Server is not registered. Please run 'sessio-server install' first.");
+    if direct_listen.is_none() && !config_manager.is_registered().await.unwrap_or(false) {
+        eprintln!("Server is not registered. Please run 'sessio-server install' first, or use 'sessio-server run --direct'.");
         std::process::exit(1);
     }
     
+    // Load host key from settings
+    let host_key = load_host_key(dirs::home_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join(".sessio").join(settings.private_key_path)).unwrap();
+    
+    // Get SSH configuration from settings
+    let ssh_config = config_manager.get_ssh_config().await
+        .expect("Failed to get SSH configuration");
+    
+    let audit_config = config_manager.get_audit_config().await
+        .expect("Failed to get audit log configuration");
+    let audit_log = Arc::new(AuditLog::open(audit_config).expect("Failed to open audit log"));
+
+    let recording_config = Arc::new(config_manager.get_recording_config().await
+        .expect("Failed to get session recording configuration"));
+    let retention = start_recording_retention_task(recording_config.clone());
+
+    let last_logins = Arc::new(LastLogins::new(config_manager.last_login_path()));
+
+    let config = russh_config(&ssh_config, &host_key);
+
+    let home_dir = homedir::my_home()
+        .expect("Failed to get home directory")
+        .expect("Home directory not found");
+    let authorized_keys = AuthorizedKeyStore::new();
+    let auth_failures = Arc::new(AuthFailures::new(&ssh_config, audit_log.clone()));
+
+    let tcp_fallback_config = config_manager.get_tcp_fallback_config().await
+        .expect("Failed to get TCP listener configuration");
+    let tcp_fallback = match &tcp_fallback_config {
+        Some(config) => Some(TcpFallback::bind(config).await.expect("Failed to start TCP listener")),
+        None => None,
+    };
+    let tcp_endpoint = tcp_fallback_config.and_then(|config| {
+        if config.advertise.is_none() && direct_listen.is_none() {
+            info!("Not advertising the TCP listener on an unspecified address, set tcp_advertise to advertise it");
+        }
+        config.advertise
+    });
+
+    let endpoint_v6 = match direct_listen {
+        Some(listen) => {
+            let socket = UdpSocket::bind(listen)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", listen, e));
+            info!("Running in direct mode on {}, without a coordinator", listen);
+            info!(
+                "Clients pin this server with the host key fingerprint {}",
+                host_key.public_key().fingerprint(HashAlg::Sha256)
+            );
+            make_server_endpoint(socket).unwrap()
+        }
+        None => {
+            start_coordinator(
+                &mut config_manager,
+                &host_key,
+                &home_dir,
+                audit_log.clone(),
+                auth_failures.clone(),
+                authorized_keys.clone(),
+                tcp_endpoint,
+            )
+            .await
+        }
+    };
+
+    let config = Arc::new(config);
+
+    let (commands_tx, commands_rx) = mpsc::channel(4);
+    tokio::spawn(handle_signals(
+        config_manager,
+        host_key.clone(),
+        authorized_keys.clone(),
+        commands_tx,
+    ));
+
+    let config_v6 = config.clone();
+    let v6_handle = tokio::spawn(async move {
+        let persistent = Arc::new(PersistentSessions::default());
+        persistent.set_detached_timeout(ssh_config.persistent_session_timeout);
+        start_persistent_expiry_task(persistent.clone());
+        let subsystems = server_subsystems(&ssh_config, &persistent);
+
+        let mut sh = Server {
+            persistent,
+            ssh_config: Arc::new(ssh_config),
+            audit: audit_log,
+            recording: recording_config,
+            retention: Some(retention),
+            subsystems: Arc::new(subsystems),
+            last_logins,
+            auth_failures,
+            authorized_keys,
+            sessions: Arc::new(ActiveSessions::default()),
+        };
+        sh.run_quic(config_v6, &endpoint_v6, tcp_fallback, commands_rx).await.unwrap();
+    });
+    let v6 = tokio::join!(v6_handle);
+}
+
+/// Connects to the coordinator and starts its tasks: hole punching, the
+/// authorized keys sync and the heartbeat, which advertises `tcp_endpoint`.
+/// Returns the endpoint clients reach through hole punching.
+async fn start_coordinator(
+    config_manager: &mut ServerConfigManager,
+    host_key: &PrivateKey,
+    home_dir: &Path,
+    audit_log: Arc<AuditLog>,
+    auth_failures: Arc<AuthFailures>,
+    authorized_keys: Arc<AuthorizedKeyStore>,
+    tcp_endpoint: Option<TcpEndpoint>,
+) -> Endpoint {
     let (jwt_token, device_id) = config_manager.get_account_info().await
         .expect("Failed to get account information");
     
     info!("Using JWT token for authentication");
     info!("Using device ID: {}", device_id);
     
-    // Load host key from settings
-    let host_key = load_host_key(dirs::home_dir()
-        .unwrap_or_else(|| PathBuf::from("."))
-        .join(".sessio").join(settings.private_key_path)).unwrap();
-    
     // Get coordinator URL from settings
     let coordinator_url = config_manager.get_coordinator_url().await
         .expect("Invalid coordinator URL in settings");
@@ -357,18 +540,6 @@ pub async fn run() {
         panic!("HTTP coordinator connections are not allowed. Enable 'dangerously_use_http_coordinator' setting in config file or use HTTPS.");
     }
 
-    // Get SSH configuration from settings
-    let ssh_config = config_manager.get_ssh_config().await
-        .expect("Failed to get SSH configuration");
-    
-    let config = server::Config {
-        inactivity_timeout: Some(Duration::from_secs(ssh_config.inactivity_timeout)),
-        auth_rejection_time: Duration::from_secs(ssh_config.auth_rejection_time),
-        auth_rejection_time_initial: Some(Duration::from_secs(0)),
-        keys: vec![host_key.clone()],
-        ..Default::default()
-    };
-
     let sock_v6 = UdpSocket::bind::<SocketAddr>("[::]:0".parse().unwrap())
         .await
         .unwrap();
@@ -380,11 +551,11 @@ pub async fn run() {
     info!("Server discovered external IPs - IPv4: {:?}, IPv6: {:?}", external_ipv4, external_ipv6);
     
     // Use the IPv6 socket for the endpoint (dual-stack)
-    let mut endpoint_v6 = make_server_endpoint(sock_v6).unwrap();
+    let endpoint_v6 = make_server_endpoint(sock_v6).unwrap();
 
     // CoordinatorClient::configure_crypto removed for WebSocket-only implementation
     let holepuncher =
-        HolepunchService::new(coordinator_url.clone(), jwt_token.clone(), external_ipv4, external_ipv6, device_id.clone(), endpoint_v6.clone())
+        HolepunchService::new(coordinator_url.clone(), jwt_token.clone(), external_ipv4, external_ipv6, device_id.clone(), endpoint_v6.clone(), tcp_endpoint.map(|endpoint| endpoint.to_string()))
             .await
             .unwrap();
     
@@ -397,18 +568,17 @@ pub async fn run() {
         .expect("Failed to load account data");
     let passkey_json = account_data.passkey_public_key; // Now contains full JSON Passkey instead of base64 CBOR
     
+    let synced_keys_path = home_dir.join(".sessio/authorized_keys");
 
-    let home_dir = homedir::my_home()
-        .expect("Failed to get home directory")
-        .expect("Home directory not found");
-    let authorized_keys_path = home_dir.join(".sessio/authorized_keys");
-    
+    let store = authorized_keys.clone();
+    let invalidated_path = synced_keys_path.clone();
     holepuncher.c_client.start_authorized_keys_sync_task(
         jwt_token.clone(),
         passkey_json,
         sync_interval,
-        authorized_keys_path,
+        synced_keys_path,
         false, // Only include verified keys
+        move || store.invalidate(&invalidated_path),
     ).await;
 
     // Start heartbeat task
@@ -417,16 +587,184 @@ pub async fn run() {
         jwt_token.clone(),
     ).await;
     
-    listen_to_coordinator(endpoint_v6.clone(), holepuncher, host_key.clone()).await;
+    listen_to_coordinator(
+        endpoint_v6.clone(),
+        holepuncher,
+        host_key.clone(),
+        audit_log,
+        auth_failures,
+        authorized_keys,
+        authorized_keys_path(home_dir, single_user()),
+    )
+    .await;
+
+    endpoint_v6
+}
 
-    let config = Arc::new(config);
+fn russh_config(ssh_config: &SshConfig, host_key: &PrivateKey) -> server::Config {
+    server::Config {
+        inactivity_timeout: Some(Duration::from_secs(ssh_config.inactivity_timeout)),
+        auth_rejection_time: Duration::from_secs(ssh_config.auth_rejection_time),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        keys: vec![host_key.clone()],
+        ..Default::default()
+    }
+}
 
-    let config_v6 = config.clone();
-    let v6_handle = tokio::spawn(async move {
-        let mut sh = Server {};
-        sh.run_quic(config_v6, &endpoint_v6).await.unwrap();
+/// The built-in subsystems and the ones configured in the settings
+fn server_subsystems(ssh_config: &SshConfig, persistent: &Arc<PersistentSessions>) -> Subsystems {
+    let mut subsystems = Subsystems::new(ssh_config.subsystems.clone());
+    subsystems.register("sftp", SftpSubsystem::new(ssh_config.sftp.clone()));
+    subsystems.register(
+        PERSISTENT_SESSIONS_SUBSYSTEM,
+        PersistentSessionsSubsystem::new(persistent.clone()),
+    );
+    subsystems
+}
+
+/// Reloads the settings on SIGHUP, and shuts the server down on SIGTERM or SIGINT
+async fn handle_signals(
+    mut config_manager: ServerConfigManager,
+    host_key: PrivateKey,
+    authorized_keys: Arc<AuthorizedKeyStore>,
+    commands: mpsc::Sender<ServerCommand>,
+) {
+    let (mut hangup, mut terminate) = match (
+        signal(SignalKind::hangup()),
+        signal(SignalKind::terminate()),
+    ) {
+        (Ok(hangup), Ok(terminate)) => (hangup, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to listen for signals: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        select! {
+            _ = hangup.recv() => {
+                info!("Reloading settings");
+                sd_notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", monotonic_usec()));
+                authorized_keys.invalidate_all();
+                match reload_config(&mut config_manager, &host_key).await {
+                    Ok(config) => {
+                        // The server reports readiness once the settings are in use
+                        let _ = commands.send(ServerCommand::Reload(Box::new(config))).await;
+                    }
+                    Err(e) => {
+                        error!("Failed to reload settings, keeping the current ones: {:#}", e);
+                        sd_notify("READY=1\nSTATUS=Accepting connections, reloading settings failed");
+                    }
+                }
+            }
+            _ = terminate.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+    let _ = commands.send(ServerCommand::Shutdown).await;
+}
+
+/// Reads the settings again. The host key stays the same, as the coordinator
+/// and clients trust it.
+async fn reload_config(
+    config_manager: &mut ServerConfigManager,
+    host_key: &PrivateKey,
+) -> anyhow::Result<ReloadedConfig> {
+    config_manager.invalidate_cache();
+    let ssh = config_manager.get_ssh_config().await?;
+    let recording = Arc::new(config_manager.get_recording_config().await?);
+    Ok(ReloadedConfig {
+        russh: Arc::new(russh_config(&ssh, host_key)),
+        ssh,
+        recording,
+        audit: config_manager.get_audit_config().await?,
+        tcp_fallback: config_manager.get_tcp_fallback_config().await?,
+    })
+}
+
+/// Deletes expired session recordings at startup and once a day
+fn start_recording_retention_task(config: Arc<RecordingConfig>) -> AbortHandle {
+    let task = tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            match prune_recordings(&config.path, config.retention_days) {
+                Ok(0) => {}
+                Ok(deleted) => info!("Deleted {} expired session recordings", deleted),
+                Err(e) => error!("Failed to prune session recordings: {}", e),
+            }
+        }
     });
-    let v6 = tokio::join!(v6_handle);
+    task.abort_handle()
+}
+
+/// Ends the persistent sessions that stayed detached for longer than the
+/// persistent session timeout, checking once a minute
+fn start_persistent_expiry_task(persistent: Arc<PersistentSessions>) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            for session in persistent.remove_detached().await {
+                info!(
+                    "Ending persistent session {} of {}, it was detached for too long",
+                    session.id, session.owner
+                );
+                session.pty.hangup().await;
+            }
+        }
+    });
+}
+
+/// Detaches the persistent sessions still attached to channels of an ended
+/// SSH session, so they expire like any other detached session
+async fn detach_all(attached: &Mutex<HashMap<ChannelId, (Arc<PersistentPty>, u64)>>) {
+    let attached: Vec<_> = attached.lock().await.drain().map(|(_, entry)| entry).collect();
+    for (persistent, attachment) in attached {
+        persistent.detach(attachment).await;
+    }
+}
+
+/// Grows or shrinks the number of concurrent connections `limit` allows from
+/// `current` to `reloaded`. Permits held by open connections are taken away
+/// once those connections end.
+fn resize_connection_limit(limit: &Arc<Semaphore>, current: usize, reloaded: usize) {
+    if reloaded >= current {
+        limit.add_permits(reloaded - current);
+        return;
+    }
+
+    let excess = current - reloaded;
+    let forgotten = limit.forget_permits(excess);
+    if forgotten < excess {
+        let limit = limit.clone();
+        tokio::spawn(async move {
+            if let Ok(permits) = limit.acquire_many_owned((excess - forgotten) as u32).await {
+                permits.forget();
+            }
+        });
+    }
+}
+
+/// Replaces the TCP listener after its settings changed. A listener on the
+/// same address is closed before the new one binds it.
+async fn rebind_fallback(fallback: &mut Option<TcpFallback>, reloaded: Option<&TcpFallbackConfig>) {
+    let Some(config) = reloaded else {
+        *fallback = None;
+        info!("TCP listener stopped");
+        return;
+    };
+
+    if fallback
+        .as_ref()
+        .is_some_and(|fallback| fallback.config().listen == config.listen)
+    {
+        *fallback = None;
+    }
+    match TcpFallback::bind(config).await {
+        Ok(listener) => *fallback = Some(listener),
+        Err(e) => error!("Failed to start the reloaded TCP listener: {:#}", e),
+    }
 }
 
 fn load_host_key<P: AsRef<Path>>(path: P) -> Result<russh::keys::ssh_key::PrivateKey, Box<dyn std::error::Error>> {
@@ -447,17 +785,169 @@ fn load_host_key<P: AsRef<Path>>(path: P) -> Result<russh::keys::ssh_key::Privat
 struct ServerSession {
     clients: Arc<Mutex<HashMap<ChannelId, Channel<Msg>>>>,
     ptys: Arc<Mutex<HashMap<ChannelId, Arc<PtyStream>>>>,
+    exec_stdins: Arc<Mutex<HashMap<ChannelId, mpsc::UnboundedSender<Vec<u8>>>>>,
+    /// Process group of each command started by exec without a PTY
+    exec_pgids: Arc<Mutex<HashMap<ChannelId, i32>>>,
     id: Arc<AtomicUsize>,
     user: Option<String>,
+    /// The local account the authenticated user runs as
+    login: Option<Arc<UserInfo>>,
+    remote_forwards: Arc<RemoteForwards>,
+    /// Persistent PTY sessions of the whole server
+    persistent: Arc<PersistentSessions>,
+    /// Persistent session IDs requested through the environment, per channel
+    persistent_ids: Arc<Mutex<HashMap<ChannelId, String>>>,
+    /// Accepted environment variables for the shell or command, per channel
+    channel_env: Arc<Mutex<HashMap<ChannelId, Vec<(String, String)>>>>,
+    /// Agent socket of the session, once the client asked for agent forwarding
+    agent: Arc<Mutex<Option<AgentForward>>>,
+    /// Persistent sessions attached to this session's channels, with the attachment numbers
+    attached: Arc<Mutex<HashMap<ChannelId, (Arc<PersistentPty>, u64)>>>,
+    ssh_config: Arc<SshConfig>,
+    /// Address of the connecting client
+    remote_addr: Option<SocketAddr>,
+    /// Options of the authorized key the user logged in with
+    key_options: Arc<KeyOptions>,
+    /// Fingerprint of the key the user logged in with
+    fingerprint: String,
+    audit: Arc<SessionAudit>,
+    /// Traffic counters of the open channels whose data passes through the handler
+    channel_stats: Arc<Mutex<HashMap<ChannelId, Arc<ChannelStats>>>>,
+    recording: Arc<RecordingConfig>,
+    /// Whether PTY sessions of the logged in user and key are recorded
+    record_session: bool,
+    subsystems: Arc<Subsystems>,
+    last_logins: Arc<LastLogins>,
+    auth_failures: Arc<AuthFailures>,
+    authorized_keys: Arc<AuthorizedKeyStore>,
+    /// The user's login before this one, shown when a shell starts
+    previous_login: Option<LastLogin>,
+}
+
+#[derive(Clone, Default)]
+struct Server {
+    persistent: Arc<PersistentSessions>,
+    ssh_config: Arc<SshConfig>,
+    audit: Arc<AuditLog>,
+    recording: Arc<RecordingConfig>,
+    /// The task deleting expired recordings
+    retention: Option<AbortHandle>,
+    subsystems: Arc<Subsystems>,
+    last_logins: Arc<LastLogins>,
+    auth_failures: Arc<AuthFailures>,
+    authorized_keys: Arc<AuthorizedKeyStore>,
+    sessions: Arc<ActiveSessions>,
 }
 
-struct Server {}
+impl Server {
+    /// Uses reloaded settings for sessions started from now on, resizes the
+    /// connection limit and rebinds the TCP listener if its settings changed
+    async fn apply_reload(
+        &mut self,
+        reloaded: ReloadedConfig,
+        connection_limit: &Arc<Semaphore>,
+        fallback: &mut Option<TcpFallback>,
+    ) -> Arc<server::Config> {
+        resize_connection_limit(
+            connection_limit,
+            self.ssh_config.max_concurrent_connections as usize,
+            reloaded.ssh.max_concurrent_connections as usize,
+        );
+        self.auth_failures.reconfigure(&reloaded.ssh);
+        self.persistent
+            .set_detached_timeout(reloaded.ssh.persistent_session_timeout);
+        if let Err(e) = self.audit.reconfigure(reloaded.audit) {
+            error!("Failed to open the reloaded audit log, keeping the current one: {}", e);
+        }
+
+        if let Some(retention) = self.retention.take() {
+            retention.abort();
+        }
+        self.retention = Some(start_recording_retention_task(reloaded.recording.clone()));
+
+        self.ssh_config = Arc::new(reloaded.ssh);
+        self.recording = reloaded.recording;
+        self.subsystems = Arc::new(server_subsystems(&self.ssh_config, &self.persistent));
+
+        let current = fallback.as_ref().map(|fallback| fallback.config());
+        if current != reloaded.tcp_fallback.as_ref() {
+            let advertise = |config: Option<&TcpFallbackConfig>| {
+                config.and_then(|config| config.advertise.clone())
+            };
+            if advertise(current) != advertise(reloaded.tcp_fallback.as_ref()) {
+                warn!("The TCP endpoint is advertised to the coordinator at startup, restart the server to change it");
+            }
+            rebind_fallback(fallback, reloaded.tcp_fallback.as_ref()).await;
+        }
+
+        info!("Settings reloaded, active sessions keep their previous settings");
+        info!("Changes to the coordinator settings and the authorized keys sync interval take effect after a restart");
+        reloaded.russh
+    }
+
+    /// Disconnects every session, waits up to the shutdown timeout for them
+    /// to end and closes the endpoint
+    async fn shutdown(&self, endpoint: &Endpoint) {
+        sd_notify("STOPPING=1\nSTATUS=Shutting down");
+        info!("Shutting down, disconnecting {} sessions", self.sessions.len());
+        self.sessions.disconnect_all("Server shutting down").await;
+
+        let timeout = Duration::from_secs(self.ssh_config.shutdown_timeout);
+        if !self.sessions.wait_idle(timeout).await {
+            warn!(
+                "{} sessions did not end within {}s",
+                self.sessions.len(),
+                timeout.as_secs()
+            );
+        }
 
-struct PtyStream {
-    reader: Mutex<Box<dyn Read + Send>>,
-    writer: Mutex<Box<dyn Write + Send>>,
+        endpoint.close(VarInt::from_u32(SHUTDOWN_CODE), b"Server shutting down");
+        // Give the peers a moment to receive the close
+        let _ = time::timeout(Duration::from_secs(1), endpoint.wait_idle()).await;
+    }
+}
+
+pub struct PtyStream {
+    /// Non-blocking reads and writes of the master
+    io: AsyncPty,
     slave: Mutex<Box<dyn SlavePty + Send>>,
     master: Mutex<Box<dyn MasterPty + Send>>,
+    /// Process ID of the session leader running on the PTY, 0 before it's spawned
+    leader_pid: AtomicI32,
+    /// Terminal type from the pty-req
+    term: String,
+    /// Recording of the program running on the PTY, if the session is recorded
+    recorder: OnceLock<SessionRecorder>,
+}
+
+impl PtyStream {
+    /// Sends SIGHUP to the session leader and the foreground process group,
+    /// like the kernel does when a terminal hangs up.
+    async fn hangup(&self) {
+        let leader = self.leader_pid.load(Ordering::SeqCst);
+        let foreground = self.master.lock().await.process_group_leader();
+        unsafe {
+            if leader > 0 {
+                libc::kill(leader, libc::SIGHUP);
+            }
+            if let Some(pgid) = foreground.filter(|pgid| *pgid > 0) {
+                libc::kill(-pgid, libc::SIGHUP);
+            }
+        }
+    }
+
+    /// Delivers a signal to the foreground process group of the terminal,
+    /// or to the session leader if the group can't be determined.
+    async fn signal(&self, signum: i32) -> io::Result<()> {
+        let foreground = self.master.lock().await.process_group_leader();
+        match foreground.filter(|pgid| *pgid > 0) {
+            Some(pgid) => send_signal(-pgid, signum),
+            None => match self.leader_pid.load(Ordering::SeqCst) {
+                0 => Ok(()),
+                leader => send_signal(leader, signum),
+            },
+        }
+    }
 }
 
 trait QuicServer {
@@ -465,14 +955,118 @@ trait QuicServer {
         &mut self,
         config: Arc<russh::server::Config>,
         connection: &Endpoint,
+        fallback: Option<TcpFallback>,
+        commands: mpsc::Receiver<ServerCommand>,
     ) -> Result<(), std::io::Error>;
 }
 
 #[async_trait::async_trait]
 impl server::Server for Server {
     type Handler = ServerSession;
-    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> ServerSession {
-        ServerSession::default()
+    fn new_client(&mut self, remote_addr: Option<std::net::SocketAddr>) -> ServerSession {
+        self.session_handler(remote_addr, Arc::new(SessionAudit::new(self.audit.clone(), remote_addr)))
+    }
+}
+
+impl Server {
+    fn session_handler(&self, remote_addr: Option<SocketAddr>, audit: Arc<SessionAudit>) -> ServerSession {
+        ServerSession {
+            persistent: self.persistent.clone(),
+            ssh_config: self.ssh_config.clone(),
+            remote_addr,
+            audit,
+            recording: self.recording.clone(),
+            subsystems: self.subsystems.clone(),
+            last_logins: self.last_logins.clone(),
+            auth_failures: self.auth_failures.clone(),
+            authorized_keys: self.authorized_keys.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Runs an SSH session over `stream` until it ends. `permit` is released
+    /// with the session.
+    async fn run_session(
+        self,
+        config: Arc<server::Config>,
+        stream: Box<dyn SshStream>,
+        remote: SocketAddr,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let _permit = permit;
+        let session_audit = Arc::new(SessionAudit::new(self.audit.clone(), Some(remote)));
+        session_audit.record(AuditEvent::SessionStart);
+        let handler = self.session_handler(Some(remote), session_audit.clone());
+        let attached = handler.attached.clone();
+
+        info!("New client connected!");
+
+        let session = match russh::server::run_stream(config, stream, handler).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Connection setup failed");
+                session_audit.finish();
+                return;
+            }
+        };
+
+        let tracked = self.sessions.insert(session.handle());
+        if tracked.is_none() {
+            // Started while the server is shutting down
+            let _ = session
+                .handle()
+                .disconnect(
+                    Disconnect::ByApplication,
+                    "Server shutting down".to_string(),
+                    String::new(),
+                )
+                .await;
+        }
+
+        match session.await {
+            Ok(_) => debug!("Connection closed"),
+            Err(e) => error!("Connection closed with error {}", e),
+        }
+        if let Some(id) = tracked {
+            self.sessions.remove(id);
+        }
+        // Channels of a dropped connection are never closed by the client
+        detach_all(&attached).await;
+        session_audit.finish();
+    }
+
+    /// Serves the SSH session of a connection to the TCP fallback listener
+    fn accept_tcp(
+        &self,
+        config: Arc<server::Config>,
+        fallback: &TcpFallback,
+        mut stream: TcpStream,
+        remote: SocketAddr,
+        connection_limit: &Arc<Semaphore>,
+    ) {
+        if let Some(left) = self.auth_failures.banned(&Offender::address(remote.ip())) {
+            info!("[server] refusing TCP {}: banned for another {}s", remote, left.as_secs());
+            return;
+        }
+
+        let Ok(permit) = connection_limit.clone().try_acquire_owned() else {
+            warn!("[server] refusing TCP {}: too many concurrent connections", remote);
+            tokio::spawn(async move {
+                // Lines before the SSH version string are shown to clients (RFC 4253 4.2)
+                let _ = stream.write_all(b"Too many concurrent connections\r\n").await;
+            });
+            return;
+        };
+
+        info!("[server] TCP connection accepted: {}", remote);
+        let handshake = fallback.handshake(stream);
+        let server = self.clone();
+        tokio::spawn(async move {
+            match handshake.await {
+                Ok(stream) => server.run_session(config, stream, remote, permit).await,
+                Err(e) => warn!("[server] TLS handshake with {} failed: {}", remote, e),
+            }
+        });
     }
 }
 
@@ -481,18 +1075,50 @@ impl QuicServer for Server {
         &mut self,
         config: Arc<server::Config>,
         endpoint: &Endpoint,
+        mut fallback: Option<TcpFallback>,
+        mut commands: mpsc::Receiver<ServerCommand>,
     ) -> Result<(), io::Error> {
-        let config_cloned = config.clone();
+        let mut config_cloned = config.clone();
+        let connection_limit = Arc::new(Semaphore::new(
+            self.ssh_config.max_concurrent_connections as usize,
+        ));
+        sd_notify("READY=1\nSTATUS=Accepting connections");
 
         loop {
             let conf = config_cloned.clone();
             info!("Waiting for connections..");
-            let incoming_conn = match endpoint.accept().await {
-                Some(conn) => conn,
-                None => {
+            let incoming_conn = select! {
+                incoming = endpoint.accept() => match incoming {
+                    Some(conn) => conn,
+                    None => {
+                        error!("[server] QUIC endpoint closed, shutting down");
+                        break;
+                    }
+                },
+                (stream, remote) = accept_fallback(fallback.as_ref()) => {
+                    if let Some(fallback) = &fallback {
+                        self.accept_tcp(conf, fallback, stream, remote, &connection_limit);
+                    }
                     continue;
-                }
+                },
+                Some(command) = commands.recv() => match command {
+                    ServerCommand::Reload(reloaded) => {
+                        config_cloned = self
+                            .apply_reload(*reloaded, &connection_limit, &mut fallback)
+                            .await;
+                        sd_notify("READY=1\nSTATUS=Accepting connections");
+                        continue;
+                    }
+                    ServerCommand::Shutdown => break,
+                },
             };
+
+            let remote_ip = incoming_conn.remote_address().ip();
+            if let Some(left) = self.auth_failures.banned(&Offender::address(remote_ip)) {
+                info!("[server] refusing {}: banned for another {}s", remote_ip, left.as_secs());
+                incoming_conn.refuse();
+                continue;
+            }
             let conn = match incoming_conn.await {
                 Ok(conn) => conn,
                 Err(e) => {
@@ -515,9 +1141,27 @@ impl QuicServer for Server {
                 sni
             );
 
+            let Ok(connection_permit) = connection_limit.clone().try_acquire_owned() else {
+                warn!(
+                    "[server] refusing {}: too many concurrent connections",
+                    conn.remote_address()
+                );
+                conn.close(
+                    VarInt::from_u32(POLICY_REJECTION_CODE),
+                    b"Too many concurrent connections",
+                );
+                continue;
+            };
+
             //A single connection can spawn multiple streams
+            let server = self.clone();
+            let stream_limit = Arc::new(Semaphore::new(
+                self.ssh_config.max_streams_per_connection as usize,
+            ));
 
             tokio::spawn(async move {
+                // Held for as long as the connection is open
+                let _connection_permit = connection_permit;
                 loop {
                     let conf = conf.clone();
                     let remote = conn.remote_address();
@@ -529,43 +1173,111 @@ impl QuicServer for Server {
                         }
                     };
 
-                    let mut bi_stream = BiStream {
+                    let Ok(stream_permit) = stream_limit.clone().try_acquire_owned() else {
+                        warn!("[server] refusing stream from {}: stream limit reached", remote);
+                        tokio::spawn(async move {
+                            // Lines before the SSH version string are shown to clients (RFC 4253 4.2)
+                            let _ = quinn_send
+                                .write_all(b"Too many sessions on this connection\r\n")
+                                .await;
+                            let _ = quinn_send.finish();
+                            let _ = quinn_recv.stop(VarInt::from_u32(POLICY_REJECTION_CODE));
+                        });
+                        continue;
+                    };
+
+                    let bi_stream = BiStream {
                         recv_stream: quinn_recv,
                         send_stream: quinn_send,
                     };
 
-                    let handler = ServerSession {
-                        ..Default::default()
-                    };
+                    tokio::spawn(server.clone().run_session(
+                        conf,
+                        Box::new(bi_stream),
+                        remote,
+                        stream_permit,
+                    ));
+                }
+            });
+        }
 
-                    info!("New client connected!");
+        self.shutdown(endpoint).await;
+        Ok(())
+    }
+}
 
-                    tokio::spawn(async move {
-                        let session =
-                            match russh::server::run_stream(conf, Box::new(bi_stream), handler)
-                                .await
-                            {
-                                Ok(s) => s,
-                                Err(e) => {
-                                    error!("Connection setup failed");
-                                    return;
-                                }
-                            };
+/// Rejects a channel request, explaining the reason on the channel's stderr
+fn reject_channel_request(session: &mut Session, channel_id: ChannelId, reason: &str) {
+    warn!("Rejected request on channel {:?}: {}", channel_id, reason);
+    let _ = session.extended_data(
+        channel_id,
+        EXTENDED_DATA_STDERR,
+        CryptoVec::from(format!("{}\r\n", reason).into_bytes()),
+    );
+    session.channel_failure(channel_id);
+}
 
-                        match session.await {
-                            Ok(_) => {
-                                debug!("Connection closed")
-                            }
-                            Err(e) => {
-                                error!("Connection closed with error {}", e);
-                                //TODO handle errors
-                            }
-                        }
-                    });
-                }
-            });
+/// Copies between a channel and a local stream until either side ends,
+/// then records the channel's traffic
+async fn relay_channel<S>(
+    channel: Channel<Msg>,
+    mut local: S,
+    channel_id: ChannelId,
+    audit: Arc<SessionAudit>,
+    stats: Option<Arc<ChannelStats>>,
+) where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let mut stream = channel.into_stream();
+    match tokio::io::copy_bidirectional(&mut stream, &mut local).await {
+        Ok((received, sent)) => {
+            if let Some(stats) = stats.as_ref() {
+                stats.add_in(received);
+                stats.add_out(sent);
+            }
         }
+        Err(e) => debug!("Channel stream ended: {:?}", e),
+    }
+
+    if let Some(stats) = stats {
+        audit.close_channel(channel_id, &stats);
+    }
+}
+
+/// Starts `sessio-server streamlocal-relay` as the login user with its stdio piped
+fn spawn_streamlocal_helper(login: &UserInfo, path: &Path) -> anyhow::Result<tokio::process::Child> {
+    let exe = std::env::current_exe().context("Failed to locate the server executable")?;
+
+    let mut command = Command::new(exe);
+    command
+        .arg("streamlocal-relay")
+        .arg("--path")
+        .arg(path)
+        .env_clear()
+        .envs(login.login_env())
+        .current_dir(if login.home.is_dir() { login.home.as_path() } else { Path::new("/") })
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    run_as(&mut command, login);
+
+    Ok(TokioCommand::from(command).kill_on_drop(true).spawn()?)
+}
+
+/// Connects to a Unix socket with the permissions of the login user. When the server
+/// runs as someone else, a helper process running as the login user connects instead.
+async fn connect_streamlocal(login: &UserInfo, path: &Path) -> anyhow::Result<StreamLocal> {
+    if !login.needs_switch() {
+        return Ok(StreamLocal::Direct(UnixStream::connect(path).await?));
+    }
+
+    let mut child = spawn_streamlocal_helper(login, path)?;
+    let stdout = child.stdout.as_mut().context("Relay helper has no stdout")?;
+    let mut status = [0; 1];
+    if stdout.read(&mut status).await? != 1 || status[0] != STREAMLOCAL_CONNECTED {
+        bail!("Relay helper failed to connect");
     }
+    Ok(StreamLocal::Helper(child))
 }
 
 impl ServerSession {
@@ -573,60 +1285,542 @@ impl ServerSession {
         let mut clients = self.clients.lock().await;
         clients.remove(&channel_id).unwrap()
     }
-}
-
-
-impl server::Handler for ServerSession {
-    type Error = anyhow::Error;
 
-    /// Basic local forwarding
-    #[allow(unused_variables)]
-    async fn channel_open_direct_tcpip(
-        &mut self,
-        mut channel: Channel<Msg>,
-        host_to_connect: &str,
-        port_to_connect: u32,
-        originator_address: &str,
-        originator_port: u32,
-        session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        info!(
-            "Forwarding {}:{} for {}:{}",
-            host_to_connect, port_to_connect, originator_address, originator_port
-        );
-        let host = host_to_connect.to_string();
-        let mut stream = TcpStream::connect((host, port_to_connect as u16)).await?;
-
-        tokio::spawn(async move {
-            let mut cin = channel.make_writer();
-            let mut cout = channel.make_reader();
+    fn login(&self) -> anyhow::Result<Arc<UserInfo>> {
+        self.login.clone().context("Session is not authenticated")
+    }
 
-            let (mut s_read, mut s_write) = stream.split();
-            tokio::try_join! {
-                tokio::io::copy(&mut s_read, &mut cin),
-                tokio::io::copy(&mut cout, &mut s_write)
+    /// Builds the command for a session program, applying the options of the authorized key:
+    /// a forced command replaces the requested program and `environment=` variables are set.
+    async fn session_command(
+        &self,
+        channel_id: ChannelId,
+        login: &UserInfo,
+        program: SessionProgram,
+    ) -> Command {
+        let mut command = match &self.key_options.command {
+            Some(forced) => {
+                let mut command = build_command(login, &SessionProgram::Command(forced.clone()));
+                if let SessionProgram::Command(original) = &program {
+                    command.env("SSH_ORIGINAL_COMMAND", original);
+                }
+                command
             }
-        });
-
-        Ok(true)
+            None => build_command(login, &program),
+        };
+        if let Some(env) = self.channel_env.lock().await.remove(&channel_id) {
+            command.envs(env);
+        }
+        if let Some(agent) = self.agent.lock().await.as_ref() {
+            command.env("SSH_AUTH_SOCK", agent.socket_path());
+        }
+        command.envs(self.key_options.environment.iter().map(|(k, v)| (k, v)));
+        command
     }
 
-    async fn subsystem_request(
+    /// Finds the authorized key entry for `public_key` that may be used
+    /// from the client's address at this time
+    async fn find_authorized_key(
+        &self,
+        login: &UserInfo,
+        public_key: &PublicKey,
+    ) -> anyhow::Result<Option<AuthorizedKey>> {
+        let authorized_keys = self.authorized_keys.user_keys(&login.home).await?;
+
+        for entry in authorized_keys.iter().filter(|entry| &entry.key == public_key) {
+            if entry.options.is_expired() {
+                warn!("Authorized key {} of {} has expired", entry.comment, login.name);
+                continue;
+            }
+            let source_allowed = match self.remote_addr {
+                Some(addr) => entry.options.allows_source(addr.ip()),
+                None => entry.options.from.is_empty(),
+            };
+            if !source_allowed {
+                warn!(
+                    "Authorized key {} of {} is not allowed from {:?}",
+                    entry.comment, login.name, self.remote_addr
+                );
+                continue;
+            }
+            return Ok(Some(entry.clone()));
+        }
+
+        Ok(None)
+    }
+
+    fn audit_auth_failure(&self, user: &str, public_key: &PublicKey, reason: &str) {
+        self.audit.set_user(user);
+        self.audit.record(AuditEvent::AuthFailure {
+            method: "publickey",
+            fingerprint: public_key.fingerprint(HashAlg::Sha256).to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Audits a failed authentication by the client and counts it towards a
+    /// ban of the remote address, and of the key if the client `signed` with it
+    fn auth_failure(&self, user: &str, public_key: &PublicKey, reason: &str, signed: bool) {
+        self.audit_auth_failure(user, public_key, reason);
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        self.auth_failures.record_failure(
+            &failure_offenders(self.remote_addr, Some(fingerprint), signed),
+            self.remote_addr,
+        );
+    }
+
+    async fn channel_stats(&self, channel_id: ChannelId) -> Option<Arc<ChannelStats>> {
+        self.channel_stats.lock().await.get(&channel_id).cloned()
+    }
+
+    /// Detaches a channel from its persistent session, which keeps running
+    async fn detach_persistent(&self, channel_id: ChannelId) {
+        if let Some((persistent, attachment)) = self.attached.lock().await.remove(&channel_id) {
+            persistent.detach(attachment).await;
+            self.ptys.lock().await.remove(&channel_id);
+        }
+    }
+
+    /// Spawns a program as the login user on the PTY allocated for the channel, streams
+    /// the PTY output to the client and reports the exit status once the program finishes.
+    /// Output of a persistent session goes through it instead, so it survives the channel.
+    fn spawn_pty_command(
+        &self,
+        channel_id: ChannelId,
+        stream: Arc<PtyStream>,
+        login: Arc<UserInfo>,
+        mut command: Command,
+        handle: Handle,
+        persistent: Option<Arc<PersistentPty>>,
+    ) {
+        let handle_reader = handle.clone();
+        let persistent_reader = persistent.clone();
+        let persistent_sessions = self.persistent.clone();
+        let channel_stats = self.channel_stats.clone();
+        let clients = self.clients.clone();
+
+        if self.record_session {
+            let size = stream.master.try_lock().ok().and_then(|master| master.get_size().ok());
+            let (cols, rows) = size.map(|size| (size.cols, size.rows)).unwrap_or((80, 24));
+            match SessionRecorder::create(&self.recording.path, &login.name, &stream.term, cols, rows) {
+                Ok(recorder) => {
+                    let _ = stream.recorder.set(recorder);
+                }
+                Err(e) => error!("Failed to start session recording: {:?}", e),
+            }
+        }
+
+        tokio::spawn(async move {
+            let stream_reader = stream.clone();
+            let stats = channel_stats.lock().await.get(&channel_id).cloned();
+            // Output is written through the channel, which waits for window space, so a
+            // slow client stops the reads and the program blocks on the full terminal
+            let channel_writer = clients
+                .lock()
+                .await
+                .get(&channel_id)
+                .map(|channel| channel.make_writer());
+            let (exited_tx, mut exited_rx) = oneshot::channel::<()>();
+
+            let reader_handle = tokio::spawn(async move {
+                let mut channel_writer = channel_writer;
+                let mut buffer = vec![0; PTY_READ_BUFFER];
+                let mut exited = false;
+
+                loop {
+                    let read = if exited {
+                        // Drain what the program wrote before it exited
+                        match stream_reader.io.try_read(&mut buffer) {
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            read => read,
+                        }
+                    } else {
+                        tokio::select! {
+                            read = stream_reader.io.read(&mut buffer) => read,
+                            _ = &mut exited_rx => {
+                                exited = true;
+                                continue;
+                            }
+                        }
+                    };
+
+                    let n = match read {
+                        Ok(0) => {
+                            debug!("PTY: No more data to read.");
+                            break;
+                        }
+                        Ok(n) => n,
+                        Err(e) => {
+                            error!("PTY read error: {:?}", e);
+                            break;
+                        }
+                    };
+                    let output = &buffer[..n];
+
+                    if let Some(recorder) = stream_reader.recorder.get() {
+                        recorder.output(output);
+                    }
+
+                    if let Some(persistent) = persistent_reader.as_ref() {
+                        persistent.output(output).await;
+                        continue;
+                    }
+
+                    let sent = match channel_writer.as_mut() {
+                        Some(writer) => writer.write_all(output).await.map_err(anyhow::Error::new),
+                        None => handle_reader
+                            .data(channel_id, CryptoVec::from_slice(output))
+                            .await
+                            .map_err(|_| anyhow::anyhow!("Channel closed")),
+                    };
+                    match sent {
+                        Ok(()) => {
+                            if let Some(stats) = stats.as_ref() {
+                                stats.add_out(n as u64);
+                            }
+                        }
+                        Err(e) => {
+                            error!("Error sending PTY data to client: {:?}", e);
+                            // Like a terminal hangup, the client is gone
+                            stream_reader.hangup().await;
+                            break;
+                        }
+                    }
+                }
+            });
+
+            let child_exit = tokio::task::spawn_blocking(move || -> anyhow::Result<ChildExit> {
+                command.env("TERM", &stream.term);
+                let child = spawn_on_pty(command, &**stream.master.blocking_lock(), &login)?;
+                stream.leader_pid.store(child.id() as i32, Ordering::SeqCst);
+                Ok(wait_pty_child(Box::new(child))?)
+            })
+            .await;
+
+            // Send the remaining output before the exit status
+            let _ = exited_tx.send(());
+            let _ = reader_handle.await;
+
+            if let Some(persistent) = persistent {
+                persistent_sessions.remove(&persistent).await;
+                match child_exit {
+                    Ok(Ok(exit)) => persistent.finish(exit).await,
+                    Ok(Err(e)) => {
+                        error!("Failed to run child process: {:?}", e);
+                        persistent.finish(ChildExit::Code(1)).await;
+                    }
+                    Err(e) => {
+                        error!("Failed to wait on child process: {:?}", e);
+                    }
+                }
+                return;
+            }
+
+            match child_exit {
+                Ok(Ok(exit)) => report_exit(&handle, channel_id, exit).await,
+                Ok(Err(e)) => {
+                    error!("Failed to run child process: {:?}", e);
+                    let _ = handle.close(channel_id).await;
+                }
+                Err(e) => {
+                    error!("Failed to wait on child process: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Runs the login shell in a newly opened persistent session, or reattaches
+    /// the channel to the running one.
+    async fn start_persistent_shell(
+        &self,
+        channel_id: ChannelId,
+        persistent: Arc<PersistentPty>,
+        reattach: bool,
+        stream: Arc<PtyStream>,
+        login: Arc<UserInfo>,
+        handle: Handle,
+    ) {
+        if reattach {
+            info!("Reattaching persistent session {} for {}", persistent.id, login.name);
+
+            // Take over the window size of the newly requested PTY
+            if let Ok(size) = stream.master.lock().await.get_size() {
+                let _ = persistent.pty.master.lock().await.resize(size);
+            }
+
+            self.ptys.lock().await.insert(channel_id, persistent.pty.clone());
+            let attachment = persistent
+                .attach(handle, channel_id, self.channel_stats(channel_id).await)
+                .await;
+            self.attached.lock().await.insert(channel_id, (persistent, attachment));
+            return;
+        }
+
+        info!("Starting persistent session {} for {}", persistent.id, login.name);
+        let attachment = persistent
+            .attach(handle.clone(), channel_id, self.channel_stats(channel_id).await)
+            .await;
+        self.attached.lock().await.insert(channel_id, (persistent.clone(), attachment));
+        let command = self
+            .session_command(channel_id, &login, SessionProgram::LoginShell)
+            .await;
+        self.spawn_pty_command(channel_id, stream, login, command, handle, Some(persistent));
+    }
+
+    /// Runs a command as the login user without a PTY. Stdout is sent as channel data
+    /// and stderr as extended data, client data is written to the process stdin.
+    async fn spawn_exec_command(
+        &self,
+        channel_id: ChannelId,
+        login: &UserInfo,
+        command: &str,
+        handle: Handle,
+    ) -> anyhow::Result<()> {
+        let mut command = self
+            .session_command(channel_id, login, SessionProgram::Command(command.to_string()))
+            .await;
+        // A group of its own lets signal requests reach the whole pipeline
+        command.process_group(0);
+        run_as(&mut command, login);
+
+        let mut child = TokioCommand::from(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().context("Child stdin not captured")?;
+        let stdout = child.stdout.take().context("Child stdout not captured")?;
+        let stderr = child.stderr.take().context("Child stderr not captured")?;
+
+        let (stdin_tx, stdin_rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_exec_stdin(stdin, stdin_rx));
+        self.exec_stdins.lock().await.insert(channel_id, stdin_tx);
+        if let Some(pid) = child.id() {
+            self.exec_pgids.lock().await.insert(channel_id, pid as i32);
+        }
+        let exec_stdins = self.exec_stdins.clone();
+        let exec_pgids = self.exec_pgids.clone();
+        let stats = self.channel_stats(channel_id).await;
+
+        tokio::spawn(async move {
+            let stdout_task = tokio::spawn(forward_output(stdout, handle.clone(), channel_id, None));
+            let stderr_task = tokio::spawn(forward_output(
+                stderr,
+                handle.clone(),
+                channel_id,
+                Some(EXTENDED_DATA_STDERR),
+            ));
+
+            let status = child.wait().await;
+            exec_pgids.lock().await.remove(&channel_id);
+            // Drain the remaining output before the exit status is sent
+            let (stdout_sent, stderr_sent) = tokio::join!(stdout_task, stderr_task);
+            if let Some(stats) = stats {
+                stats.add_out(stdout_sent.unwrap_or(0) + stderr_sent.unwrap_or(0));
+            }
+            exec_stdins.lock().await.remove(&channel_id);
+
+            match status {
+                Ok(status) => report_exit(&handle, channel_id, status.into()).await,
+                Err(e) => {
+                    error!("Failed to wait on child process: {:?}", e);
+                    let _ = handle.close(channel_id).await;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+
+impl server::Handler for ServerSession {
+    type Error = anyhow::Error;
+
+    /// Basic local forwarding
+    #[allow(unused_variables)]
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        mut channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        originator_address: &str,
+        originator_port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(login) = self.login.clone() else {
+            return Ok(false);
+        };
+        let audit_request = |accepted: bool| AuditEvent::DirectTcpip {
+            channel: channel.id().into(),
+            host: host_to_connect.to_string(),
+            port: port_to_connect,
+            originator: format!("{}:{}", originator_address, originator_port),
+            accepted,
+        };
+        if !self.ssh_config.enable_port_forwarding || self.key_options.no_port_forwarding {
+            warn!("Rejected direct-tcpip for {}: port forwarding is disabled", login.name);
+            self.audit.record(audit_request(false));
+            return Ok(false);
+        }
+        if !permit_open_allows(&self.ssh_config.permit_open, host_to_connect, port_to_connect)
+            || !permit_open_allows(&self.key_options.permit_open, host_to_connect, port_to_connect)
+        {
+            warn!(
+                "Rejected direct-tcpip for {} to {}:{}: not in permit_open",
+                login.name, host_to_connect, port_to_connect
+            );
+            self.audit.record(audit_request(false));
+            return Ok(false);
+        }
+        self.audit.record(audit_request(true));
+        info!(
+            "Forwarding {}:{} for {}:{} as {}",
+            host_to_connect, port_to_connect, originator_address, originator_port, login.name
+        );
+        let host = host_to_connect.to_string();
+        let mut stream = TcpStream::connect((host, port_to_connect as u16)).await?;
+
+        // The channel's data doesn't pass through the handler, so its traffic is counted here
+        let channel_id = channel.id();
+        let stats = self.audit.open_channel(channel_id, "direct-tcpip");
+        let audit = self.audit.clone();
+        tokio::spawn(async move {
+            let mut cin = channel.make_writer();
+            let mut cout = channel.make_reader();
+
+            let (mut s_read, mut s_write) = stream.split();
+            let (sent, received) = tokio::join! {
+                tokio::io::copy(&mut s_read, &mut cin),
+                tokio::io::copy(&mut cout, &mut s_write)
+            };
+            stats.add_out(sent.unwrap_or(0));
+            stats.add_in(received.unwrap_or(0));
+            audit.close_channel(channel_id, &stats);
+        });
+
+        Ok(true)
+    }
+
+    async fn channel_open_direct_streamlocal(
+        &mut self,
+        channel: Channel<Msg>,
+        socket_path: &str,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(login) = self.login.clone() else {
+            return Ok(false);
+        };
+        let audit_request = |accepted: bool| AuditEvent::DirectStreamlocal {
+            channel: channel.id().into(),
+            socket_path: socket_path.to_string(),
+            accepted,
+        };
+        if !self.ssh_config.enable_port_forwarding || self.key_options.no_port_forwarding {
+            warn!("Rejected direct-streamlocal for {}: port forwarding is disabled", login.name);
+            self.audit.record(audit_request(false));
+            return Ok(false);
+        }
+        // A permit_open allowlist limits forwarding to the TCP destinations it lists
+        if !self.ssh_config.permit_open.is_empty() || !self.key_options.permit_open.is_empty() {
+            warn!(
+                "Rejected direct-streamlocal for {} to {}: forwarding is restricted by permit_open",
+                login.name, socket_path
+            );
+            self.audit.record(audit_request(false));
+            return Ok(false);
+        }
+
+        // Relative paths are resolved against the home directory, like in OpenSSH
+        let path = login.home.join(socket_path);
+        let local = match connect_streamlocal(&login, &path).await {
+            Ok(local) => local,
+            Err(e) => {
+                warn!("Failed to connect to {:?} for {}: {:?}", path, login.name, e);
+                self.audit.record(audit_request(false));
+                return Ok(false);
+            }
+        };
+        self.audit.record(audit_request(true));
+        info!("Forwarding Unix socket {:?} as {}", path, login.name);
+
+        let channel_id = channel.id();
+        let stats = Some(self.audit.open_channel(channel_id, "direct-streamlocal"));
+        let audit = self.audit.clone();
+        tokio::spawn(async move {
+            match local {
+                StreamLocal::Direct(stream) => {
+                    relay_channel(channel, stream, channel_id, audit, stats).await;
+                }
+                StreamLocal::Helper(mut child) => {
+                    let stdin = child.stdin.take().unwrap();
+                    let stdout = child.stdout.take().unwrap();
+                    let helper = tokio::io::join(stdout, stdin);
+                    relay_channel(channel, helper, channel_id, audit, stats).await;
+                    let _ = child.wait().await;
+                }
+            }
+        });
+
+        Ok(true)
+    }
+
+    async fn subsystem_request(
         &mut self,
         channel_id: ChannelId,
         name: &str,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         info!("subsystem: {}", name);
+        self.audit.record(AuditEvent::Subsystem {
+            channel: channel_id.into(),
+            name: name.to_string(),
+        });
 
-        if name == "sftp" {
-            let channel = self.take_channel(channel_id).await;
-            let user = self.user.as_ref().unwrap().clone();
-            let sftp = SftpSession::new(user);
-            session.channel_success(channel_id);
-            russh_sftp::server::run(channel.into_stream(), sftp).await;
-        } else {
-            session.channel_failure(channel_id);
+        if self.key_options.command.is_some() {
+            reject_channel_request(session, channel_id, "This key is restricted to a forced command");
+            return Ok(());
+        }
+
+        if name == "sftp" && !self.ssh_config.enable_sftp {
+            reject_channel_request(session, channel_id, "SFTP is disabled on this server");
+            return Ok(());
+        }
+
+        let login = self.login()?;
+        match self.subsystems.get(name) {
+            Some(SubsystemHandler::Builtin(subsystem)) => {
+                let io = match subsystem.start(&login, &self.fingerprint).await {
+                    Ok(io) => io,
+                    Err(e) => {
+                        error!("Failed to start subsystem {} for {}: {:?}", name, login.name, e);
+                        session.channel_failure(channel_id);
+                        return Ok(());
+                    }
+                };
+                session.channel_success(channel_id);
+
+                let channel = self.take_channel(channel_id).await;
+                // The data goes straight to the channel stream, its traffic is counted by the relay
+                let stats = self.channel_stats.lock().await.remove(&channel_id);
+                let audit = self.audit.clone();
+                tokio::spawn(serve_subsystem(io, channel, session.handle(), audit, stats));
+            }
+            Some(SubsystemHandler::Command(command)) => {
+                info!("Starting subsystem {} for {}: {}", name, login.name, command);
+                if let Err(e) = self
+                    .spawn_exec_command(channel_id, &login, &command, session.handle())
+                    .await
+                {
+                    error!("Failed to start subsystem {} for {}: {:?}", name, login.name, e);
+                    session.channel_failure(channel_id);
+                    return Ok(());
+                }
+                session.channel_success(channel_id);
+            }
+            None => session.channel_failure(channel_id),
         }
 
         Ok(())
@@ -645,6 +1839,8 @@ impl server::Handler for ServerSession {
                 new_id,
                 channel.id()
             );
+            let stats = self.audit.open_channel(channel.id(), "session");
+            self.channel_stats.lock().await.insert(channel.id(), stats);
             clients.insert(channel.id(), channel);
         }
         Ok(true)
@@ -670,97 +1866,145 @@ impl server::Handler for ServerSession {
         channel_id: ChannelId,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let handle_reader = session.handle();
-        let handle_waiter = session.handle();
-
-        let ptys = self.ptys.clone();
-
-        // We're not using the stored user as proper multi-user handling is not implemented yet
-
-        let shell = if cfg!(windows) {
-            vec![
-                OsString::from("cmd.exe")
-            ]
-        } else {
-            vec![OsString::from("/bin/bash")]
+        let login = self.login()?;
+        let Some(stream) = self.ptys.lock().await.get(&channel_id).cloned() else {
+            error!("Shell requested without a PTY");
+            session.channel_failure(channel_id);
+            return Ok(());
         };
 
-        tokio::spawn(async move {
-            let pty_cloned = ptys.clone();
-            let reader_handle = tokio::spawn(async move {
-                loop {
-                    let mut buffer = vec![0; 1024];
-                    let pty_cloned = ptys.clone();
-                    match tokio::task::spawn_blocking(move || {
-                        let stream = pty_cloned.blocking_lock().get(&channel_id).unwrap().clone();
-                        let mut reader = stream.reader.blocking_lock();
-                        reader.read(&mut buffer).map(|n| (n, buffer))
-                    })
-                    .await
-                    {
-                        Ok(Ok((n, _))) if n == 0 => {
-                            debug!("PTY: No more data to read.");
-                            break;
-                        }
-                        Ok(Ok((n, buffer))) => {
-                            if let Err(e) = handle_reader
-                                .data(channel_id, CryptoVec::from_slice(&buffer[0..n]))
-                                .await
-                            {
-                                error!("Error sending PTY data to client: {:?}", e);
-                                break;
-                            }
-                        }
-                        Ok(Err(e)) => {
-                            error!("PTY read error: {:?}", e);
-                            break;
-                        }
-                        Err(e) => {
-                            error!("Join error: {:?}", e);
-                            break;
-                        }
-                    }
+        let mut persistent_id = self.persistent_ids.lock().await.remove(&channel_id);
+        // A persistent session may be a shell started with an unrestricted key,
+        // a key restricted to a forced command runs that command instead
+        if persistent_id.is_some() && self.key_options.command.is_some() {
+            warn!("Ignoring {} for a key restricted to a forced command", PERSISTENT_SESSION_ENV);
+            persistent_id = None;
+        }
+        let persistent = match persistent_id {
+            Some(id) => match self
+                .persistent
+                .open(id, &login.name, &stream, self.ssh_config.max_persistent_sessions)
+                .await
+            {
+                Ok(persistent) => Some(persistent),
+                Err(e) => {
+                    reject_channel_request(session, channel_id, &e.to_string());
+                    return Ok(());
                 }
+            },
+            None => None,
+        };
+        // A reattached session continues where it was, without a new greeting
+        let reattaching = matches!(persistent, Some((_, true)));
+        if !reattaching {
+            let motd = load_motd(&self.ssh_config).await;
+            let last_login = self
+                .previous_login
+                .as_ref()
+                .filter(|_| self.ssh_config.print_last_login);
+            let message = welcome_message(motd.as_deref(), last_login);
+            if !message.is_empty() {
+                let _ = session.data(channel_id, CryptoVec::from(message.into_bytes()));
+            }
+        }
+
+        if let Some((persistent, reattach)) = persistent {
+            self.audit.record(AuditEvent::Shell {
+                channel: channel_id.into(),
             });
+            self.start_persistent_shell(
+                channel_id,
+                persistent,
+                reattach,
+                stream,
+                login,
+                session.handle(),
+            )
+            .await;
+            return Ok(());
+        }
+
+        info!("Starting login shell for {}", login.name);
+        self.audit.record(AuditEvent::Shell {
+            channel: channel_id.into(),
+        });
+        let command = self
+            .session_command(channel_id, &login, SessionProgram::LoginShell)
+            .await;
+        self.spawn_pty_command(channel_id, stream, login, command, session.handle(), None);
+        Ok(())
+    }
 
-            let child_status = tokio::task::spawn_blocking(move || {
-                let stream = pty_cloned.blocking_lock().get(&channel_id).unwrap().clone();
+    async fn env_request(
+        &mut self,
+        channel_id: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        if variable_name == PERSISTENT_SESSION_ENV && !variable_value.is_empty() {
+            self.persistent_ids
+                .lock()
+                .await
+                .insert(channel_id, variable_value.to_string());
+            session.channel_success(channel_id);
+        } else if accept_env_allows(&self.ssh_config.accept_env, variable_name)
+            && !variable_name.contains(['=', '\0'])
+            && !variable_value.contains('\0')
+        {
+            self.channel_env
+                .lock()
+                .await
+                .entry(channel_id)
+                .or_default()
+                .push((variable_name.to_string(), variable_value.to_string()));
+            session.channel_success(channel_id);
+        } else {
+            debug!("Ignoring environment variable {} not accepted by accept_env", variable_name);
+            session.channel_failure(channel_id);
+        }
+        Ok(())
+    }
 
-                let command_builder = CommandBuilder::from_argv(shell);
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        Ok(load_banner(&self.ssh_config).await)
+    }
 
-                let mut child = stream
-                    .slave
-                    .blocking_lock()
-                    .spawn_command(command_builder)
-                    .expect("Failed to spawn child process");
-                child.wait().expect("Failed to wait on child process")
-            })
-            .await;
+    async fn agent_request(
+        &mut self,
+        channel_id: ChannelId,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Some(login) = self.login.clone() else {
+            return Ok(false);
+        };
+        let audit_request = |accepted: bool| AuditEvent::AgentForward {
+            channel: channel_id.into(),
+            accepted,
+        };
+        if !self.ssh_config.enable_agent_forwarding || self.key_options.no_agent_forwarding {
+            warn!("Rejected agent forwarding for {}: agent forwarding is disabled", login.name);
+            self.audit.record(audit_request(false));
+            return Ok(false);
+        }
 
-            match child_status {
-                Ok(status) => {
-                    if status.success() {
-                        info!("Child process exited successfully.");
-                        //reader_handle.abort();
-                        let _ = handle_waiter
-                            .exit_status_request(channel_id, status.exit_code())
-                            .await;
-                        let _ = handle_waiter.close(channel_id).await;
-                    } else {
-                        error!("Child process exited with status: {:?}", status);
-                        //reader_handle.abort();
-                        let _ = handle_waiter
-                            .exit_status_request(channel_id, status.exit_code())
-                            .await;
-                        let _ = handle_waiter.close(channel_id).await;
-                    }
+        // One socket serves every channel of the session
+        let mut agent = self.agent.lock().await;
+        if agent.is_none() {
+            match AgentForward::start(&login, session.handle()) {
+                Ok(forward) => {
+                    info!("Forwarding agent of {} at {:?}", login.name, forward.socket_path());
+                    *agent = Some(forward);
                 }
                 Err(e) => {
-                    error!("Failed to wait on child process: {:?}", e);
+                    error!("Failed to create agent socket for {}: {:?}", login.name, e);
+                    self.audit.record(audit_request(false));
+                    return Ok(false);
                 }
             }
-        });
-        Ok(())
+        }
+        self.audit.record(audit_request(true));
+        Ok(true)
     }
 
     async fn window_change_request(
@@ -772,9 +2016,9 @@ impl server::Handler for ServerSession {
         pix_height: u32,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        let clone = self.ptys.clone();
-        let ptys_guard = clone.lock().await;
-        let pty = ptys_guard.get(&channel_id).unwrap();
+        let Some(pty) = self.ptys.lock().await.get(&channel_id).cloned() else {
+            return Ok(());
+        };
 
         let _ = pty.master.lock().await.resize(PtySize {
             rows: row_height as u16,
@@ -782,6 +2026,9 @@ impl server::Handler for ServerSession {
             pixel_width: pix_width as u16,
             pixel_height: pix_height as u16,
         });
+        if let Some(recorder) = pty.recorder.get() {
+            recorder.resize(col_width, row_height);
+        }
 
         Ok(())
     }
@@ -799,6 +2046,11 @@ impl server::Handler for ServerSession {
     ) -> Result<(), Self::Error> {
         info!("Requesting PTY!");
 
+        if self.key_options.no_pty {
+            reject_channel_request(session, channel_id, "PTY allocation is disabled for this key");
+            return Ok(());
+        }
+
         info!(
             "PTY request received: term={}, col_width={}, row_height={}",
             term, col_width, row_height
@@ -816,18 +2068,22 @@ impl server::Handler for ServerSession {
         let slave = pair.slave;
         let mut master = pair.master;
 
-        let master_reader = Mutex::new(master.try_clone_reader().unwrap());
-        let mut master_writer = Mutex::new(master.take_writer().unwrap());
+        if let Err(e) = apply_terminal_modes(&*master, modes) {
+            warn!("Failed to apply terminal modes: {:?}", e);
+        }
 
+        let io = AsyncPty::new(&*master)?;
         let master_lock = Mutex::new(master);
 
         self.ptys.lock().await.insert(
             channel_id,
             Arc::new(PtyStream {
-                reader: master_reader,
-                writer: master_writer,
+                io,
                 master: master_lock,
                 slave: Mutex::new(slave),
+                leader_pid: AtomicI32::new(0),
+                term: if term.is_empty() { "xterm".to_string() } else { term.to_string() },
+                recorder: OnceLock::new(),
             }),
         );
 
@@ -855,25 +2111,53 @@ impl server::Handler for ServerSession {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<server::Auth, Self::Error> {
-        //User based auth isn't implemented yet
-
         log::debug!("Attempting to authenticate user: {}", user);
         log::debug!("Public key: {:?}", public_key);
 
-        // For now, we ignore the user parameter
-        let authorized_keys = common::utils::keygen::read_authorized_keys(None)
+        let reject = server::Auth::Reject {
+            proceed_with_methods: None,
+            partial_success: false,
+        };
+
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        if self
+            .auth_failures
+            .any_banned(&offenders(self.remote_addr, Some(fingerprint.clone())))
+        {
+            warn!("Rejecting banned key {} for user {}", fingerprint, user);
+            self.audit_auth_failure(user, public_key, "Banned");
+            return Ok(reject);
+        }
+
+        let login = match resolve_login_user(user) {
+            Ok(Some(login)) => login,
+            Ok(None) => {
+                warn!("Rejecting login for unknown user {}", user);
+                self.auth_failure(user, public_key, "Unknown user", false);
+                return Ok(reject);
+            }
+            Err(e) => {
+                error!("Failed to look up user {}: {}", user, e);
+                self.audit_auth_failure(user, public_key, "User lookup failed");
+                return Ok(reject);
+            }
+        };
+
+        let authorized_key = self
+            .find_authorized_key(&login, public_key)
             .await
             .map_err(|e| {
                 error!("{}", e);
+                self.audit_auth_failure(user, public_key, "Failed to read authorized keys");
                 russh::Error::CouldNotReadKey
             })?;
-        let res = if authorized_keys.contains(&public_key) {
+        let res = if authorized_key.is_some() {
             server::Auth::Accept
         } else {
-            server::Auth::Reject {
-                proceed_with_methods: None,
-                partial_success: false,
-            }
+            // Clients offer each of their keys until one is accepted, so a key
+            // the user doesn't have is no failure until it's used with a signature
+            self.audit_auth_failure(user, public_key, "Key not authorized");
+            reject
         };
 
         Ok(res)
@@ -884,8 +2168,44 @@ impl server::Handler for ServerSession {
         user: &str,
         public_key: &PublicKey,
     ) -> Result<server::Auth, Self::Error> {
+        let reject = server::Auth::Reject {
+            proceed_with_methods: None,
+            partial_success: false,
+        };
+
+        //The key was checked by auth_publickey_offered, look it up again for its options
+        let Some(login) = resolve_login_user(user)? else {
+            return Ok(reject);
+        };
+        let Some(authorized_key) = self.find_authorized_key(&login, public_key).await? else {
+            self.auth_failure(user, public_key, "Key not authorized", true);
+            return Ok(reject);
+        };
+
+        info!("User {} logged in as {}", user, login.name);
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        self.auth_failures
+            .record_success(&offenders(self.remote_addr, Some(fingerprint.clone())));
+        self.audit.set_user(user);
+        self.audit.record(AuditEvent::AuthSuccess {
+            method: "publickey",
+            fingerprint: fingerprint.clone(),
+            login: login.name.clone(),
+        });
+        self.record_session = self.recording.should_record(&login.name, &fingerprint);
+        // Keys synced from the coordinator are commented with `<device id>@<os>`
+        let source = match authorized_key.comment.rsplit_once('@') {
+            Some((device_id, _)) if !device_id.is_empty() => device_id.to_string(),
+            _ => self
+                .remote_addr
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|| "unknown".to_string()),
+        };
+        self.previous_login = self.last_logins.record(&login.name, &source);
         self.user = Some(user.into());
-        //Accept after auth_publickey_offered has succeeded
+        self.login = Some(Arc::new(login));
+        self.key_options = Arc::new(authorized_key.options);
+        self.fingerprint = fingerprint;
         Ok(server::Auth::Accept)
     }
 
@@ -895,14 +2215,22 @@ impl server::Handler for ServerSession {
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        if let Some(pty_stream) = self.ptys.lock().await.get_mut(&channel_id) {
-            log::info!("pty_writer: data = {data:02x?}");
+        if let Some(stats) = self.channel_stats(channel_id).await {
+            stats.add_in(data.len() as u64);
+        }
 
-            let mut pty_writer = pty_stream.writer.lock().await;
+        let pty = self.ptys.lock().await.get(&channel_id).cloned();
+        if let Some(pty_stream) = pty {
+            log::debug!("pty_writer: data = {data:02x?}");
 
-            pty_writer.write_all(data).map_err(anyhow::Error::new)?;
+            if let Some(recorder) = pty_stream.recorder.get() {
+                recorder.input(data);
+            }
 
-            pty_writer.flush().map_err(anyhow::Error::new)?;
+            pty_stream.io.write_all(data).await.map_err(anyhow::Error::new)?;
+        } else if let Some(stdin) = self.exec_stdins.lock().await.get(&channel_id) {
+            // The writer task has stopped if the process closed its stdin
+            let _ = stdin.send(data.to_vec());
         }
         Ok(())
     }
@@ -923,22 +2251,53 @@ impl server::Handler for ServerSession {
         channel: ChannelId,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
+        info!("Receiving channel eof!");
+
+        // Commands started by exec only get their stdin closed (by dropping
+        // the sender), the channel is closed once the process has exited.
+        if self.exec_stdins.lock().await.remove(&channel).is_some() {
+            return Ok(());
+        }
+
+        self.detach_persistent(channel).await;
+
         // After a client has sent an EOF, indicating that they don't want
         // to send more data in this session, the channel can be closed.
-        info!("Receiving channel eof!");
         session.close(channel);
 
         Ok(())
     }
 
-    #[allow(unused_variables)]
     async fn exec_request(
         &mut self,
-        channel: ChannelId,
+        channel_id: ChannelId,
         data: &[u8],
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        info!("Receiving exec req!");
+        let command = String::from_utf8_lossy(data).into_owned();
+        info!("Receiving exec req: {}", command);
+        self.audit.record(AuditEvent::Exec {
+            channel: channel_id.into(),
+            command: command.clone(),
+        });
+
+        let login = self.login()?;
+        let pty = self.ptys.lock().await.get(&channel_id).cloned();
+        if let Some(stream) = pty {
+            let pty_command = self
+                .session_command(channel_id, &login, SessionProgram::Command(command))
+                .await;
+            self.spawn_pty_command(channel_id, stream, login, pty_command, session.handle(), None);
+        } else if let Err(e) = self
+            .spawn_exec_command(channel_id, &login, &command, session.handle())
+            .await
+        {
+            error!("Failed to spawn command {}: {:?}", command, e);
+            session.channel_failure(channel_id);
+            return Ok(());
+        }
+
+        session.channel_success(channel_id);
         Ok(())
     }
 
@@ -949,7 +2308,25 @@ impl server::Handler for ServerSession {
         signal: Sig,
         session: &mut Session,
     ) -> Result<(), Self::Error> {
-        info!("Receiving signal!");
+        info!("Receiving signal {:?} for channel {:?}", signal, channel);
+
+        let Some(signum) = signum_from_sig(&signal) else {
+            warn!("Unsupported signal {:?}", signal);
+            return Ok(());
+        };
+
+        let pty = self.ptys.lock().await.get(&channel).cloned();
+        let res = if let Some(stream) = pty {
+            stream.signal(signum).await
+        } else if let Some(pgid) = self.exec_pgids.lock().await.get(&channel).copied() {
+            send_signal(-pgid, signum)
+        } else {
+            Ok(())
+        };
+
+        if let Err(e) = res {
+            error!("Failed to deliver signal {:?}: {:?}", signal, e);
+        }
         Ok(())
     }
 
@@ -960,6 +2337,11 @@ impl server::Handler for ServerSession {
         session: &mut Session,
     ) -> Result<(), Self::Error> {
         info!("Receiving channel close!");
+        self.detach_persistent(channel).await;
+        self.channel_env.lock().await.remove(&channel);
+        if let Some(stats) = self.channel_stats.lock().await.remove(&channel) {
+            self.audit.close_channel(channel, &stats);
+        }
         session.close(channel);
         Ok(())
     }
@@ -971,22 +2353,188 @@ impl server::Handler for ServerSession {
         port: &mut u32,
         session: &mut Session,
     ) -> Result<bool, Self::Error> {
-        info!("Received tcpip_forward");
-        let handle = session.handle();
-        let address = address.to_string();
-        let port = *port;
-        tokio::spawn(async move {
-            let channel = handle
-                .channel_open_forwarded_tcpip(address, port, "1.2.3.4", 1234)
-                .await
-                .unwrap();
-            let _ = channel.data(&b"Hello from a forwarded port"[..]).await;
-            let _ = channel.eof().await;
+        let Some(login) = self.login.clone() else {
+            return Ok(false);
+        };
+        info!("Received tcpip_forward {}:{} for {}", address, port, login.name);
+
+        let audit_request = |port: u32, accepted: bool| AuditEvent::TcpipForward {
+            address: address.to_string(),
+            port,
+            accepted,
+        };
+
+        if !self.ssh_config.enable_port_forwarding || self.key_options.no_port_forwarding {
+            warn!("Rejected tcpip_forward for {}: port forwarding is disabled", login.name);
+            self.audit.record(audit_request(*port, false));
+            return Ok(false);
+        }
+
+        // Like sshd, only root may listen on privileged ports
+        if *port != 0 && *port < 1024 && login.uid != 0 {
+            warn!("Refusing to forward privileged port {} for {}", port, login.name);
+            self.audit.record(audit_request(*port, false));
+            return Ok(false);
+        }
+
+        match self
+            .remote_forwards
+            .start(address, *port, self.ssh_config.gateway_ports, session.handle())
+            .await
+        {
+            Ok(bound_port) => {
+                *port = bound_port;
+                self.audit.record(audit_request(bound_port, true));
+                Ok(true)
+            }
+            Err(e) => {
+                error!("Failed to listen on {}:{}: {:?}", address, port, e);
+                self.audit.record(audit_request(*port, false));
+                Ok(false)
+            }
+        }
+    }
+
+    async fn cancel_tcpip_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        info!("Received cancel_tcpip_forward {}:{}", address, port);
+        self.audit.record(AuditEvent::CancelTcpipForward {
+            address: address.to_string(),
+            port,
         });
-        Ok(true)
+        Ok(self.remote_forwards.cancel(address, port).await)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh::server::Handler as _;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAABAgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4f";
 
+    /// Lets anyone in, for a live session handle and channel
+    struct AcceptAll;
 
+    impl server::Handler for AcceptAll {
+        type Error = anyhow::Error;
 
+        async fn auth_none(&mut self, _user: &str) -> Result<server::Auth, Self::Error> {
+            Ok(server::Auth::Accept)
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<Msg>,
+            _session: &mut Session,
+        ) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    struct TrustAll;
+
+    impl russh::client::Handler for TrustAll {
+        type Error = russh::Error;
+
+        async fn check_server_key(&mut self, _key: &PublicKey) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    fn test_pty() -> Arc<PtyStream> {
+        let pair = native_pty_system().openpty(PtySize::default()).unwrap();
+        Arc::new(PtyStream {
+            io: AsyncPty::new(&*pair.master).unwrap(),
+            master: Mutex::new(pair.master),
+            slave: Mutex::new(pair.slave),
+            leader_pid: AtomicI32::new(0),
+            term: "xterm".to_string(),
+            recorder: OnceLock::new(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_offered_key_is_not_banned() {
+        let ssh_config = SshConfig::default();
+        let ssh_server = Server {
+            auth_failures: Arc::new(AuthFailures::new(&ssh_config, Arc::new(AuditLog::default()))),
+            ..Default::default()
+        };
+        let remote: SocketAddr = "192.0.2.1:2222".parse().unwrap();
+        let audit = Arc::new(SessionAudit::new(ssh_server.audit.clone(), Some(remote)));
+        let mut session = ssh_server.session_handler(Some(remote), audit);
+
+        // A client with many keys offers the ones the user doesn't have first
+        let user = UserInfo::current().unwrap().name;
+        let key = PublicKey::from_openssh(KEY).unwrap();
+        for _ in 0..ssh_config.max_auth_failures * 2 {
+            let auth = session.auth_publickey_offered(&user, &key).await.unwrap();
+            assert!(matches!(auth, server::Auth::Reject { .. }));
+        }
+
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        assert!(ssh_server.auth_failures.banned(&Offender::Key(fingerprint)).is_none());
+        assert!(ssh_server.auth_failures.banned(&Offender::address(remote.ip())).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_session_detaches_persistent() {
+        let host_key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let config = Arc::new(server::Config {
+            keys: vec![host_key],
+            ..Default::default()
+        });
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let (running, client) = tokio::join!(
+            russh::server::run_stream(config, server_io, AcceptAll),
+            russh::client::connect_stream(Arc::new(Default::default()), client_io, TrustAll),
+        );
+        let (running, mut client) = (running.unwrap(), client.unwrap());
+        assert!(client.authenticate_none("sessio").await.unwrap().success());
+        let channel = client.channel_open_session().await.unwrap();
+        let channel_id = channel.id();
+
+        let sessions = PersistentSessions::default();
+        sessions.set_detached_timeout(1);
+        let (persistent, _) = sessions
+            .open("main".to_string(), "sessio", &test_pty(), 10)
+            .await
+            .unwrap();
+        let attachment = persistent.attach(running.handle(), channel_id, None).await;
+        let attached = Mutex::new(HashMap::from([(channel_id, (persistent, attachment))]));
+
+        // The connection goes away without the channel ever being closed
+        drop(channel);
+        drop(client);
+        let _ = time::timeout(Duration::from_secs(5), running).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert!(sessions.remove_detached().await.is_empty());
+
+        detach_all(&attached).await;
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(sessions.remove_detached().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_resize_connection_limit() {
+        let limit = Arc::new(Semaphore::new(2));
+        resize_connection_limit(&limit, 2, 4);
+        assert_eq!(limit.available_permits(), 4);
+
+        let held = limit.clone().try_acquire_many_owned(3).unwrap();
+        resize_connection_limit(&limit, 4, 1);
+        assert_eq!(limit.available_permits(), 0);
+
+        // The connections that end give back only what the new limit allows
+        drop(held);
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(limit.available_permits(), 1);
+    }
+}
@@ -1,36 +1,97 @@
 use homedir;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use russh_sftp::protocol::{
-    Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode, Version,
+    Attrs, Data, ExtendedReply, File, FileAttributes, Handle, Name, OpenFlags, Packet, Status,
+    StatusCode, Version,
 };
 use russh_sftp::server::Handler;
 use std::collections::HashMap;
-use std::path::PathBuf;
-use tokio::fs::metadata;
-use tokio::fs::{self, File as TokioFile, OpenOptions, ReadDir};
+use std::ffi::CStr;
+use std::fs::Metadata;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::process::Command as TokioCommand;
+use tokio::fs::{self, File as TokioFile, ReadDir};
 use tokio::io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 
+use crate::config_manager::{SftpConfig, SftpRoot};
+use crate::process::run_as;
+use crate::sftp_extensions::{self, CheckFile, CopyData, HandleRequest, PathPair, PathRequest};
+use crate::sftp_jail::Jail;
+use crate::subsystem::{Subsystem, SubsystemIo};
+use crate::user::{group_name, user_name, UserInfo};
+
+/// Entries per READDIR response, like OpenSSH's sftp-server, so that
+/// huge directories don't produce huge packets
+const READDIR_BATCH: usize = 100;
+
+/// Largest READ response, like OpenSSH's sftp-server, so that a client can't
+/// make the server allocate whatever length it asks for
+const MAX_READ_LEN: u32 = 256 * 1024;
+
 pub struct SftpSession {
     version: Option<u32>,
     root_dir_read_done: bool,
     open_directories: HashMap<String, OpenDir>,
     open_files: HashMap<String, TokioFile>,
-    user: String,
+    /// Number of the next handle, handles are never reused within a session
+    next_handle: u64,
+    /// Files and directories the session may have open at once
+    max_handles: usize,
+    jail: Jail,
+    read_only: bool,
+    owners: OwnerNames,
 }
 
 struct OpenDir {
     dir: ReadDir,
-    read: bool,
+}
+
+/// Names of the file owners, looked up once per session
+#[derive(Default)]
+struct OwnerNames {
+    users: HashMap<u32, String>,
+    groups: HashMap<u32, String>,
+}
+
+impl OwnerNames {
+    /// Attributes of a file with the names of its owners, numeric IDs stand
+    /// in for owners without a name
+    fn attributes(&mut self, metadata: &Metadata) -> FileAttributes {
+        let mut attrs = file_attributes(metadata);
+        let uid = metadata.uid();
+        let gid = metadata.gid();
+        let user = self.users.entry(uid).or_insert_with(|| {
+            user_name(uid).ok().flatten().unwrap_or_else(|| uid.to_string())
+        });
+        attrs.user = Some(user.clone());
+        let group = self.groups.entry(gid).or_insert_with(|| {
+            group_name(gid).ok().flatten().unwrap_or_else(|| gid.to_string())
+        });
+        attrs.group = Some(group.clone());
+        attrs
+    }
 }
 
 impl SftpSession {
-    pub fn new(user: String) -> Self {
+    pub fn new(jail: Jail, read_only: bool, max_handles: u32) -> Self {
         SftpSession {
             version: None,
             root_dir_read_done: false,
             open_directories: HashMap::new(),
             open_files: HashMap::new(),
-            user,
+            next_handle: 0,
+            max_handles: max_handles as usize,
+            jail,
+            read_only,
+            owners: OwnerNames::default(),
         }
     }
     fn success(id: u32) -> Status {
@@ -42,13 +103,436 @@ impl SftpSession {
         }
     }
 
-    fn get_user_relative_path(&mut self, filename: &String) -> Result<PathBuf, StatusCode> {
-        let path = homedir::home(&self.user)
-            .map_err(|e| StatusCode::Failure)?
-            .ok_or(StatusCode::NoSuchFile)?
-            .join(filename);
-        Ok(path)
+    /// The real path of a client path, see `Jail::resolve`
+    async fn resolve(&self, path: &str, follow: bool) -> Result<PathBuf, StatusCode> {
+        self.jail
+            .resolve(path, follow)
+            .await
+            .map_err(|e| fs_error("resolve", Path::new(path), e))
+    }
+
+    /// Runs a blocking call on the jail, failing with the status of its error
+    async fn blocking<T, F>(&self, operation: &str, path: &Path, call: F) -> Result<T, StatusCode>
+    where
+        F: FnOnce(&Jail) -> io::Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let jail = self.jail.clone();
+        tokio::task::spawn_blocking(move || call(&jail))
+            .await
+            .map_err(|_| StatusCode::Failure)?
+            .map_err(|e| fs_error(operation, path, e))
+    }
+
+    /// Fails operations that change files in a read-only session
+    fn check_writable(&self) -> Result<(), StatusCode> {
+        if self.read_only {
+            return Err(StatusCode::PermissionDenied);
+        }
+        Ok(())
+    }
+
+    fn open_file(&mut self, handle: &str) -> Result<&mut TokioFile, StatusCode> {
+        self.open_files.get_mut(handle).ok_or(StatusCode::Failure)
+    }
+
+    /// Fails opening another file or directory once the session has
+    /// `max_handles` open
+    fn check_handle_limit(&self) -> Result<(), StatusCode> {
+        if self.open_files.len() + self.open_directories.len() >= self.max_handles {
+            warn!("SFTP session reached its limit of {} open handles", self.max_handles);
+            return Err(StatusCode::Failure);
+        }
+        Ok(())
+    }
+
+    /// A handle no other open file or directory of the session has
+    fn new_handle(&mut self) -> String {
+        let handle = format!("{:x}", self.next_handle);
+        self.next_handle += 1;
+        handle
+    }
+}
+
+impl Drop for SftpSession {
+    /// The session ends with its channel, close what the client left open
+    fn drop(&mut self) {
+        if !self.open_files.is_empty() || !self.open_directories.is_empty() {
+            debug!(
+                "Closing {} files and {} directories left open by the SFTP client",
+                self.open_files.len(),
+                self.open_directories.len()
+            );
+        }
+        self.open_files.clear();
+        self.open_directories.clear();
+    }
+}
+
+/// The SFTP status of a failed file system operation, mapped like OpenSSH's sftp-server
+fn io_status(e: &io::Error) -> StatusCode {
+    match e.raw_os_error() {
+        Some(libc::ENOENT | libc::ENOTDIR | libc::EBADF | libc::ELOOP) => StatusCode::NoSuchFile,
+        Some(libc::EPERM | libc::EACCES | libc::EFAULT | libc::EROFS) => StatusCode::PermissionDenied,
+        Some(libc::ENAMETOOLONG | libc::EINVAL) => StatusCode::BadMessage,
+        Some(libc::ENOSYS | libc::EOPNOTSUPP) => StatusCode::OpUnsupported,
+        Some(_) => StatusCode::Failure,
+        None => match e.kind() {
+            io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+            io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => StatusCode::BadMessage,
+            io::ErrorKind::Unsupported => StatusCode::OpUnsupported,
+            _ => StatusCode::Failure,
+        },
+    }
+}
+
+/// Logs a failed operation on `path` and returns its SFTP status
+fn fs_error(operation: &str, path: &Path, e: io::Error) -> StatusCode {
+    let status = io_status(&e);
+    debug!("{} {} failed: {} ({:?})", operation, path.display(), e, status);
+    status
+}
+
+/// Attributes of a file as sent in ATTRS and NAME responses
+fn file_attributes(metadata: &Metadata) -> FileAttributes {
+    FileAttributes {
+        size: Some(metadata.size()),
+        uid: Some(metadata.uid()),
+        user: None,
+        gid: Some(metadata.gid()),
+        group: None,
+        // Includes the file type bits
+        permissions: Some(metadata.mode()),
+        atime: Some(metadata.atime() as u32),
+        mtime: Some(metadata.mtime() as u32),
+    }
+}
+
+/// The `ls -l` line of a directory entry, formatted like OpenSSH's sftp-server
+fn longname(filename: &str, metadata: &Metadata, attrs: &FileAttributes, now: i64) -> String {
+    format!(
+        "{} {:>3} {:<8} {:<8} {:>8} {} {}",
+        mode_string(metadata.mode()),
+        metadata.nlink(),
+        attrs.user.as_deref().unwrap_or_default(),
+        attrs.group.as_deref().unwrap_or_default(),
+        metadata.size(),
+        format_mtime(metadata.mtime(), now),
+        filename
+    )
+}
+
+/// The type and permissions of a mode as `ls -l` shows them, e.g. `drwxr-xr-x`
+fn mode_string(mode: u32) -> String {
+    let file_type = match mode & libc::S_IFMT {
+        libc::S_IFDIR => 'd',
+        libc::S_IFLNK => 'l',
+        libc::S_IFCHR => 'c',
+        libc::S_IFBLK => 'b',
+        libc::S_IFIFO => 'p',
+        libc::S_IFSOCK => 's',
+        libc::S_IFREG => '-',
+        _ => '?',
+    };
+    // Execute bits shown as s or t, in capitals without execute permission
+    let execute = |shift: u32, special: u32, letter: char| {
+        match (mode >> shift & 0o1 != 0, mode & special != 0) {
+            (true, true) => letter,
+            (false, true) => letter.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        }
+    };
+    let bit = |shift: u32, letter: char| if mode >> shift & 0o1 != 0 { letter } else { '-' };
+
+    [
+        file_type,
+        bit(8, 'r'),
+        bit(7, 'w'),
+        execute(6, libc::S_ISUID, 's'),
+        bit(5, 'r'),
+        bit(4, 'w'),
+        execute(3, libc::S_ISGID, 's'),
+        bit(2, 'r'),
+        bit(1, 'w'),
+        execute(0, libc::S_ISVTX, 't'),
+    ]
+    .iter()
+    .collect()
+}
+
+/// A modification time as `ls -l` shows it in the local time zone: with the
+/// time of day within half a year of `now`, with the year otherwise
+fn format_mtime(mtime: i64, now: i64) -> String {
+    const HALF_YEAR: i64 = 365 * 24 * 60 * 60 / 2;
+    let format = if (now - HALF_YEAR..now + HALF_YEAR).contains(&mtime) {
+        c"%b %e %H:%M"
+    } else {
+        c"%b %e  %Y"
+    };
+
+    let time = mtime as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return "?".to_string();
+        }
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// What SETSTAT and FSETSTAT change
+#[derive(Clone, Copy)]
+enum AttrTarget<'a> {
+    /// A file in an open directory, see `Jail::open_parent`
+    At(RawFd, &'a CStr),
+    Fd(RawFd),
+}
+
+fn check_errno(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Applies the attributes present in `attrs`: size, owner, permissions and times.
+/// A symlink at `AttrTarget::At` is not followed, it fails the call instead.
+fn set_attributes(target: AttrTarget, attrs: &FileAttributes) -> io::Result<()> {
+    if let Some(size) = attrs.size {
+        let size = libc::off_t::try_from(size).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        match target {
+            AttrTarget::At(dir, name) => {
+                // Non-blocking, a FIFO without a reader would never open
+                let flags = libc::O_WRONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC;
+                let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+                check_errno(fd)?;
+                let file = unsafe { std::fs::File::from_raw_fd(fd) };
+                check_errno(unsafe { libc::ftruncate(file.as_raw_fd(), size) })?;
+            }
+            AttrTarget::Fd(fd) => check_errno(unsafe { libc::ftruncate(fd, size) })?,
+        }
     }
+
+    if attrs.uid.is_some() || attrs.gid.is_some() {
+        // -1 leaves the ID unchanged
+        let uid = attrs.uid.unwrap_or(u32::MAX);
+        let gid = attrs.gid.unwrap_or(u32::MAX);
+        check_errno(match target {
+            AttrTarget::At(dir, name) => unsafe {
+                libc::fchownat(dir, name.as_ptr(), uid, gid, libc::AT_SYMLINK_NOFOLLOW)
+            },
+            AttrTarget::Fd(fd) => unsafe { libc::fchown(fd, uid, gid) },
+        })?;
+    }
+
+    if let Some(permissions) = attrs.permissions {
+        // The file type bits can't be changed
+        let mode = (permissions & 0o7777) as libc::mode_t;
+        match target {
+            AttrTarget::At(dir, name) => chmod_at(dir, name, mode)?,
+            AttrTarget::Fd(fd) => check_errno(unsafe { libc::fchmod(fd, mode) })?,
+        }
+    }
+
+    if attrs.atime.is_some() || attrs.mtime.is_some() {
+        let time = |secs: Option<u32>| libc::timespec {
+            tv_sec: secs.map_or(0, |secs| secs as libc::time_t),
+            tv_nsec: if secs.is_some() { 0 } else { libc::UTIME_OMIT },
+        };
+        let times = [time(attrs.atime), time(attrs.mtime)];
+        check_errno(match target {
+            AttrTarget::At(dir, name) => unsafe {
+                libc::utimensat(dir, name.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW)
+            },
+            AttrTarget::Fd(fd) => unsafe { libc::futimens(fd, times.as_ptr()) },
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Changes the permissions of `name` in `dir` without following a symlink.
+/// C libraries that can't do that fail with EOPNOTSUPP for every file, then
+/// the file is checked not to be a symlink right before.
+fn chmod_at(dir: RawFd, name: &CStr, mode: libc::mode_t) -> io::Result<()> {
+    let ret = unsafe { libc::fchmodat(dir, name.as_ptr(), mode, libc::AT_SYMLINK_NOFOLLOW) };
+    match check_errno(ret) {
+        Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+            let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+            check_errno(unsafe {
+                libc::fstatat(dir, name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW)
+            })?;
+            if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+                return Err(e);
+            }
+            check_errno(unsafe { libc::fchmodat(dir, name.as_ptr(), mode, 0) })
+        }
+        res => res,
+    }
+}
+
+/// open(2) flags of an SFTP open, like russh_sftp's conversion to `OpenOptions`.
+/// Without READ or WRITE the file is opened for reading.
+fn open_flags(pflags: OpenFlags) -> libc::c_int {
+    let write = pflags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+    let mut flags = match (pflags.contains(OpenFlags::READ), write) {
+        (true, true) => libc::O_RDWR,
+        (false, true) => libc::O_WRONLY,
+        (_, false) => libc::O_RDONLY,
+    };
+    if pflags.contains(OpenFlags::APPEND) {
+        flags |= libc::O_APPEND;
+    }
+    if pflags.contains(OpenFlags::CREATE) {
+        flags |= libc::O_CREAT;
+        if pflags.contains(OpenFlags::EXCLUDE) {
+            flags |= libc::O_EXCL;
+        }
+    }
+    if pflags.contains(OpenFlags::TRUNCATE) {
+        flags |= libc::O_TRUNC;
+    }
+    flags
+}
+
+/// What an SFTP session may access
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SftpAccess {
+    pub root: SftpRoot,
+    pub read_only: bool,
+    /// Files and directories the session may have open at once
+    pub max_handles: u32,
+}
+
+impl SftpAccess {
+    /// The directory the session is confined to, None if it isn't
+    fn root_dir(&self, login: &UserInfo) -> Option<PathBuf> {
+        match &self.root {
+            SftpRoot::Home => Some(login.home.clone()),
+            SftpRoot::Directory(path) => Some(path.clone()),
+            SftpRoot::Unrestricted => None,
+        }
+    }
+}
+
+impl SftpConfig {
+    /// The access of a login user with a key. The key's root takes precedence
+    /// over the user's, and either being read-only makes the session read-only.
+    pub fn access(&self, login: &str, fingerprint: &str) -> SftpAccess {
+        let root = self
+            .key_roots
+            .get(fingerprint)
+            .or_else(|| self.user_roots.get(login))
+            .unwrap_or(&self.root)
+            .clone();
+        let read_only = self.read_only
+            || self.read_only_users.iter().any(|user| user == "*" || user == login)
+            || self.read_only_keys.iter().any(|key| key == fingerprint);
+        SftpAccess {
+            root,
+            read_only,
+            max_handles: self.max_open_handles,
+        }
+    }
+}
+
+/// The built-in `sftp` subsystem
+pub struct SftpSubsystem {
+    config: SftpConfig,
+}
+
+impl SftpSubsystem {
+    pub fn new(config: SftpConfig) -> Self {
+        SftpSubsystem { config }
+    }
+}
+
+#[async_trait::async_trait]
+impl Subsystem for SftpSubsystem {
+    async fn start(&self, login: &Arc<UserInfo>, fingerprint: &str) -> anyhow::Result<SubsystemIo> {
+        let access = self.config.access(&login.name, fingerprint);
+        let root = access.root_dir(login);
+        info!(
+            "SFTP for {} in {}{}",
+            login.name,
+            root.as_deref().unwrap_or(Path::new("/")).display(),
+            if access.read_only { ", read-only" } else { "" }
+        );
+
+        if login.needs_switch() {
+            // Serve SFTP from a helper process running as the login user,
+            // so file access goes through the user's own permissions
+            let mut child = spawn_sftp_helper(login, root.as_deref(), &access)?;
+            let stdin = child.stdin.take().context("Helper stdin not captured")?;
+            let stdout = child.stdout.take().context("Helper stdout not captured")?;
+            return Ok(SubsystemIo::process(io::join(stdout, stdin), child));
+        }
+
+        let jail = session_jail(root.as_deref(), login.home.clone())?;
+        let (local, remote) = io::duplex(64 * 1024);
+        let session = SftpSession::new(jail, access.read_only, access.max_handles);
+        russh_sftp::server::run(remote, session).await;
+        Ok(SubsystemIo::stream(local))
+    }
+}
+
+/// The jail of a session confined to `root`, or of an unrestricted one starting at `home`
+fn session_jail(root: Option<&Path>, home: PathBuf) -> anyhow::Result<Jail> {
+    match root {
+        Some(root) => Jail::new(root).with_context(|| format!("Invalid SFTP root {}", root.display())),
+        None => Ok(Jail::unrestricted(home)),
+    }
+}
+
+/// Starts `sessio-server sftp-server` as the login user with its stdio piped
+fn spawn_sftp_helper(
+    login: &UserInfo,
+    root: Option<&Path>,
+    access: &SftpAccess,
+) -> anyhow::Result<tokio::process::Child> {
+    let exe = std::env::current_exe().context("Failed to locate the server executable")?;
+
+    let mut command = Command::new(exe);
+    command.arg("sftp-server").arg("--user").arg(&login.name);
+    if let Some(root) = root {
+        command.arg("--root").arg(root);
+    }
+    if access.read_only {
+        command.arg("--read-only");
+    }
+    command.arg("--max-handles").arg(access.max_handles.to_string());
+    command
+        .env_clear()
+        .envs(login.login_env())
+        .current_dir(if login.home.is_dir() { login.home.as_path() } else { Path::new("/") })
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    run_as(&mut command, login);
+
+    Ok(TokioCommand::from(command).kill_on_drop(true).spawn()?)
+}
+
+/// Serves SFTP over stdin/stdout. The server spawns this in a separate process
+/// when the subsystem has to run as a different user than the server itself.
+pub async fn serve_stdio(
+    user: String,
+    root: Option<PathBuf>,
+    read_only: bool,
+    max_handles: u32,
+) -> anyhow::Result<()> {
+    let home = homedir::home(&user)?.context("Home directory not found")?;
+    let jail = session_jail(root.as_deref(), home)?;
+    let (mut local, remote) = io::duplex(64 * 1024);
+    russh_sftp::server::run(remote, SftpSession::new(jail, read_only, max_handles)).await;
+
+    let mut stdio = io::join(io::stdin(), io::stdout());
+    io::copy_bidirectional(&mut stdio, &mut local).await?;
+    Ok(())
 }
 
 impl Handler for SftpSession {
@@ -70,36 +554,43 @@ impl Handler for SftpSession {
 
         self.version = Some(version);
         info!("version: {:?}, extensions: {:?}", self.version, extensions);
-        Ok(Version::new())
+        let mut reply = Version::new();
+        reply.extensions = sftp_extensions::SUPPORTED
+            .iter()
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect();
+        Ok(reply)
     }
 
     async fn open(
         &mut self,
         id: u32,
         filename: String,
-        _pflags: OpenFlags,
-        _attrs: FileAttributes,
+        pflags: OpenFlags,
+        attrs: FileAttributes,
     ) -> Result<Handle, Self::Error> {
-        info!("OPENING FILE {}", filename);
+        debug!("open: {id} {filename} {:?}", pflags);
 
-        let path = self.get_user_relative_path(&filename)?;
-
-        info!("FINAL PATH {}", path.display());
+        let writes = OpenFlags::WRITE | OpenFlags::APPEND | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        if pflags.intersects(writes) {
+            self.check_writable()?;
+        }
+        self.check_handle_limit()?;
+        let path = self.resolve(&filename, true).await?;
 
-        let file = OpenOptions::new()
-            .read(true)
-            .create(true)
-            .write(true)
-            .open(path)
-            .await
-            .map_err(|_e| StatusCode::NoSuchFile)?;
+        // Mode of a newly created file, before the umask
+        let mode = attrs.permissions.map_or(0o666, |permissions| permissions & 0o7777);
+        let target = path.clone();
+        let file = self
+            .blocking("open", &path, move |jail| {
+                jail.open(&target, open_flags(pflags), mode as libc::mode_t)
+            })
+            .await?;
 
-        self.open_files.insert(filename.clone(), file);
+        let handle = self.new_handle();
+        self.open_files.insert(handle.clone(), TokioFile::from_std(file));
 
-        Ok(Handle {
-            id,
-            handle: filename,
-        })
+        Ok(Handle { id, handle })
     }
 
     async fn read(
@@ -109,49 +600,122 @@ impl Handler for SftpSession {
         offset: u64,
         len: u32,
     ) -> Result<Data, Self::Error> {
-        if let Some(file) = self.open_files.get_mut(&handle) {
-            file.seek(std::io::SeekFrom::Start(offset))
-                .await
-                .map_err(|_| StatusCode::Failure)?;
-            let mut buffer = vec![0; len as usize];
-            let n = file
-                .read(&mut buffer)
-                .await
-                .map_err(|_| StatusCode::Failure)?;
-            buffer.truncate(n);
-
-            return Ok(Data { id, data: buffer });
+        let file = self.open_file(&handle)?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(|e| io_status(&e))?;
+        let mut buffer = vec![0; len.min(MAX_READ_LEN) as usize];
+        let n = file.read(&mut buffer).await.map_err(|e| io_status(&e))?;
+        if n == 0 && len > 0 {
+            return Err(StatusCode::Eof);
         }
-        Err(StatusCode::Failure)
+        buffer.truncate(n);
+
+        Ok(Data { id, data: buffer })
     }
 
     async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
-        self.open_directories.remove(&handle);
-
-        if let Some(file) = self.open_files.remove(&handle) {
-            drop(file);
+        if self.open_directories.remove(&handle).is_some() {
+            return Ok(SftpSession::success(id));
         }
 
-        Ok(Status {
+        let Some(mut file) = self.open_files.remove(&handle) else {
+            return Err(StatusCode::Failure);
+        };
+        // Report write errors the kernel only returns on flush
+        file.flush().await.map_err(|e| io_status(&e))?;
+
+        Ok(SftpSession::success(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        debug!("lstat: {id} {path}");
+
+        let path = self.resolve(&path, false).await?;
+        let metadata = fs::symlink_metadata(&path)
+            .await
+            .map_err(|e| fs_error("lstat", &path, e))?;
+
+        Ok(Attrs {
             id,
-            status_code: StatusCode::Ok,
-            error_message: "Ok".to_string(),
-            language_tag: "en-US".to_string(),
+            attrs: self.owners.attributes(&metadata),
         })
     }
 
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let file = self.open_file(&handle)?;
+        let metadata = file.metadata().await.map_err(|e| io_status(&e))?;
+
+        Ok(Attrs {
+            id,
+            attrs: self.owners.attributes(&metadata),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        debug!("setstat: {id} {path} {:?}", attrs);
+
+        self.check_writable()?;
+        let path = self.resolve(&path, true).await?;
+        let target = path.clone();
+        self.blocking("setstat", &path, move |jail| {
+            let (dir, name) = jail.open_parent(&target)?;
+            set_attributes(AttrTarget::At(dir.as_raw_fd(), &name), &attrs)
+        })
+        .await?;
+
+        Ok(SftpSession::success(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        debug!("fsetstat: {id} {handle} {:?}", attrs);
+
+        self.check_writable()?;
+        let file = self.open_file(&handle)?;
+        // Pending writes must land before a truncate
+        file.flush().await.map_err(|e| io_status(&e))?;
+        set_attributes(AttrTarget::Fd(file.as_raw_fd()), &attrs).map_err(|e| io_status(&e))?;
+
+        Ok(SftpSession::success(id))
+    }
+
     async fn remove(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
         debug!("remove: {id} {path}");
 
-        let path = self.get_user_relative_path(&path)?;
+        self.check_writable()?;
+        let path = self.resolve(&path, false).await?;
 
-        // Try to remove as a file
-        if let Err(e) = fs::remove_file(&path).await {
-            if e.kind() == io::ErrorKind::NotFound {
-                error!("File not found: {}", path.display());
-                return Err(StatusCode::NoSuchFile);
-            }
-        }
+        let target = path.clone();
+        self.blocking("remove", &path, move |jail| jail.remove_file(&target)).await?;
+
+        Ok(SftpSession::success(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        debug!("mkdir: {id} {path}");
+
+        self.check_writable()?;
+        let path = self.resolve(&path, false).await?;
+
+        let mode = attrs.permissions.map_or(0o777, |permissions| permissions & 0o7777);
+        let target = path.clone();
+        self.blocking("mkdir", &path, move |jail| jail.mkdir(&target, mode as libc::mode_t))
+            .await?;
 
         Ok(SftpSession::success(id))
     }
@@ -159,19 +723,13 @@ impl Handler for SftpSession {
     async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
         debug!("rmdir: {id} {path}");
 
-        let path = self.get_user_relative_path(&path)?;
+        self.check_writable()?;
+        let path = self.resolve(&path, false).await?;
 
-        match fs::remove_dir(&path).await {
-            Ok(_) => Ok(SftpSession::success(id)),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                error!("Directory not found: {}", path.display());
-                Err(StatusCode::NoSuchFile)
-            }
-            Err(e) => {
-                error!("Failed to remove directory {}: {:?}", path.display(), e);
-                Err(StatusCode::Failure)
-            }
-        }
+        let target = path.clone();
+        self.blocking("rmdir", &path, move |jail| jail.remove_dir(&target)).await?;
+
+        Ok(SftpSession::success(id))
     }
 
     async fn rename(
@@ -182,29 +740,20 @@ impl Handler for SftpSession {
     ) -> Result<Status, Self::Error> {
         debug!("rename: {id} from {oldpath} to {newpath}");
 
-        let oldpath = self.get_user_relative_path(&oldpath)?;
-        let newpath = self.get_user_relative_path(&newpath)?;
+        self.check_writable()?;
+        let oldpath = self.resolve(&oldpath, false).await?;
+        let newpath = self.resolve(&newpath, false).await?;
 
-        match fs::rename(&oldpath, &newpath).await {
-            Ok(_) => Ok(SftpSession::success(id)),
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                error!("File or directory not found: {}", oldpath.display());
-                Err(StatusCode::NoSuchFile)
-            }
-            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
-                error!("Destination already exists: {}", newpath.display());
-                Err(StatusCode::Failure)
-            }
-            Err(e) => {
-                error!(
-                    "Failed to rename from {} to {}: {:?}",
-                    oldpath.display(),
-                    newpath.display(),
-                    e
-                );
-                Err(StatusCode::Failure)
-            }
+        // SFTP v3 renames never replace an existing file
+        if fs::symlink_metadata(&newpath).await.is_ok() {
+            debug!("rename destination {} already exists", newpath.display());
+            return Err(StatusCode::Failure);
         }
+
+        let from = oldpath.clone();
+        self.blocking("rename", &oldpath, move |jail| jail.rename(&from, &newpath)).await?;
+
+        Ok(SftpSession::success(id))
     }
 
     async fn write(
@@ -214,114 +763,83 @@ impl Handler for SftpSession {
         offset: u64,
         data: Vec<u8>,
     ) -> Result<Status, Self::Error> {
-        if let Some(file) = self.open_files.get_mut(&handle) {
-            file.seek(std::io::SeekFrom::Start(offset))
-                .await
-                .map_err(|_| StatusCode::Failure)?;
-            file.write_all(&data)
-                .await
-                .map_err(|_| StatusCode::Failure)?;
-
-            return Ok(Status {
-                id,
-                status_code: StatusCode::Ok,
-                error_message: "Ok".to_string(),
-                language_tag: "en-US".to_string(),
-            });
-        }
-        Err(StatusCode::Failure)
+        self.check_writable()?;
+        let file = self.open_file(&handle)?;
+        file.seek(std::io::SeekFrom::Start(offset))
+            .await
+            .map_err(|e| io_status(&e))?;
+        file.write_all(&data).await.map_err(|e| io_status(&e))?;
+
+        Ok(SftpSession::success(id))
     }
 
     async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
         info!("opendir: {}", path);
 
-        let cleaned_path = if path.starts_with('/') {
-            &path[1..]
-        } else {
-            &path
-        };
-
-        let path_full = homedir::home(&self.user)
-            .map_err(|e| StatusCode::Failure)?
-            .ok_or(StatusCode::NoSuchFile)?
-            .join(cleaned_path);
+        self.check_handle_limit()?;
+        let path_full = self.resolve(&path, true).await?;
 
         info!("Opening dir {}", path_full.display());
 
         let read_dir = fs::read_dir(&path_full)
             .await
-            .map_err(|_| StatusCode::Failure)?;
+            .map_err(|e| fs_error("opendir", &path_full, e))?;
 
-        self.open_directories.insert(
-            path.clone(),
-            OpenDir {
-                dir: read_dir,
-                read: false,
-            },
-        );
 
-        Ok(Handle { id, handle: path })
+        let handle = self.new_handle();
+        self.open_directories.insert(handle.clone(), OpenDir { dir: read_dir });
+
+        Ok(Handle { id, handle })
     }
 
     async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
-        info!("readdir handle: {}", handle);
-
-        if let Some(mut read_dir) = self.open_directories.get_mut(&handle) {
-            let mut files = Vec::new();
-            if !read_dir.read {
-                while let Some(entry) = read_dir.dir.next_entry().await.map_err(|e| {
-                    error!("{}", e.to_string());
-                    StatusCode::Failure
-                })? {
-                    let file_name = entry.file_name().into_string().unwrap();
-                    let file_path = entry.path();
-                    info!("path {}", file_path.display());
-
-                    // Get file metadata
-                    let Ok(metadata) = metadata(&file_path).await else {
-                        continue;
-                    };
-
-                    let mut file_attrs = FileAttributes {
-                        size: Some(metadata.len()),
-                        uid: None, // Setting these to None as placeholders
-                        user: None,
-                        gid: None,
-                        group: None,
-                        mtime: None,
-                        atime: None,
-                        permissions: None,
-                    };
-
-                    file_attrs.set_dir(metadata.is_dir());
-                    file_attrs.set_regular(metadata.is_file());
-                    file_attrs.set_symlink(metadata.is_symlink());
-
-                    files.push(File {
-                        filename: file_name.clone(),
-                        longname: file_name.clone(),
-                        attrs: file_attrs,
-                    });
-                }
-                read_dir.read = true;
-                info!("Returned files: {}", files.len());
-                return Ok(Name { id, files });
-            } else {
-                return Err(StatusCode::Eof);
-            }
+        debug!("readdir: {id} {handle}");
+
+        let open_dir = self
+            .open_directories
+            .get_mut(&handle)
+            .ok_or(StatusCode::Failure)?;
+        let mut entries = Vec::new();
+        while entries.len() < READDIR_BATCH {
+            let Some(entry) = open_dir.dir.next_entry().await.map_err(|e| io_status(&e))? else {
+                break;
+            };
+            // Not following links, their targets may be outside the jail
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            entries.push((entry.file_name().to_string_lossy().into_owned(), metadata));
         }
-        Err(StatusCode::Eof)
+        if entries.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs() as i64);
+        let files = entries
+            .into_iter()
+            .map(|(filename, metadata)| {
+                let attrs = self.owners.attributes(&metadata);
+                File {
+                    longname: longname(&filename, &metadata, &attrs, now),
+                    filename,
+                    attrs,
+                }
+            })
+            .collect();
+
+        Ok(Name { id, files })
     }
 
     async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
         info!("realpath: {}", path);
 
-        let path_full = self.get_user_relative_path(&path)?;
-
-        let canonical_path = fs::canonicalize(&path_full)
-            .await
-            .map_err(|_| StatusCode::Failure)?;
-        let canonical_path_str = canonical_path
+        // Missing components are kept, the path may name a file about to be created
+        let path_full = self.resolve(&path, true).await?;
+        let canonical_path_str = self
+            .jail
+            .client_path(&path_full)
             .to_str()
             .ok_or(StatusCode::Failure)?
             .to_string();
@@ -335,4 +853,617 @@ impl Handler for SftpSession {
             }],
         })
     }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        debug!("stat: {id} {path}");
+
+        let path = self.resolve(&path, true).await?;
+        let metadata = fs::metadata(&path)
+            .await
+            .map_err(|e| fs_error("stat", &path, e))?;
+
+        Ok(Attrs {
+            id,
+            attrs: self.owners.attributes(&metadata),
+        })
+    }
+
+    async fn readlink(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        debug!("readlink: {id} {path}");
+
+        let path = self.resolve(&path, false).await?;
+        let target = fs::read_link(&path)
+            .await
+            .map_err(|e| fs_error("readlink", &path, e))?;
+        let target = target.to_str().ok_or(StatusCode::Failure)?.to_string();
+
+        Ok(Name {
+            id,
+            files: vec![File {
+                filename: target.clone(),
+                longname: target,
+                attrs: FileAttributes::empty(),
+            }],
+        })
+    }
+
+    /// OpenSSH sends the arguments of SSH_FXP_SYMLINK in the opposite order of
+    /// the draft, and every client follows it: `linkpath` is what the new link
+    /// points to and `targetpath` is where the link is created.
+    async fn symlink(
+        &mut self,
+        id: u32,
+        linkpath: String,
+        targetpath: String,
+    ) -> Result<Status, Self::Error> {
+        debug!("symlink: {id} {targetpath} -> {linkpath}");
+
+        self.check_writable()?;
+        // The link content is stored as sent. Inside a jail it's resolved
+        // relative to the jail's root, like any other link.
+        let link = self.resolve(&targetpath, false).await?;
+        let target = link.clone();
+        self.blocking("symlink", &link, move |jail| jail.symlink(&linkpath, &target)).await?;
+
+        Ok(SftpSession::success(id))
+    }
+
+    async fn extended(
+        &mut self,
+        id: u32,
+        request: String,
+        data: Vec<u8>,
+    ) -> Result<Packet, Self::Error> {
+        debug!("extended: {id} {request}");
+
+        match request.as_str() {
+            sftp_extensions::POSIX_RENAME => self.posix_rename(id, data).await,
+            sftp_extensions::HARDLINK => self.hardlink(id, data).await,
+            sftp_extensions::STATVFS => self.statvfs(id, data).await,
+            sftp_extensions::FSTATVFS => self.fstatvfs(id, data),
+            sftp_extensions::FSYNC => self.fsync(id, data).await,
+            sftp_extensions::COPY_DATA => self.copy_data(id, data).await,
+            sftp_extensions::CHECK_FILE_HANDLE => self.check_file_handle(id, data).await,
+            sftp_extensions::CHECK_FILE_NAME => self.check_file_name(id, data).await,
+            _ => Err(StatusCode::OpUnsupported),
+        }
+    }
+}
+
+/// Extension requests, see `sftp_extensions`
+impl SftpSession {
+    /// Renames like rename(2), replacing an existing destination atomically
+    async fn posix_rename(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: PathPair = sftp_extensions::parse(data)?;
+        debug!(
+            "posix-rename: {id} from {} to {}",
+            request.oldpath, request.newpath
+        );
+
+        self.check_writable()?;
+        let oldpath = self.resolve(&request.oldpath, false).await?;
+        let newpath = self.resolve(&request.newpath, false).await?;
+        let from = oldpath.clone();
+        self.blocking("posix-rename", &oldpath, move |jail| jail.rename(&from, &newpath)).await?;
+
+        Ok(SftpSession::success(id).into())
+    }
+
+    async fn hardlink(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: PathPair = sftp_extensions::parse(data)?;
+        debug!("hardlink: {id} {} -> {}", request.newpath, request.oldpath);
+
+        self.check_writable()?;
+        let oldpath = self.resolve(&request.oldpath, false).await?;
+        let newpath = self.resolve(&request.newpath, false).await?;
+        let link = newpath.clone();
+        self.blocking("hardlink", &newpath, move |jail| jail.hard_link(&oldpath, &link)).await?;
+
+        Ok(SftpSession::success(id).into())
+    }
+
+    async fn statvfs(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: PathRequest = sftp_extensions::parse(data)?;
+        debug!("statvfs: {id} {}", request.path);
+
+        let path = self.resolve(&request.path, true).await?;
+        let target = path.clone();
+        let stats = tokio::task::spawn_blocking(move || sftp_extensions::statvfs(&target))
+            .await
+            .map_err(|_| StatusCode::Failure)?
+            .map_err(|e| fs_error("statvfs", &path, e))?;
+        self.statvfs_reply(id, stats)
+    }
+
+    fn fstatvfs(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: HandleRequest = sftp_extensions::parse(data)?;
+        debug!("fstatvfs: {id} {}", request.handle);
+
+        let file = self.open_file(&request.handle)?;
+        let stats = sftp_extensions::fstatvfs(file).map_err(|e| io_status(&e))?;
+        self.statvfs_reply(id, stats)
+    }
+
+    fn statvfs_reply(
+        &self,
+        id: u32,
+        mut stats: russh_sftp::extensions::Statvfs,
+    ) -> Result<Packet, StatusCode> {
+        if self.read_only {
+            sftp_extensions::set_read_only(&mut stats);
+        }
+        let data = sftp_extensions::encode(&stats)?;
+        Ok(ExtendedReply { id, data }.into())
+    }
+
+    async fn fsync(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: HandleRequest = sftp_extensions::parse(data)?;
+        debug!("fsync: {id} {}", request.handle);
+
+        self.check_writable()?;
+        let file = self.open_file(&request.handle)?;
+        file.flush().await.map_err(|e| io_status(&e))?;
+        file.sync_all().await.map_err(|e| io_status(&e))?;
+
+        Ok(SftpSession::success(id).into())
+    }
+
+    /// Copies data between two open files without sending it over the channel
+    async fn copy_data(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: CopyData = sftp_extensions::parse(data)?;
+        debug!(
+            "copy-data: {id} {} at {} to {} at {}, {} bytes",
+            request.read_handle,
+            request.read_offset,
+            request.write_handle,
+            request.write_offset,
+            request.length
+        );
+
+        self.check_writable()?;
+        let from = self.blocking_file(&request.read_handle).await?;
+        let to = self.blocking_file(&request.write_handle).await?;
+        // Like OpenSSH, copies within a file aren't supported, whether through one
+        // handle or two: copying to the end of a file that grows with the copy
+        // would never end
+        if sftp_extensions::same_file(&from, &to).map_err(|e| io_status(&e))? {
+            debug!("copy-data from a file to itself");
+            return Err(StatusCode::Failure);
+        }
+
+        tokio::task::spawn_blocking(move || {
+            sftp_extensions::copy_range(
+                &from,
+                request.read_offset,
+                request.length,
+                &to,
+                request.write_offset,
+            )
+        })
+        .await
+        .map_err(|_| StatusCode::Failure)?
+        .map_err(|e| io_status(&e))?;
+
+        Ok(SftpSession::success(id).into())
+    }
+
+    async fn check_file_handle(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: CheckFile = sftp_extensions::parse(data)?;
+        debug!(
+            "check-file-handle: {id} {} {}",
+            request.target, request.algorithms
+        );
+
+        let file = self.blocking_file(&request.target).await?;
+        Self::check_file(id, file, request).await
+    }
+
+    async fn check_file_name(&mut self, id: u32, data: Vec<u8>) -> Result<Packet, StatusCode> {
+        let request: CheckFile = sftp_extensions::parse(data)?;
+        debug!(
+            "check-file-name: {id} {} {}",
+            request.target, request.algorithms
+        );
+
+        let path = self.resolve(&request.target, true).await?;
+        let target = path.clone();
+        let file = self
+            .blocking("check-file", &path, move |jail| jail.open(&target, libc::O_RDONLY, 0))
+            .await?;
+        Self::check_file(id, file, request).await
+    }
+
+    async fn check_file(
+        id: u32,
+        file: std::fs::File,
+        request: CheckFile,
+    ) -> Result<Packet, StatusCode> {
+        let data = tokio::task::spawn_blocking(move || {
+            sftp_extensions::check_file(
+                &file,
+                &request.algorithms,
+                request.offset,
+                request.length,
+                request.block_size,
+            )
+        })
+        .await
+        .map_err(|_| StatusCode::Failure)?
+        .map_err(|e| {
+            debug!("check-file failed: {}", e);
+            io_status(&e)
+        })?;
+
+        Ok(ExtendedReply { id, data }.into())
+    }
+
+    /// A duplicate of an open file for blocking calls, once its pending writes landed
+    async fn blocking_file(&mut self, handle: &str) -> Result<std::fs::File, StatusCode> {
+        let file = self.open_file(handle)?;
+        file.flush().await.map_err(|e| io_status(&e))?;
+        let file = file.try_clone().await.map_err(|e| io_status(&e))?;
+        Ok(file.into_std().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh_sftp::client::error::Error as ClientError;
+    use russh_sftp::client::RawSftpSession;
+    use std::collections::BTreeMap;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    /// A protocol client of a session served like the subsystem does
+    async fn client(jail: Jail, read_only: bool) -> RawSftpSession {
+        let (local, remote) = io::duplex(64 * 1024);
+        russh_sftp::server::run(remote, SftpSession::new(jail, read_only, 16)).await;
+        let client = RawSftpSession::new(local);
+        client.init().await.unwrap();
+        client
+    }
+
+    /// The status code a request failed with
+    fn status_code<T: std::fmt::Debug>(result: Result<T, ClientError>) -> StatusCode {
+        match result {
+            Err(ClientError::Status(status)) => status.status_code,
+            other => panic!("expected an error status, got {:?}", other),
+        }
+    }
+
+    fn file_type(attrs: &FileAttributes) -> u32 {
+        attrs.permissions.unwrap() & libc::S_IFMT
+    }
+
+    #[test]
+    fn test_io_status() {
+        let status = |errno| io_status(&io::Error::from_raw_os_error(errno));
+        assert_eq!(status(libc::ENOENT), StatusCode::NoSuchFile);
+        assert_eq!(status(libc::EACCES), StatusCode::PermissionDenied);
+        assert_eq!(status(libc::ENOSYS), StatusCode::OpUnsupported);
+        assert_eq!(status(libc::ENOSPC), StatusCode::Failure);
+        assert_eq!(
+            io_status(&io::Error::from(io::ErrorKind::NotFound)),
+            StatusCode::NoSuchFile
+        );
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(0o040755), "drwxr-xr-x");
+        assert_eq!(mode_string(0o100644), "-rw-r--r--");
+        assert_eq!(mode_string(0o120777), "lrwxrwxrwx");
+        assert_eq!(mode_string(0o104755), "-rwsr-xr-x");
+        assert_eq!(mode_string(0o102640), "-rw-r-S---");
+        assert_eq!(mode_string(0o041777), "drwxrwxrwt");
+    }
+
+    #[test]
+    fn test_longname() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, b"0123456789").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+
+        let attrs = OwnerNames::default().attributes(&metadata);
+        let line = longname("notes.txt", &metadata, &attrs, metadata.mtime());
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        assert_eq!(fields[0], "-rw-r-----");
+        assert_eq!(fields[1], "1");
+        assert_eq!(Some(fields[2]), attrs.user.as_deref());
+        assert_eq!(Some(fields[3]), attrs.group.as_deref());
+        assert_eq!(fields[4], "10");
+        // Recent files show the time of day
+        assert!(fields[7].contains(':'));
+        assert_eq!(fields[8], "notes.txt");
+    }
+
+    #[test]
+    fn test_access() {
+        let config = SftpConfig {
+            root: SftpRoot::Home,
+            user_roots: BTreeMap::from([("backup".to_string(), SftpRoot::Unrestricted)]),
+            key_roots: BTreeMap::from([(
+                "SHA256:upload".to_string(),
+                SftpRoot::Directory(PathBuf::from("/srv/upload")),
+            )]),
+            read_only: false,
+            read_only_users: vec!["backup".to_string()],
+            read_only_keys: vec![],
+            max_open_handles: 16,
+        };
+
+        assert_eq!(
+            config.access("alice", "SHA256:laptop"),
+            SftpAccess { root: SftpRoot::Home, read_only: false, max_handles: 16 }
+        );
+        assert_eq!(
+            config.access("backup", "SHA256:laptop"),
+            SftpAccess { root: SftpRoot::Unrestricted, read_only: true, max_handles: 16 }
+        );
+        assert_eq!(
+            config.access("backup", "SHA256:upload").root,
+            SftpRoot::Directory(PathBuf::from("/srv/upload"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handles() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("file"), b"0123456789").unwrap();
+
+        let mut session = SftpSession::new(Jail::new(dir).unwrap(), false, 3);
+        async fn open(session: &mut SftpSession) -> Result<Handle, StatusCode> {
+            session
+                .open(1, "/file".to_string(), OpenFlags::READ, FileAttributes::empty())
+                .await
+        }
+        let first = open(&mut session).await.unwrap().handle;
+        let second = open(&mut session).await.unwrap().handle;
+        assert_ne!(first, second);
+        let directory = session.opendir(2, "/".to_string()).await.unwrap().handle;
+        assert!(open(&mut session).await.is_err());
+
+        // Closing one handle leaves the other open
+        session.close(3, first.clone()).await.unwrap();
+        assert!(session.read(4, first.clone(), 0, 4).await.is_err());
+        assert_eq!(session.read(5, second, 2, 4).await.unwrap().data, b"2345");
+
+        session.close(6, directory).await.unwrap();
+        let third = open(&mut session).await.unwrap().handle;
+        assert_ne!(third, first);
+
+        // A huge length is capped rather than allocated
+        assert_eq!(session.read(7, third, 0, u32::MAX).await.unwrap().data, b"0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_extensions_read_only() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("file"), b"0123456789").unwrap();
+
+        let mut session = SftpSession::new(Jail::new(dir).unwrap(), true, 16);
+        let mut rename = Vec::new();
+        for path in ["/file", "/renamed"] {
+            rename.extend_from_slice(&(path.len() as u32).to_be_bytes());
+            rename.extend_from_slice(path.as_bytes());
+        }
+        for extension in [sftp_extensions::POSIX_RENAME, sftp_extensions::HARDLINK] {
+            let result = session.extended(1, extension.to_string(), rename.clone()).await;
+            assert_eq!(result.unwrap_err(), StatusCode::PermissionDenied);
+        }
+        assert!(dir.join("file").exists());
+
+        // Reading extensions still work, and report the session read-only
+        let mut statvfs = Vec::new();
+        statvfs.extend_from_slice(&1u32.to_be_bytes());
+        statvfs.extend_from_slice(b"/");
+        match session.extended(2, sftp_extensions::STATVFS.to_string(), statvfs).await {
+            Ok(Packet::ExtendedReply(reply)) => assert_eq!(reply.data[9 * 8 + 7] & 0x1, 0x1),
+            other => panic!("unexpected statvfs reply {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_copy_data_same_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("file"), b"0123456789").unwrap();
+
+        let mut session = SftpSession::new(Jail::new(dir).unwrap(), false, 16);
+        let flags = OpenFlags::READ | OpenFlags::WRITE;
+        let mut handles = Vec::new();
+        for id in 1..3 {
+            let opened = session.open(id, "/file".to_string(), flags, FileAttributes::empty());
+            handles.push(opened.await.unwrap().handle);
+        }
+
+        // Two handles of one file are refused like a single one
+        let mut copy = Vec::new();
+        for (handle, offset) in [(&handles[0], 0u64), (&handles[1], 10)] {
+            copy.extend_from_slice(&(handle.len() as u32).to_be_bytes());
+            copy.extend_from_slice(handle.as_bytes());
+            copy.extend_from_slice(&offset.to_be_bytes());
+            if offset == 0 {
+                copy.extend_from_slice(&0u64.to_be_bytes());
+            }
+        }
+        let result = session.extended(3, sftp_extensions::COPY_DATA.to_string(), copy).await;
+        assert_eq!(result.unwrap_err(), StatusCode::Failure);
+        assert_eq!(std::fs::read(dir.join("file")).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn test_set_attributes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let attrs = FileAttributes {
+            size: Some(4),
+            permissions: Some(0o100600),
+            atime: Some(1_000_000),
+            mtime: Some(2_000_000),
+            ..FileAttributes::empty()
+        };
+        let parent = std::fs::File::open(dir.path()).unwrap();
+        set_attributes(AttrTarget::At(parent.as_raw_fd(), c"file"), &attrs).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.size(), 4);
+        assert_eq!(metadata.mode() & 0o7777, 0o600);
+        assert_eq!(metadata.atime(), 1_000_000);
+        assert_eq!(metadata.mtime(), 2_000_000);
+
+        // Only the given time changes
+        let file = std::fs::File::open(&path).unwrap();
+        let attrs = FileAttributes {
+            mtime: Some(3_000_000),
+            ..FileAttributes::empty()
+        };
+        set_attributes(AttrTarget::Fd(file.as_raw_fd()), &attrs).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.atime(), 1_000_000);
+        assert_eq!(metadata.mtime(), 3_000_000);
+
+        // A symlink in its place is not followed
+        std::os::unix::fs::symlink(&path, dir.path().join("link")).unwrap();
+        let attrs = FileAttributes {
+            size: Some(0),
+            ..FileAttributes::empty()
+        };
+        assert!(set_attributes(AttrTarget::At(parent.as_raw_fd(), c"link"), &attrs).is_err());
+        assert_eq!(std::fs::metadata(&path).unwrap().size(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_client_session() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("file"), b"0123456789").unwrap();
+        let client = client(Jail::new(dir).unwrap(), false).await;
+
+        client.mkdir("/docs", FileAttributes::empty()).await.unwrap();
+        let attrs = client.stat("/docs").await.unwrap().attrs;
+        assert_eq!(file_type(&attrs), libc::S_IFDIR);
+
+        // The link content comes first, like OpenSSH sends it
+        client.symlink("/file", "/docs/link").await.unwrap();
+        let target = client.readlink("/docs/link").await.unwrap();
+        assert_eq!(target.files[0].filename, "/file");
+        let attrs = client.lstat("/docs/link").await.unwrap().attrs;
+        assert_eq!(file_type(&attrs), libc::S_IFLNK);
+        let attrs = client.stat("/docs/link").await.unwrap().attrs;
+        assert_eq!(file_type(&attrs), libc::S_IFREG);
+        assert_eq!(attrs.size, Some(10));
+
+        let attrs = FileAttributes {
+            permissions: Some(0o600),
+            mtime: Some(2_000_000),
+            atime: Some(1_000_000),
+            ..FileAttributes::empty()
+        };
+        client.setstat("/docs/link", attrs).await.unwrap();
+        let metadata = std::fs::metadata(dir.join("file")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o600);
+        assert_eq!(metadata.mtime(), 2_000_000);
+
+        client.rename("/file", "/docs/moved").await.unwrap();
+        let handle = client
+            .open("/docs/moved", OpenFlags::READ, FileAttributes::empty())
+            .await
+            .unwrap()
+            .handle;
+        let attrs = client.fstat(handle.as_str()).await.unwrap().attrs;
+        assert_eq!(attrs.size, Some(10));
+        assert_eq!(client.read(handle.as_str(), 2, 4).await.unwrap().data, b"2345");
+        client.close(handle).await.unwrap();
+
+        // Errors come back with the status OpenSSH's sftp-server would send
+        assert_eq!(status_code(client.stat("/file").await), StatusCode::NoSuchFile);
+        assert_eq!(status_code(client.stat("/docs/link").await), StatusCode::NoSuchFile);
+        assert_eq!(status_code(client.readlink("/docs/moved").await), StatusCode::BadMessage);
+        assert_eq!(
+            status_code(client.mkdir("/docs", FileAttributes::empty()).await),
+            StatusCode::Failure
+        );
+        assert_eq!(status_code(client.rmdir("/docs").await), StatusCode::Failure);
+        assert_eq!(
+            status_code(client.rename("/docs/moved", "/docs/link").await),
+            StatusCode::Failure
+        );
+        assert_eq!(
+            status_code(client.open("/docs", OpenFlags::WRITE, FileAttributes::empty()).await),
+            StatusCode::Failure
+        );
+        assert_eq!(status_code(client.remove("/missing").await), StatusCode::NoSuchFile);
+
+        let read_only = client(Jail::new(dir).unwrap(), true).await;
+        assert_eq!(
+            status_code(read_only.mkdir("/new", FileAttributes::empty()).await),
+            StatusCode::PermissionDenied
+        );
+        assert_eq!(
+            status_code(read_only.setstat("/docs/moved", FileAttributes::empty()).await),
+            StatusCode::PermissionDenied
+        );
+    }
+
+    /// Runs OpenSSH's sftp client against the `sftp-server` command. Build the
+    /// server first, then run with `cargo test -p sessio-server -- --ignored openssh`
+    #[test]
+    #[ignore]
+    fn test_openssh_client() {
+        let exe = std::env::current_exe().unwrap();
+        // Tests run from target/<profile>/deps, next to the binary's directory
+        let server = exe.parent().unwrap().parent().unwrap().join("sessio-server");
+        assert!(server.exists(), "build the server first: {}", server.display());
+
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let root = dir.join("root");
+        std::fs::create_dir(&root).unwrap();
+        std::fs::write(dir.join("upload"), b"0123456789").unwrap();
+        let batch = format!(
+            "mkdir docs\n\
+             put {upload} docs/file\n\
+             rename docs/file docs/moved\n\
+             ln -s /docs/moved docs/link\n\
+             chmod 600 docs/moved\n\
+             ls -l docs\n\
+             get docs/link {download}\n",
+            upload = dir.join("upload").display(),
+            download = dir.join("download").display(),
+        );
+        std::fs::write(dir.join("batch"), batch).unwrap();
+
+        let user = crate::user::UserInfo::current().unwrap().name;
+        let output = Command::new("/usr/bin/sftp")
+            .arg("-D")
+            .arg(format!(
+                "{} sftp-server --user {} --root {}",
+                server.display(),
+                user,
+                root.display()
+            ))
+            .arg("-b")
+            .arg(dir.join("batch"))
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "sftp failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+
+        assert_eq!(std::fs::read(root.join("docs/moved")).unwrap(), b"0123456789");
+        assert_eq!(std::fs::read_link(root.join("docs/link")).unwrap(), Path::new("/docs/moved"));
+        let metadata = std::fs::metadata(root.join("docs/moved")).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o600);
+        assert_eq!(std::fs::read(dir.join("download")).unwrap(), b"0123456789");
+    }
 }
@@ -0,0 +1,276 @@
+//! SFTP extensions beyond version 3: OpenSSH's `@openssh.com` ones, and
+//! `copy-data` and `check-file` from draft-ietf-secsh-filexfer-extensions. Their
+//! requests and replies are defined in `common::utils::sftp_extensions`.
+
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+use md5::Md5;
+use russh_sftp::extensions::Statvfs;
+use russh_sftp::protocol::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha1::Sha1;
+use sha2::digest::DynDigest;
+use sha2::{Sha224, Sha256, Sha384, Sha512};
+
+pub use common::utils::sftp_extensions::{
+    CheckFile, CheckFileReply, CopyData, HandleRequest, PathPair, PathRequest, CHECK_FILE_HANDLE,
+    CHECK_FILE_NAME, COPY_DATA, FSTATVFS, FSYNC, HARDLINK, POSIX_RENAME, STATVFS,
+};
+
+/// Extensions advertised in the version packet, with their versions
+pub const SUPPORTED: &[(&str, &str)] = &[
+    (POSIX_RENAME, "1"),
+    (STATVFS, "2"),
+    (FSTATVFS, "2"),
+    (HARDLINK, "1"),
+    (FSYNC, "1"),
+    (COPY_DATA, "1"),
+    (CHECK_FILE_HANDLE, "1"),
+    (CHECK_FILE_NAME, "1"),
+];
+
+/// Hash algorithms of `check-file`
+const HASH_ALGORITHMS: &[&str] = &["md5", "sha1", "sha224", "sha256", "sha384", "sha512"];
+
+/// Smallest block size `check-file` hashes separately
+const MIN_HASH_BLOCK: u32 = 256;
+
+/// Largest `check-file` reply, so a small block size can't produce a huge packet
+const MAX_HASHES_LEN: usize = 256 * 1024;
+
+/// Bytes read or copied at once
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// `statvfs` flags, as OpenSSH sends them
+const SSH_FXE_STATVFS_ST_RDONLY: u64 = 0x1;
+const SSH_FXE_STATVFS_ST_NOSUID: u64 = 0x2;
+
+/// Decodes the data of an extension request
+pub fn parse<T: DeserializeOwned>(data: Vec<u8>) -> Result<T, StatusCode> {
+    russh_sftp::de::from_bytes(&mut data.into()).map_err(|_| StatusCode::BadMessage)
+}
+
+/// Encodes the data of an extension reply
+pub fn encode<T: Serialize>(reply: &T) -> Result<Vec<u8>, StatusCode> {
+    russh_sftp::ser::to_bytes(reply)
+        .map(|bytes| bytes.to_vec())
+        .map_err(|_| StatusCode::Failure)
+}
+
+/// File system statistics of the file system holding `path`
+pub fn statvfs(path: &Path) -> io::Result<Statvfs> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(statvfs_reply(&stats))
+}
+
+/// File system statistics of the file system holding an open file
+pub fn fstatvfs(file: &impl AsRawFd) -> io::Result<Statvfs> {
+    let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatvfs(file.as_raw_fd(), &mut stats) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(statvfs_reply(&stats))
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn statvfs_reply(stats: &libc::statvfs) -> Statvfs {
+    let mut flags = 0;
+    if stats.f_flag & libc::ST_RDONLY != 0 {
+        flags |= SSH_FXE_STATVFS_ST_RDONLY;
+    }
+    if stats.f_flag & libc::ST_NOSUID != 0 {
+        flags |= SSH_FXE_STATVFS_ST_NOSUID;
+    }
+    Statvfs {
+        block_size: stats.f_bsize as u64,
+        fragment_size: stats.f_frsize as u64,
+        blocks: stats.f_blocks as u64,
+        blocks_free: stats.f_bfree as u64,
+        blocks_avail: stats.f_bavail as u64,
+        inodes: stats.f_files as u64,
+        inodes_free: stats.f_ffree as u64,
+        inodes_avail: stats.f_favail as u64,
+        fs_id: stats.f_fsid as u64,
+        flags,
+        name_max: stats.f_namemax as u64,
+    }
+}
+
+/// Marks file system statistics read-only, for read-only sessions
+pub fn set_read_only(stats: &mut Statvfs) {
+    stats.flags |= SSH_FXE_STATVFS_ST_RDONLY;
+}
+
+/// Whether two open files are the same file, by device and inode
+pub fn same_file(a: &File, b: &File) -> io::Result<bool> {
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+/// Copies `length` bytes, or up to the end of `from` if 0, between two open files
+pub fn copy_range(
+    from: &File,
+    from_offset: u64,
+    length: u64,
+    to: &File,
+    to_offset: u64,
+) -> io::Result<()> {
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut copied = 0;
+    while length == 0 || copied < length {
+        let wanted = match length {
+            0 => BUFFER_SIZE,
+            _ => (length - copied).min(BUFFER_SIZE as u64) as usize,
+        };
+        let n = from.read_at(&mut buffer[..wanted], from_offset + copied)?;
+        if n == 0 {
+            // The file ended before the length the client asked for
+            return match length {
+                0 => Ok(()),
+                _ => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            };
+        }
+        to.write_all_at(&buffer[..n], to_offset + copied)?;
+        copied += n as u64;
+    }
+    Ok(())
+}
+
+/// Hashes a range of `file` with the first of the client's `algorithms` the
+/// server supports, block by block if `block_size` isn't 0. Returns the
+/// `check-file` reply.
+pub fn check_file(
+    file: &File,
+    algorithms: &str,
+    offset: u64,
+    length: u64,
+    block_size: u32,
+) -> io::Result<Vec<u8>> {
+    let algorithm = algorithms
+        .split(',')
+        .find_map(|name| {
+            HASH_ALGORITHMS
+                .iter()
+                .find(|supported| **supported == name.trim())
+        })
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no supported hash algorithm"))?;
+    if block_size != 0 && block_size < MIN_HASH_BLOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "hash block size too small",
+        ));
+    }
+
+    let end = match length {
+        0 => file.metadata()?.len(),
+        _ => offset.saturating_add(length),
+    };
+    let range = end.saturating_sub(offset);
+    let block_size = match block_size {
+        0 => range.max(1),
+        _ => block_size as u64,
+    };
+    let blocks = range.div_ceil(block_size).max(1);
+    if blocks.saturating_mul(hasher(algorithm).output_size() as u64) > MAX_HASHES_LEN as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many hash blocks",
+        ));
+    }
+
+    let mut hashes = Vec::new();
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut position = offset;
+    for _ in 0..blocks {
+        let block_end = position.saturating_add(block_size).min(end);
+        let mut hash = hasher(algorithm);
+        while position < block_end {
+            let wanted = (block_end - position).min(BUFFER_SIZE as u64) as usize;
+            let n = file.read_at(&mut buffer[..wanted], position)?;
+            if n == 0 {
+                break;
+            }
+            hash.update(&buffer[..n]);
+            position += n as u64;
+        }
+        hashes.extend_from_slice(&hash.finalize());
+        position = block_end;
+    }
+
+    encode(&CheckFileReply {
+        name: "check-file".to_string(),
+        algorithm: algorithm.to_string(),
+        hashes,
+    })
+    .map_err(|_| io::Error::other("failed to encode the check-file reply"))
+}
+
+fn hasher(algorithm: &str) -> Box<dyn DynDigest + Send> {
+    match algorithm {
+        "md5" => Box::new(Md5::default()),
+        "sha1" => Box::new(Sha1::default()),
+        "sha224" => Box::new(Sha224::default()),
+        "sha384" => Box::new(Sha384::default()),
+        "sha512" => Box::new(Sha512::default()),
+        _ => Box::new(Sha256::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Digest;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_copy_and_check_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::fs::write(dir.join("from"), b"0123456789").unwrap();
+        let from = File::open(dir.join("from")).unwrap();
+        let to = File::create(dir.join("to")).unwrap();
+
+        copy_range(&from, 2, 4, &to, 0).unwrap();
+        copy_range(&from, 8, 0, &to, 4).unwrap();
+        assert_eq!(std::fs::read(dir.join("to")).unwrap(), b"234589");
+        assert!(copy_range(&from, 8, 4, &to, 0).is_err());
+
+        assert!(!same_file(&from, &to).unwrap());
+        assert!(same_file(&from, &File::open(dir.join("from")).unwrap()).unwrap());
+
+        let reply = check_file(&from, "crc32,sha256,md5", 0, 0, 0).unwrap();
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&10u32.to_be_bytes());
+        expected.extend_from_slice(b"check-file");
+        expected.extend_from_slice(&6u32.to_be_bytes());
+        expected.extend_from_slice(b"sha256");
+        expected.extend_from_slice(&Sha256::digest(b"0123456789"));
+        assert_eq!(reply, expected);
+
+        // One MD5 hash per block, the last one covering what is left
+        let data = vec![7u8; 600];
+        std::fs::write(dir.join("blocks"), &data).unwrap();
+        let blocks = File::open(dir.join("blocks")).unwrap();
+        let reply = check_file(&blocks, "md5", 0, 0, 256).unwrap();
+        let hashes = &reply[4 + 10 + 4 + 3..];
+        assert_eq!(hashes.len(), 3 * 16);
+        assert_eq!(&hashes[32..], Md5::digest(&data[512..]).as_slice());
+
+        assert_eq!(
+            check_file(&from, "crc32", 0, 0, 0).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        assert!(check_file(&from, "md5", 0, 0, 16).is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use std::collections::VecDeque;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use tokio::fs;
+
+/// Symlinks followed while resolving one path, like the kernel's limit
+const MAX_SYMLINKS: usize = 40;
+
+/// Directories are only opened for the *at calls, which don't need read permission
+#[cfg(target_os = "linux")]
+const DIR_ACCESS: libc::c_int = libc::O_PATH;
+#[cfg(not(target_os = "linux"))]
+const DIR_ACCESS: libc::c_int = libc::O_RDONLY;
+
+/// Resolves the paths of an SFTP session below a root directory, the way a
+/// chroot would: `..` stops at the root and symlinks are followed inside it,
+/// with absolute link targets starting at the root. Clients see the root as `/`.
+///
+/// Paths are resolved before the file system call using them. Opening and
+/// changing files goes through `open_parent`, so a client swapping a resolved
+/// directory for a symlink at the same time can't lead those out of the root.
+/// Reading attributes, links and directory listings by path could still race it.
+#[derive(Debug, Clone)]
+pub struct Jail {
+    /// Canonical root directory, `/` for unrestricted access
+    root: PathBuf,
+    /// Where relative client paths start
+    cwd: PathBuf,
+}
+
+impl Jail {
+    /// A jail of `root`, relative paths start at the root
+    pub fn new(root: &Path) -> io::Result<Self> {
+        let root = std::fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::ENOTDIR));
+        }
+        Ok(Jail {
+            root,
+            cwd: PathBuf::from("/"),
+        })
+    }
+
+    /// Access to the whole file system, relative paths start at `home`
+    pub fn unrestricted(home: PathBuf) -> Self {
+        Jail {
+            root: PathBuf::from("/"),
+            cwd: home,
+        }
+    }
+
+    /// The root directory, `/` when unrestricted
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves a client path to the real path it names. Symlinks are followed,
+    /// the last component only if `follow` is set. Missing components are
+    /// kept as they are, so a file about to be created resolves too.
+    pub async fn resolve(&self, path: &str, follow: bool) -> io::Result<PathBuf> {
+        let path = Path::new(path);
+        let mut pending = components(path);
+        if path.is_relative() {
+            let mut cwd = components(&self.cwd);
+            cwd.append(&mut pending);
+            pending = cwd;
+        }
+
+        let mut resolved = self.root.clone();
+        // Components of `resolved` below the root, `..` doesn't go above it
+        let mut depth = 0;
+        let mut links = 0;
+        while let Some(name) = pending.pop_front() {
+            if name == ".." {
+                if depth > 0 {
+                    resolved.pop();
+                    depth -= 1;
+                }
+                continue;
+            }
+
+            let candidate = resolved.join(&name);
+            if pending.is_empty() && !follow {
+                return Ok(candidate);
+            }
+            match fs::symlink_metadata(&candidate).await {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                    }
+                    let target = fs::read_link(&candidate).await?;
+                    if target.is_absolute() {
+                        resolved = self.root.clone();
+                        depth = 0;
+                    }
+                    for component in components(&target).into_iter().rev() {
+                        pending.push_front(component);
+                    }
+                }
+                Ok(_) => {
+                    resolved = candidate;
+                    depth += 1;
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    resolved = candidate;
+                    depth += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// The path clients see for a real path below the root
+    pub fn client_path(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    /// Opens the directory containing `path`, a path `resolve` returned, and
+    /// returns it with the last component, `.` for the root itself. The
+    /// directories are opened one by one from the root without following
+    /// symlinks, so one swapped for a symlink after resolving fails the call.
+    pub fn open_parent(&self, path: &Path) -> io::Result<(OwnedFd, CString)> {
+        let relative = path
+            .strip_prefix(&self.root)
+            .map_err(|_| io::Error::from(io::ErrorKind::PermissionDenied))?;
+        let mut names = relative
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => Ok(name),
+                _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+            })
+            .collect::<io::Result<Vec<&OsStr>>>()?;
+        let name = names.pop().unwrap_or(OsStr::new("."));
+
+        let mut dir = open_dir(libc::AT_FDCWD, self.root.as_os_str())?;
+        for name in names {
+            dir = open_dir(dir.as_raw_fd(), name)?;
+        }
+        Ok((dir, CString::new(name.as_bytes())?))
+    }
+
+    /// Opens the file at a resolved path with open(2) `flags`, `mode` is the
+    /// mode of a newly created file. A symlink is not followed.
+    pub fn open(&self, path: &Path, flags: libc::c_int, mode: libc::mode_t) -> io::Result<File> {
+        let (dir, name) = self.open_parent(path)?;
+        let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = check_fd(unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                flags,
+                libc::c_uint::from(mode),
+            )
+        })?;
+        Ok(File::from(fd))
+    }
+
+    pub fn mkdir(&self, path: &Path, mode: libc::mode_t) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path)?;
+        check_errno(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), mode) })
+    }
+
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.unlink(path, 0)
+    }
+
+    pub fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.unlink(path, libc::AT_REMOVEDIR)
+    }
+
+    fn unlink(&self, path: &Path, flags: libc::c_int) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path)?;
+        check_errno(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), flags) })
+    }
+
+    /// Renames like rename(2), replacing an existing destination
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dir, from_name) = self.open_parent(from)?;
+        let (to_dir, to_name) = self.open_parent(to)?;
+        check_errno(unsafe {
+            libc::renameat(
+                from_dir.as_raw_fd(),
+                from_name.as_ptr(),
+                to_dir.as_raw_fd(),
+                to_name.as_ptr(),
+            )
+        })
+    }
+
+    /// Creates a symlink at `link` with `target` as its content
+    pub fn symlink(&self, target: &str, link: &Path) -> io::Result<()> {
+        let target = CString::new(target)?;
+        let (dir, name) = self.open_parent(link)?;
+        check_errno(unsafe { libc::symlinkat(target.as_ptr(), dir.as_raw_fd(), name.as_ptr()) })
+    }
+
+    /// Creates a hard link at `link` to `original`, a symlink is linked itself
+    pub fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        let (original_dir, original_name) = self.open_parent(original)?;
+        let (link_dir, link_name) = self.open_parent(link)?;
+        check_errno(unsafe {
+            libc::linkat(
+                original_dir.as_raw_fd(),
+                original_name.as_ptr(),
+                link_dir.as_raw_fd(),
+                link_name.as_ptr(),
+                0,
+            )
+        })
+    }
+}
+
+/// Opens the directory `name` in `dir`, failing if it's a symlink
+fn open_dir(dir: RawFd, name: &OsStr) -> io::Result<OwnedFd> {
+    let name = CString::new(name.as_bytes())?;
+    let flags = DIR_ACCESS | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    check_fd(unsafe { libc::openat(dir, name.as_ptr(), flags) })
+}
+
+fn check_fd(fd: libc::c_int) -> io::Result<OwnedFd> {
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn check_errno(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The names and `..` of a path, without the root and `.`
+fn components(path: &Path) -> VecDeque<OsString> {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name.to_os_string()),
+            Component::ParentDir => Some(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_resolve_stays_in_jail() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(dir.join("secret"), b"outside").unwrap();
+        std::os::unix::fs::symlink("../secret", root.join("relative")).unwrap();
+        std::os::unix::fs::symlink("/docs", root.join("absolute")).unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join("passwd")).unwrap();
+
+        let jail = Jail::new(&root).unwrap();
+        let root = jail.root().to_path_buf();
+
+        assert_eq!(jail.resolve("/", true).await.unwrap(), root);
+        assert_eq!(
+            jail.resolve("../../etc/shadow", true).await.unwrap(),
+            root.join("etc/shadow")
+        );
+        assert_eq!(
+            jail.resolve("docs/../../secret", true).await.unwrap(),
+            root.join("secret")
+        );
+        // Link targets are resolved inside the jail
+        assert_eq!(
+            jail.resolve("relative", true).await.unwrap(),
+            root.join("secret")
+        );
+        assert_eq!(
+            jail.resolve("absolute/new", true).await.unwrap(),
+            root.join("docs/new")
+        );
+        assert_eq!(
+            jail.resolve("/passwd", true).await.unwrap(),
+            root.join("etc/passwd")
+        );
+        // Unless the link itself is meant
+        assert_eq!(
+            jail.resolve("relative", false).await.unwrap(),
+            root.join("relative")
+        );
+
+        assert_eq!(
+            jail.client_path(&root.join("docs/new")),
+            PathBuf::from("/docs/new")
+        );
+        assert_eq!(jail.client_path(&root), PathBuf::from("/"));
+    }
+
+    #[tokio::test]
+    async fn test_open_parent_stays_in_jail() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(dir.join("secret"), b"outside").unwrap();
+        std::fs::write(root.join("docs/notes"), b"inside").unwrap();
+
+        let jail = Jail::new(&root).unwrap();
+        let notes = jail.resolve("docs/notes", true).await.unwrap();
+        assert!(jail.open(&notes, libc::O_RDONLY, 0).is_ok());
+        let (_, name) = jail.open_parent(jail.root()).unwrap();
+        assert_eq!(name.as_c_str(), c".");
+
+        // A directory swapped for a symlink after resolving doesn't lead out
+        let secret = jail.resolve("docs/secret", true).await.unwrap();
+        std::fs::rename(root.join("docs"), root.join("moved")).unwrap();
+        std::os::unix::fs::symlink(dir, root.join("docs")).unwrap();
+        assert!(jail.open(&secret, libc::O_RDONLY, 0).is_err());
+        assert!(jail.remove_file(&secret).is_err());
+        assert!(dir.join("secret").exists());
+
+        let moved = jail.resolve("moved/notes", true).await.unwrap();
+        assert!(jail.open(&moved, libc::O_RDONLY, 0).is_ok());
+        // Nor does a file swapped for a symlink
+        std::fs::remove_file(root.join("moved/notes")).unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), root.join("moved/notes")).unwrap();
+        assert!(jail.open(&moved, libc::O_RDONLY, 0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_symlink_loop() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path();
+        std::os::unix::fs::symlink("b", dir.join("a")).unwrap();
+        std::os::unix::fs::symlink("a", dir.join("b")).unwrap();
+
+        let jail = Jail::new(dir).unwrap();
+        let e = jail.resolve("a", true).await.unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ELOOP));
+    }
+}
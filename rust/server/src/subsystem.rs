@@ -0,0 +1,171 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use log::{debug, error};
+use russh::server::{Handle, Msg};
+use russh::Channel;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::process::Child;
+
+use crate::audit::{ChannelStats, SessionAudit};
+use crate::process::{report_exit, ChildExit};
+use crate::user::UserInfo;
+
+/// A subsystem implemented by the server itself, like `sftp`
+#[async_trait::async_trait]
+pub trait Subsystem: Send + Sync {
+    /// Starts serving the subsystem for `login`, who authenticated with the key
+    /// of `fingerprint`. The returned stream is relayed to the channel until it
+    /// ends, then the channel is closed.
+    async fn start(&self, login: &Arc<UserInfo>, fingerprint: &str) -> anyhow::Result<SubsystemIo>;
+}
+
+pub trait SubsystemStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> SubsystemStream for T {}
+
+/// The local end of a running subsystem
+pub struct SubsystemIo {
+    stream: Box<dyn SubsystemStream>,
+    /// Process serving the subsystem, its exit status is reported to the client
+    child: Option<Child>,
+}
+
+impl SubsystemIo {
+    pub fn stream(stream: impl SubsystemStream + 'static) -> Self {
+        SubsystemIo {
+            stream: Box::new(stream),
+            child: None,
+        }
+    }
+
+    pub fn process(stream: impl SubsystemStream + 'static, child: Child) -> Self {
+        SubsystemIo {
+            stream: Box::new(stream),
+            child: Some(child),
+        }
+    }
+}
+
+/// How a requested subsystem is served
+pub enum SubsystemHandler {
+    Builtin(Arc<dyn Subsystem>),
+    /// A command run with the login shell, its stdio wired to the channel
+    Command(String),
+}
+
+/// The subsystems of the server by name. Commands from the settings take
+/// precedence over built-in subsystems of the same name.
+#[derive(Default)]
+pub struct Subsystems {
+    builtin: HashMap<String, Arc<dyn Subsystem>>,
+    commands: BTreeMap<String, String>,
+}
+
+impl Subsystems {
+    pub fn new(commands: BTreeMap<String, String>) -> Self {
+        Subsystems {
+            builtin: HashMap::new(),
+            commands,
+        }
+    }
+
+    pub fn register(&mut self, name: &str, subsystem: impl Subsystem + 'static) {
+        self.builtin.insert(name.to_string(), Arc::new(subsystem));
+    }
+
+    pub fn get(&self, name: &str) -> Option<SubsystemHandler> {
+        if let Some(command) = self.commands.get(name) {
+            return Some(SubsystemHandler::Command(command.clone()));
+        }
+        self.builtin
+            .get(name)
+            .map(|subsystem| SubsystemHandler::Builtin(subsystem.clone()))
+    }
+}
+
+/// Relays a built-in subsystem to its channel. Once the subsystem's output ends,
+/// its exit status is sent and the channel is closed.
+pub async fn serve_subsystem(
+    io: SubsystemIo,
+    channel: Channel<Msg>,
+    handle: Handle,
+    audit: Arc<SessionAudit>,
+    stats: Option<Arc<ChannelStats>>,
+) {
+    let channel_id = channel.id();
+    let SubsystemIo { stream, mut child } = io;
+
+    let (mut channel_read, mut channel_write) = tokio::io::split(channel.into_stream());
+    let (mut local_read, mut local_write) = tokio::io::split(stream);
+
+    let input_stats = stats.clone();
+    let input = tokio::spawn(async move {
+        match tokio::io::copy(&mut channel_read, &mut local_write).await {
+            Ok(received) => {
+                if let Some(stats) = input_stats {
+                    stats.add_in(received);
+                }
+            }
+            Err(e) => debug!("Subsystem input ended: {:?}", e),
+        }
+        // Pass the end of the client's data on to the subsystem
+        let _ = local_write.shutdown().await;
+    });
+
+    match tokio::io::copy(&mut local_read, &mut channel_write).await {
+        Ok(sent) => {
+            if let Some(stats) = stats.as_ref() {
+                stats.add_out(sent);
+            }
+        }
+        Err(e) => debug!("Subsystem output ended: {:?}", e),
+    }
+    let _ = channel_write.flush().await;
+    input.abort();
+
+    let exit = match child.as_mut() {
+        Some(child) => match child.wait().await {
+            Ok(status) => status.into(),
+            Err(e) => {
+                error!("Failed to wait on subsystem process: {:?}", e);
+                ChildExit::Code(1)
+            }
+        },
+        None => ChildExit::Code(0),
+    };
+    report_exit(&handle, channel_id, exit).await;
+
+    if let Some(stats) = stats {
+        audit.close_channel(channel_id, &stats);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait::async_trait]
+    impl Subsystem for Echo {
+        async fn start(&self, _login: &Arc<UserInfo>, _fingerprint: &str) -> anyhow::Result<SubsystemIo> {
+            Ok(SubsystemIo::stream(tokio::io::duplex(64).0))
+        }
+    }
+
+    #[test]
+    fn test_commands_take_precedence() {
+        let commands = BTreeMap::from([("sftp".to_string(), "/usr/lib/sftp-server".to_string())]);
+        let mut subsystems = Subsystems::new(commands);
+        subsystems.register("sftp", Echo);
+        subsystems.register("echo", Echo);
+
+        assert!(matches!(
+            subsystems.get("sftp"),
+            Some(SubsystemHandler::Command(command)) if command == "/usr/lib/sftp-server"
+        ));
+        assert!(matches!(subsystems.get("echo"), Some(SubsystemHandler::Builtin(_))));
+        assert!(subsystems.get("netconf").is_none());
+    }
+}
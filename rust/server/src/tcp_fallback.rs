@@ -0,0 +1,119 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use common::utils::tcp_fallback::SshStream;
+use log::{error, info};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config_manager::TcpFallbackConfig;
+
+/// Time a client has to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listener serving SSH over TCP, optionally wrapped in TLS, next to the QUIC
+/// endpoint. Each TCP connection carries a single SSH session.
+pub struct TcpFallback {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    config: TcpFallbackConfig,
+}
+
+impl TcpFallback {
+    pub async fn bind(config: &TcpFallbackConfig) -> anyhow::Result<Self> {
+        let tls = match config.tls {
+            true => Some(tls_acceptor(config)?),
+            false => None,
+        };
+        let listener = TcpListener::bind(config.listen)
+            .await
+            .with_context(|| format!("Failed to listen on TCP {}", config.listen))?;
+        info!(
+            "Accepting SSH over {} on {}",
+            if tls.is_some() { "TLS" } else { "TCP" },
+            config.listen
+        );
+        Ok(TcpFallback {
+            listener,
+            tls,
+            config: config.clone(),
+        })
+    }
+
+    /// The settings the listener was started with
+    pub fn config(&self) -> &TcpFallbackConfig {
+        &self.config
+    }
+
+    /// Waits for the next connection. Failed accepts are logged and skipped.
+    pub async fn accept(&self) -> (TcpStream, SocketAddr) {
+        loop {
+            match self.listener.accept().await {
+                Ok((stream, remote)) => {
+                    let _ = stream.set_nodelay(true);
+                    return (stream, remote);
+                }
+                Err(e) => {
+                    error!("[server] accept TCP connection error: {}", e);
+                    // Usually out of file descriptors, don't spin on it
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    }
+
+    /// Returns what the connection's SSH session runs over, after the TLS handshake if any
+    pub fn handshake(
+        &self,
+        stream: TcpStream,
+    ) -> impl std::future::Future<Output = io::Result<Box<dyn SshStream>>> + Send + 'static {
+        let tls = self.tls.clone();
+        async move {
+            let Some(tls) = tls else {
+                return Ok(Box::new(stream) as Box<dyn SshStream>);
+            };
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+                Ok(stream) => Ok(Box::new(stream?) as Box<dyn SshStream>),
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")),
+            }
+        }
+    }
+}
+
+/// Waits for a connection on `fallback`, or forever if there is no fallback listener
+pub async fn accept_fallback(fallback: Option<&TcpFallback>) -> (TcpStream, SocketAddr) {
+    match fallback {
+        Some(fallback) => fallback.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn tls_acceptor(config: &TcpFallbackConfig) -> anyhow::Result<TlsAcceptor> {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let (certs, key) = match (&config.cert_path, &config.key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let certs = CertificateDer::pem_file_iter(cert_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Failed to read TLS certificate {:?}", cert_path))?;
+            let key = PrivateKeyDer::from_pem_file(key_path)
+                .with_context(|| format!("Failed to read TLS key {:?}", key_path))?;
+            (certs, key)
+        }
+        // Clients authenticate the server by its SSH host key, not the certificate
+        _ => {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+            let key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+            (vec![CertificateDer::from(cert.cert)], key.into())
+        }
+    };
+
+    let tls_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
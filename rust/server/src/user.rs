@@ -0,0 +1,223 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::PathBuf;
+
+use log::warn;
+
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_ROOT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// A local account that a login session runs as
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserInfo {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups, including the primary group
+    pub groups: Vec<u32>,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+impl UserInfo {
+    /// Looks up an account by name from the passwd database
+    pub fn lookup(name: &str) -> io::Result<Option<UserInfo>> {
+        let c_name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "User name contains a nul byte"))?;
+
+        read_passwd(|pwd, buf, len, result| unsafe {
+            libc::getpwnam_r(c_name.as_ptr(), pwd, buf, len, result)
+        })
+    }
+
+    /// The account the server itself is running as
+    pub fn current() -> io::Result<UserInfo> {
+        let uid = unsafe { libc::geteuid() };
+        read_passwd(|pwd, buf, len, result| unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) })?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Current user not found in passwd"))
+    }
+
+    /// Whether spawning processes for this account requires dropping privileges
+    pub fn needs_switch(&self) -> bool {
+        unsafe { libc::geteuid() != self.uid }
+    }
+
+    /// The clean environment a login session starts with
+    pub fn login_env(&self) -> Vec<(String, String)> {
+        let path = if self.uid == 0 {
+            DEFAULT_ROOT_PATH
+        } else {
+            DEFAULT_PATH
+        };
+
+        vec![
+            ("HOME".to_string(), self.home.to_string_lossy().into_owned()),
+            ("USER".to_string(), self.name.clone()),
+            ("LOGNAME".to_string(), self.name.clone()),
+            ("SHELL".to_string(), self.shell.to_string_lossy().into_owned()),
+            ("PATH".to_string(), path.to_string()),
+        ]
+    }
+
+    /// Switches the calling process to this account.
+    /// This is meant to be called from a `pre_exec` hook, so it must not allocate.
+    pub fn switch_to(&self) -> io::Result<()> {
+        unsafe {
+            if libc::setgroups(self.groups.len() as _, self.groups.as_ptr()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setgid(self.gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setuid(self.uid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether every login runs as the account of the server. Only a server
+/// running as root can switch users.
+pub fn single_user() -> bool {
+    unsafe { libc::geteuid() != 0 }
+}
+
+/// Resolves which account a login for `name` runs as.
+/// Only a server running as root can switch users, an unprivileged server
+/// runs every session as itself.
+pub fn resolve_login_user(name: &str) -> io::Result<Option<UserInfo>> {
+    if single_user() {
+        let current = UserInfo::current()?;
+        if current.name != name {
+            warn!(
+                "Server is not running as root, login for {} will run as {}",
+                name, current.name
+            );
+        }
+        return Ok(Some(current));
+    }
+
+    UserInfo::lookup(name)
+}
+
+fn read_passwd<F>(mut get: F) -> io::Result<Option<UserInfo>>
+where
+    F: FnMut(*mut libc::passwd, *mut libc::c_char, libc::size_t, *mut *mut libc::passwd) -> libc::c_int,
+{
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 4096];
+
+    loop {
+        let rc = get(&mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+        if rc == libc::ERANGE {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        break;
+    }
+
+    if result.is_null() {
+        return Ok(None);
+    }
+
+    let (name, home, shell) = unsafe {
+        (
+            CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned(),
+            CStr::from_ptr(pwd.pw_dir).to_string_lossy().into_owned(),
+            CStr::from_ptr(pwd.pw_shell).to_string_lossy().into_owned(),
+        )
+    };
+
+    let shell = if shell.is_empty() {
+        PathBuf::from("/bin/sh")
+    } else {
+        PathBuf::from(shell)
+    };
+
+    let groups = group_list(&name, pwd.pw_gid)?;
+
+    Ok(Some(UserInfo {
+        name,
+        uid: pwd.pw_uid,
+        gid: pwd.pw_gid,
+        groups,
+        home: PathBuf::from(home),
+        shell,
+    }))
+}
+
+/// The name of the account with `uid`, None if there is no such account
+pub fn user_name(uid: u32) -> io::Result<Option<String>> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    read_name(|buf, len| {
+        let mut result: *mut libc::passwd = std::ptr::null_mut();
+        let rc = unsafe { libc::getpwuid_r(uid, &mut pwd, buf, len, &mut result) };
+        (rc, if result.is_null() { std::ptr::null() } else { pwd.pw_name })
+    })
+}
+
+/// The name of the group with `gid`, None if there is no such group
+pub fn group_name(gid: u32) -> io::Result<Option<String>> {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    read_name(|buf, len| {
+        let mut result: *mut libc::group = std::ptr::null_mut();
+        let rc = unsafe { libc::getgrgid_r(gid, &mut grp, buf, len, &mut result) };
+        (rc, if result.is_null() { std::ptr::null() } else { grp.gr_name })
+    })
+}
+
+/// Calls a reentrant database lookup with a growing buffer until it fits.
+/// `get` returns the error code and the found name, which points into the buffer.
+fn read_name<F>(mut get: F) -> io::Result<Option<String>>
+where
+    F: FnMut(*mut libc::c_char, libc::size_t) -> (libc::c_int, *const libc::c_char),
+{
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let (rc, name) = get(buf.as_mut_ptr(), buf.len());
+        if rc == libc::ERANGE {
+            let len = buf.len() * 2;
+            buf.resize(len, 0);
+            continue;
+        }
+        if rc != 0 {
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+        if name.is_null() {
+            return Ok(None);
+        }
+        return Ok(Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()));
+    }
+}
+
+#[cfg(target_vendor = "apple")]
+type GroupId = libc::c_int;
+#[cfg(not(target_vendor = "apple"))]
+type GroupId = libc::gid_t;
+
+fn group_list(name: &str, gid: libc::gid_t) -> io::Result<Vec<u32>> {
+    let c_name = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "User name contains a nul byte"))?;
+
+    let mut groups: Vec<GroupId> = vec![0; 64];
+    loop {
+        let mut count = groups.len() as libc::c_int;
+        let rc = unsafe {
+            libc::getgrouplist(c_name.as_ptr(), gid as GroupId, groups.as_mut_ptr(), &mut count)
+        };
+        if rc == -1 {
+            // The buffer was too small, count holds the required size on most platforms
+            let len = (count as usize).max(groups.len() * 2);
+            groups.resize(len, 0);
+            continue;
+        }
+        groups.truncate(count as usize);
+        return Ok(groups.into_iter().map(|g| g as u32).collect());
+    }
+}